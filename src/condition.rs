@@ -0,0 +1,259 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use evalexpr::{self, Context, EvalexprError, EvalexprResult, Function,
+               HashMapContext, Value};
+use glob::Pattern;
+use regex::Regex;
+use semver::{Version, VersionReq};
+
+use crate::template::{self, Source, Token};
+
+/// Bound names of values that come from the repository, which are always
+/// strings, even if a branch or tag is called `123` or `true`
+const VCS_VALUES: &[&str] = &[
+    "BRANCH", "TAG", "REFTYPE",
+    "ENV_CINDERELLA_BRANCH", "ENV_CINDERELLA_TAG", "ENV_CINDERELLA_REFTYPE",
+    "ENV_CINDERELLA_PROJECT", "ENV_CINDERELLA_REPO_URL",
+];
+
+/// Evaluates a `when` condition of a pipeline
+///
+/// Variables are never pasted into the expression as text. `%VAR` outside
+/// of a string literal is bound as a typed value (integer, boolean or
+/// string) in the evaluation context under the name `VAR` (`$VAR` under
+/// `ENV_VAR`), except for values from the repository like the branch,
+/// which are always bound as strings. Inside a string
+/// literal like `"%BRANCH"` the value is inserted as an escaped string, so
+/// that quotes in a value cannot change the logic of the condition.
+pub fn evaluate(
+    condition: &str,
+    variables: &HashMap<String, String>,
+    environment: &HashMap<String, String>) -> Result<bool, String>
+{
+    let (expression, bound) = substitute(condition, variables, environment)?;
+
+    let mut context = HashMapContext::new();
+    register_functions(&mut context)
+        .map_err(|e| format!("Could not set up condition context: {}", e))?;
+
+    for (name, value) in bound {
+        let value = if VCS_VALUES.contains(&name.as_str()) {
+            Value::String(value)
+        } else {
+            typed_value(&value)
+        };
+        context.set_value(name, value)
+            .map_err(|e| format!("Could not bind variable: {}", e))?;
+    }
+
+    evalexpr::eval_boolean_with_context(&expression, &context)
+        .map_err(|e| format!("Invalid condition {}: {}", condition, e))
+}
+
+/// Rewrites the placeholders of a condition into evalexpr syntax
+///
+/// Returns the rewritten expression and the variables that have to be
+/// bound in the context. Fails if an unknown variable is used.
+pub fn substitute(
+    condition: &str,
+    variables: &HashMap<String, String>,
+    environment: &HashMap<String, String>)
+    -> Result<(String, HashMap<String, String>), String>
+{
+    let mut expression = String::new();
+    let mut bound = HashMap::new();
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for token in template::tokenize(condition)? {
+        match token {
+            Token::Text(text) => {
+                for c in text.chars() {
+                    if escaped {
+                        escaped = false;
+                    } else if c == '\\' && in_string {
+                        escaped = true;
+                    } else if c == '"' {
+                        in_string = !in_string;
+                    }
+                    expression.push(c);
+                }
+            },
+            Token::Placeholder(placeholder) => {
+                let value = template::resolve(&placeholder, variables, environment)
+                    .ok_or_else(|| format!(
+                        "Undefined variable {} in condition: {}",
+                        placeholder.raw, condition))?;
+
+                if in_string {
+                    expression.push_str(&escape(&value));
+                } else {
+                    let name = match placeholder.source {
+                        Source::Variable => placeholder.name.to_uppercase(),
+                        Source::Environment => format!("ENV_{}", placeholder.name),
+                    };
+                    expression.push_str(&name);
+                    bound.insert(name, value);
+                }
+            },
+        }
+    }
+
+    Ok((expression, bound))
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn typed_value(value: &str) -> Value {
+    if let Ok(int) = value.parse::<i64>() {
+        Value::Int(int)
+    } else if value == "true" || value == "false" {
+        Value::Boolean(value == "true")
+    } else {
+        Value::String(value.to_string())
+    }
+}
+
+fn string_args(argument: &Value) -> EvalexprResult<(String, String)> {
+    let args = argument.as_fixed_len_tuple(2)?;
+    Ok((args[0].as_string()?, args[1].as_string()?))
+}
+
+fn parse_version(version: &str) -> EvalexprResult<Version> {
+    // tags are often prefixed with a "v", e.g. "v1.2.0"
+    let version = version.trim_start_matches('v');
+
+    Version::parse(version).map_err(|e| EvalexprError::CustomMessage(
+        format!("Invalid version {}: {}", version, e)))
+}
+
+fn register_functions(context: &mut HashMapContext) -> EvalexprResult<()> {
+    // glob(%BRANCH, "release/*")
+    context.set_function("glob".to_string(), Function::new(Box::new(|arg| {
+        let (text, pattern) = string_args(arg)?;
+        let pattern = Pattern::new(&pattern).map_err(|e| {
+            EvalexprError::CustomMessage(format!("Invalid glob: {}", e))
+        })?;
+
+        Ok(Value::Boolean(pattern.matches(&text)))
+    })))?;
+
+    // regex(%TAG, "^v[0-9]+")
+    context.set_function("regex".to_string(), Function::new(Box::new(|arg| {
+        let (text, pattern) = string_args(arg)?;
+        let regex = Regex::new(&pattern).map_err(|e| {
+            EvalexprError::CustomMessage(format!("Invalid regex: {}", e))
+        })?;
+
+        Ok(Value::Boolean(regex.is_match(&text)))
+    })))?;
+
+    // semver_cmp(%TAG, "1.0.0") returns -1, 0 or 1
+    context.set_function("semver_cmp".to_string(), Function::new(Box::new(|arg| {
+        let (left, right) = string_args(arg)?;
+        let ordering = parse_version(&left)?.cmp(&parse_version(&right)?);
+
+        Ok(Value::Int(match ordering {
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        }))
+    })))?;
+
+    // semver_match(%TAG, ">=1.2, <2")
+    context.set_function("semver_match".to_string(), Function::new(Box::new(|arg| {
+        let (version, requirement) = string_args(arg)?;
+        let requirement = VersionReq::parse(&requirement).map_err(|e| {
+            EvalexprError::CustomMessage(
+                format!("Invalid version requirement: {}", e))
+        })?;
+
+        Ok(Value::Boolean(requirement.matches(&parse_version(&version)?)))
+    })))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_quoted_and_unquoted_variables() {
+        let vars = variables(&[("branch", "master")]);
+
+        assert_eq!(evaluate("\"%BRANCH\" == \"master\"", &vars, &HashMap::new()), Ok(true));
+        assert_eq!(evaluate("%BRANCH == \"master\"", &vars, &HashMap::new()), Ok(true));
+        assert_eq!(evaluate("%BRANCH != \"master\"", &vars, &HashMap::new()), Ok(false));
+    }
+
+    #[test]
+    fn test_quotes_in_values_cannot_inject() {
+        let vars = variables(&[("branch", "x\" == \"x")]);
+
+        assert_eq!(evaluate("\"%BRANCH\" == \"master\"", &vars, &HashMap::new()), Ok(false));
+        assert_eq!(evaluate("%BRANCH == \"master\"", &vars, &HashMap::new()), Ok(false));
+        assert_eq!(evaluate("\"%BRANCH\" == \"x\\\" == \\\"x\"", &vars, &HashMap::new()),
+                   Ok(true));
+    }
+
+    #[test]
+    fn test_undefined_variable_is_an_error() {
+        let vars = variables(&[("tag", "1.0.0")]);
+
+        let result = evaluate("\"%BRANCH\" == \"master\"", &vars, &HashMap::new());
+
+        assert!(result.unwrap_err().contains("%BRANCH"));
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let vars = variables(&[("count", "42")]);
+
+        assert_eq!(evaluate("%COUNT > 10", &vars, &HashMap::new()), Ok(true));
+        assert_eq!(evaluate("\"%COUNT\" == \"42\"", &vars, &HashMap::new()), Ok(true));
+    }
+
+    #[test]
+    fn test_vcs_values_are_strings() {
+        let vars = variables(&[("branch", "123"), ("tag", "true")]);
+        let mut env = HashMap::new();
+        env.insert(String::from("CINDERELLA_BRANCH"), String::from("123"));
+
+        assert_eq!(evaluate("%BRANCH == \"123\"", &vars, &HashMap::new()), Ok(true));
+        assert_eq!(evaluate("%TAG == \"true\"", &vars, &HashMap::new()), Ok(true));
+        assert_eq!(evaluate("$CINDERELLA_BRANCH == \"123\"", &vars, &env), Ok(true));
+    }
+
+    #[test]
+    fn test_template_syntax() {
+        let vars = variables(&[("tag", "1.0")]);
+
+        assert_eq!(evaluate("\"%{TAG}-rc\" == \"1.0-rc\"", &vars, &HashMap::new()), Ok(true));
+        assert_eq!(evaluate("\"%{BRANCH:-none}\" == \"none\"", &vars, &HashMap::new()),
+                   Ok(true));
+        assert_eq!(evaluate("\"100%%\" == \"100%\"", &vars, &HashMap::new()), Ok(true));
+    }
+
+    #[test]
+    fn test_helper_functions() {
+        let vars = variables(&[("branch", "release/1.2"), ("tag", "v1.4.0")]);
+
+        assert_eq!(evaluate("glob(%BRANCH, \"release/*\")", &vars, &HashMap::new()), Ok(true));
+        assert_eq!(evaluate("regex(%TAG, \"^v[0-9]+\")", &vars, &HashMap::new()), Ok(true));
+        assert_eq!(evaluate("semver_cmp(%TAG, \"1.3.9\") == 1", &vars, &HashMap::new()),
+                   Ok(true));
+        assert_eq!(evaluate("semver_match(%TAG, \">=1.2, <2\")", &vars, &HashMap::new()),
+                   Ok(true));
+        assert!(evaluate("semver_match(%BRANCH, \">=1\")", &vars, &HashMap::new()).is_err());
+    }
+}
@@ -1,20 +1,47 @@
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::vec::Vec;
 
 use serde::Deserialize;
 use toml;
 
+use crate::limits::Limits;
+use crate::locking::Policy;
+use crate::pipeline::Selection;
+
 pub struct Configs<'a> {
     pub cinderella_config: &'a CinderellaConfig,
     pub execution_config: &'a ExecutionConfig,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct CinderellaConfig {
     pub email: Option<Email>,
     pub secrets: Option<Secrets>,
     pub dashboard: Option<Dashboard>,
+    pub scheduler: Option<Scheduler>,
+    pub daemon: Option<Daemon>,
+    pub logs: Option<Logs>,
+    pub history: Option<History>,
+    #[serde(default)]
+    pub projects: Vec<Project>,
+    #[serde(default)]
+    pub hosts: HashMap<String, Host>,
+    #[serde(default)]
+    pub sandboxes: HashMap<String, Sandbox>,
+    /// Default resource limits for all pipelines
+    #[serde(default)]
+    pub limits: Limits,
+    /// Unprivileged user that executes the build commands
+    pub run_as: Option<String>,
+    /// Program like `sudo` to switch to `run_as` if not running as root
+    pub run_as_helper: Option<String>,
+    /// Without a policy the commands inherit Cinderella's environment
+    pub environment: Option<EnvironmentPolicy>,
+    #[serde(default)]
+    pub concurrency: Concurrency,
 }
 
 #[derive(Deserialize, Debug)]
@@ -36,44 +63,205 @@ pub struct Dashboard {
     pub folder: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct Scheduler {
+    pub state_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Logs {
+    /// Directory in which the log files of all builds are stored
+    pub root: Option<String>,
+    /// Number of lines at the end of the output of each step that are
+    /// kept in memory, e.g. for the notification mail
+    #[serde(default = "default_tail_lines")]
+    pub tail_lines: usize,
+}
+
+fn default_tail_lines() -> usize {
+    crate::logs::DEFAULT_TAIL_LINES
+}
+
+#[derive(Deserialize, Debug)]
+pub struct History {
+    /// Directory in which the records of all builds are stored
+    pub dir: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Daemon {
+    pub spool_dir: Option<String>,
+    /// Number of builds that run at the same time
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Project {
+    pub repo_url: String,
+    pub branch: Option<String>,
+    /// CI configuration of scheduled builds instead of `.cinderella.toml`,
+    /// like `--file`
+    pub cinderella_filepath: Option<String>,
+    pub run_as: Option<String>,
+    /// Shell script that is sourced to set up the environment of builds
+    pub profile: Option<String>,
+    /// Overrides the global concurrency policy for this project
+    pub concurrency: Option<Policy>,
+    /// Default priority of enqueued builds
+    pub priority: Option<i32>,
+}
+
+/// Controls which environment variables the commands of a build get
+#[derive(Deserialize, Debug, Default)]
+pub struct EnvironmentPolicy {
+    /// Variables passed through from Cinderella's environment
+    #[serde(default)]
+    pub pass: Vec<String>,
+    /// Variables set to fixed values
+    #[serde(default)]
+    pub set: HashMap<String, String>,
+}
+
+/// Controls builds of the same project and ref that overlap
+#[derive(Deserialize, Debug, Default)]
+pub struct Concurrency {
+    #[serde(default)]
+    pub policy: Policy,
+    /// Directory for the lock files, shared by all Cinderella processes
+    pub lock_dir: Option<String>,
+}
+
+/// A build host on which pipelines can be executed via SSH
+#[derive(Deserialize, Debug, Clone)]
+pub struct Host {
+    pub address: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_file: Option<String>,
+    /// Base directory for work dirs on the build host
+    pub workdir: Option<String>,
+}
+
+/// A root filesystem in which pipelines can be executed isolated from the
+/// server
+#[derive(Deserialize, Debug, Clone)]
+pub struct Sandbox {
+    /// Tarball with the root filesystem, unpacked for every pipeline
+    pub rootfs: String,
+    /// Directories or files of the server that are mounted read-only at the
+    /// same path inside the sandbox
+    #[serde(default)]
+    pub binds: Vec<String>,
+}
+
 impl CinderellaConfig {
     pub fn from_file(path: PathBuf) -> CinderellaConfig {
         match fs::read_to_string(path) {
             Ok(contents) => {
                 toml::from_str(&contents).expect("Configuration invalid")
             },
-            _ => CinderellaConfig {
-                email: None,
-                secrets: None,
-                dashboard: None,
-            }
+            _ => CinderellaConfig::default(),
+        }
+    }
+
+    /// Finds the project settings for a repository URL
+    pub fn project(&self, repo_url: &str) -> Option<&Project> {
+        let repo_url = repo_url.trim_end_matches('/');
+
+        self.projects.iter()
+            .find(|project| project.repo_url.trim_end_matches('/') == repo_url)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// Build started by the post-update hook or manually with `run`
+    Push,
+    /// Build started by the scheduler because a cron expression was due
+    Schedule,
+}
+
+impl Trigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Trigger::Push => "push",
+            Trigger::Schedule => "schedule",
+        }
+    }
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(trigger: &str) -> Result<Trigger, String> {
+        match trigger {
+            "push" => Ok(Trigger::Push),
+            "schedule" => Ok(Trigger::Schedule),
+            _ => Err(format!("Unknown trigger {}", trigger)),
+        }
+    }
+}
+
+/// How the query commands print their results
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    /// Machine-readable output for scripts
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<OutputFormat, String> {
+        match format {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown format {}, expected text or json", format)),
         }
     }
 }
 
+/// The name of a project, the last component of its repository URL
+pub fn project_name(repo_url: &str) -> String {
+    let components: Vec<&str> = repo_url.split('/').collect();
+
+    // TODO: Make more Rusty
+    // TODO: Always use the canonical path for getting project name? e.g.
+    // when user defines "." as path, still use the folder name
+    if components.last().is_some() && components.last().unwrap().to_string() != "" {
+        return components.last().unwrap().to_string();
+    } else if components.len() >= 2 {
+        return components[components.len() - 2].to_string();
+    } else {
+        return "".to_string();
+    }
+}
+
 pub struct ExecutionConfig {
     pub repo_url: String,
     pub branch: Option<String>,
     pub tag: Option<String>,
     pub cinderella_filepath: Option<String>,
+    pub pipelines: Vec<String>,
+    pub skip_pipelines: Vec<String>,
+    pub trigger: Trigger,
+    /// Exact commit to build instead of the head of the branch or tag
+    pub commit: Option<String>,
+    /// Number of the build that is repeated by this build
+    pub rerun_of: Option<u64>,
 }
 
 impl ExecutionConfig {
     // TODO: This approach only works for URLs, not for local paths.
     // TODO: Move the name() function to the CodeSource
     pub fn name(&self) -> String {
-        let components: Vec<&str> = self.repo_url.split('/').collect();
-
-        // TODO: Make more Rusty
-        // TODO: Always use the canonical path for getting project name? e.g.
-        // when user defines "." as path, still use the folder name
-        if components.last().is_some() && components.last().unwrap().to_string() != "" {
-            return components.last().unwrap().to_string();
-        } else if components.len() >= 2 {
-            return components[components.len() - 2].to_string();
-        } else {
-            return "".to_string();
-        }
+        project_name(&self.repo_url)
     }
 
     pub fn cinderella_file(&self, folder: &PathBuf) -> PathBuf {
@@ -89,12 +277,36 @@ impl ExecutionConfig {
         filepath
     }
 
+    pub fn pipeline_selection(&self) -> Selection {
+        Selection {
+            pipelines: self.pipelines.clone(),
+            skip: self.skip_pipelines.clone(),
+            scheduled_only: self.trigger == Trigger::Schedule,
+        }
+    }
+
     pub fn secrets_file(&self, folder: &PathBuf) -> PathBuf {
         let mut secrets_file = folder.clone();
         secrets_file.push(".cinderella");
         secrets_file.push("secrets");
         secrets_file
     }
+
+    /// A push build of the branch without any further options
+    #[cfg(test)]
+    pub fn for_branch(repo_url: &str, branch: &str) -> ExecutionConfig {
+        ExecutionConfig {
+            repo_url: String::from(repo_url),
+            branch: Some(String::from(branch)),
+            tag: None,
+            cinderella_filepath: None,
+            pipelines: vec![],
+            skip_pipelines: vec![],
+            trigger: Trigger::Push,
+            commit: None,
+            rerun_of: None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -105,28 +317,20 @@ mod tests {
 
     #[test]
     fn test_extract_project_name_from_path() {
-        let config = ExecutionConfig {
-            repo_url: "/path/to/repo".to_string(),
-            branch: Some("master".to_string()),
-            tag: None,
-            cinderella_filepath: None,
-        };
+        let config = ExecutionConfig::for_branch("/path/to/repo", "master");
         assert_eq!(config.name(), "repo");
 
         // even if the path ends with a slash, we should extract the right
         // project name
-        let config = ExecutionConfig {
-            repo_url: "/path/to/repo.git/".to_string(),
-            branch: Some("master".to_string()),
-            tag: None,
-            cinderella_filepath: None,
-        };
+        let config = ExecutionConfig::for_branch("/path/to/repo.git/", "master");
         assert_eq!(config.name(), "repo.git");
     }
 
     #[test]
     fn test_load_valid_config() {
         let config = r#"
+            run_as = "cinderella-build"
+
             [email]
             server = "localhost"
             user = "user"
@@ -136,6 +340,48 @@ mod tests {
 
             [dashboard]
             folder = "/var/www/cinderella"
+
+            [[projects]]
+            repo_url = "/srv/git/cinderella.git"
+            branch = "master"
+
+            [[projects]]
+            repo_url = "/srv/git/website.git"
+            run_as = "www-build"
+            profile = "/srv/cinderella/website.profile"
+            concurrency = "cancel"
+            priority = 10
+
+            [hosts.arm]
+            address = "arm.example.com"
+            user = "cinderella"
+            port = 2222
+
+            [sandboxes.debian]
+            rootfs = "/srv/rootfs/debian.tar.gz"
+            binds = ["/etc/resolv.conf"]
+
+            [environment]
+            pass = ["PATH", "LANG"]
+            set = { CI = "true" }
+
+            [limits]
+            memory = "4G"
+            cpu_time = "1h"
+
+            [daemon]
+            workers = 4
+
+            [logs]
+            root = "/var/log/cinderella"
+            tail_lines = 200
+
+            [history]
+            dir = "/var/lib/cinderella/history"
+
+            [concurrency]
+            policy = "skip"
+            lock_dir = "/var/lib/cinderella/locks"
         "#;
         let mut tmpfile = NamedTempFile::new().unwrap();
         let f = tmpfile.as_file_mut();
@@ -143,6 +389,28 @@ mod tests {
 
         let config = CinderellaConfig::from_file(tmpfile.path().to_path_buf());
 
+        assert_eq!(config.run_as, Some(String::from("cinderella-build")));
+        let website = config.project("/srv/git/website.git/").unwrap();
+        assert_eq!(website.run_as, Some(String::from("www-build")));
+        assert_eq!(website.profile,
+                   Some(String::from("/srv/cinderella/website.profile")));
+        assert_eq!(website.concurrency, Some(Policy::Cancel));
+        assert_eq!(website.priority, Some(10));
+        assert_eq!(config.concurrency.policy, Policy::Skip);
+        let logs = config.logs.as_ref().unwrap();
+        assert_eq!(logs.root, Some(String::from("/var/log/cinderella")));
+        assert_eq!(logs.tail_lines, 200);
+        assert_eq!(config.history.as_ref().unwrap().dir, "/var/lib/cinderella/history");
+        let daemon = config.daemon.as_ref().unwrap();
+        assert_eq!(daemon.workers, 4);
+        assert!(daemon.spool_dir.is_none());
+        assert_eq!(config.concurrency.lock_dir,
+                   Some(String::from("/var/lib/cinderella/locks")));
+
+        let environment = config.environment.as_ref().unwrap();
+        assert_eq!(environment.pass, vec!["PATH", "LANG"]);
+        assert_eq!(environment.set["CI"], "true");
+
         let email = config.email.unwrap();
         assert_eq!(email.server, "localhost");
         assert_eq!(email.user, "user");
@@ -152,6 +420,25 @@ mod tests {
 
         let dashboard = config.dashboard.unwrap();
         assert_eq!(dashboard.folder, "/var/www/cinderella");
+
+        assert_eq!(config.projects.len(), 2);
+        assert_eq!(config.projects[0].repo_url, "/srv/git/cinderella.git");
+        assert_eq!(config.projects[0].branch, Some(String::from("master")));
+        assert!(config.projects[1].branch.is_none());
+
+        let host = &config.hosts["arm"];
+        assert_eq!(host.address, "arm.example.com");
+        assert_eq!(host.user, Some(String::from("cinderella")));
+        assert_eq!(host.port, Some(2222));
+        assert!(host.identity_file.is_none());
+
+        let sandbox = &config.sandboxes["debian"];
+        assert_eq!(sandbox.rootfs, "/srv/rootfs/debian.tar.gz");
+        assert_eq!(sandbox.binds, vec!["/etc/resolv.conf"]);
+
+        assert_eq!(config.limits.memory, Some(4 << 30));
+        assert_eq!(config.limits.cpu_time, Some(3600));
+        assert!(config.limits.processes.is_none());
     }
 
     #[test]
@@ -170,12 +457,7 @@ mod tests {
         // path to the secrets file changes, so that we can mention this in
         // the change notes
 
-        let exec_config = ExecutionConfig {
-            repo_url: String::from("https://example.com/my-repo.git"),
-            branch: Some(String::from("master")),
-            tag: None,
-            cinderella_filepath: None,
-        };
+        let exec_config = ExecutionConfig::for_branch("https://example.com/my-repo.git", "master");
 
         let base_path = PathBuf::from("/tmp/work-dir");
         let secrets_file = exec_config.secrets_file(&base_path);
@@ -185,4 +467,11 @@ mod tests {
             PathBuf::from("/tmp/work-dir/.cinderella/secrets")
         );
     }
+
+    #[test]
+    fn test_parse_output_format() {
+        assert_eq!("json".parse::<OutputFormat>(), Ok(OutputFormat::Json));
+        assert_eq!("text".parse::<OutputFormat>(), Ok(OutputFormat::Text));
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
 }
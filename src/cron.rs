@@ -0,0 +1,240 @@
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, NaiveDateTime, Timelike};
+
+/// A cron expression in the standard five field format
+/// `minute hour day-of-month month day-of-week`
+///
+/// Each field supports `*`, single values, ranges (`1-5`), steps (`*/15`,
+/// `0-30/10`) and lists (`1,15,30`). Day of week uses `0` or `7` for Sunday
+/// like crontab does. If both day of month and day of week are restricted,
+/// a time matches if either of them matches.
+#[derive(Debug, PartialEq)]
+pub struct Schedule {
+    minutes: Vec<u32>,
+    hours: Vec<u32>,
+    days: Vec<u32>,
+    months: Vec<u32>,
+    weekdays: Vec<u32>,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl Schedule {
+    pub fn matches(&self, time: &NaiveDateTime) -> bool {
+        let day_matches = self.days.contains(&time.day());
+        // cron counts the days of the week from Sunday = 0
+        let weekday = time.weekday().num_days_from_sunday();
+        let weekday_matches = self.weekdays.contains(&weekday);
+
+        let date_matches = if self.days_restricted && self.weekdays_restricted {
+            day_matches || weekday_matches
+        } else {
+            day_matches && weekday_matches
+        };
+
+        date_matches
+            && self.minutes.contains(&time.minute())
+            && self.hours.contains(&time.hour())
+            && self.months.contains(&time.month())
+    }
+
+    /// Returns the first matching minute strictly after `time` and not
+    /// after `until`
+    ///
+    /// The search is bounded, because cron expressions like `0 0 30 2 *`
+    /// never match at all.
+    pub fn next_until(&self, time: &NaiveDateTime, until: &NaiveDateTime)
+        -> Option<NaiveDateTime>
+    {
+        let mut candidate = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+
+        while candidate <= *until {
+            if self.matches(&candidate) {
+                return Some(candidate);
+            }
+            candidate += Duration::minutes(1);
+        }
+
+        None
+    }
+
+    /// Checks if the schedule was due in the interval `(from, to]`
+    pub fn due_between(&self, from: &NaiveDateTime, to: &NaiveDateTime)
+        -> bool
+    {
+        self.next_until(from, to).is_some()
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Expected 5 fields in cron expression, found {}: {}",
+                fields.len(), expression));
+        }
+
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // 7 is an alias for Sunday
+        if weekdays.contains(&7) {
+            weekdays.retain(|&day| day != 7);
+            if !weekdays.contains(&0) {
+                weekdays.insert(0, 0);
+            }
+        }
+
+        Ok(Schedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<u32>, String> {
+    let mut values = Vec::new();
+
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(idx) => {
+                let step = parse_number(&part[idx + 1..])?;
+                if step == 0 {
+                    return Err(format!("Step must not be zero: {}", part));
+                }
+                (&part[..idx], step)
+            },
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(idx) = range.find('-') {
+            (parse_number(&range[..idx])?, parse_number(&range[idx + 1..])?)
+        } else {
+            let value = parse_number(range)?;
+            // "5/10" means "starting at 5 every 10"
+            if step > 1 { (value, max) } else { (value, value) }
+        };
+
+        if start < min || end > max || start > end {
+            return Err(format!(
+                "Value out of range {}-{}: {}", min, max, part));
+        }
+
+        values.extend((start..=end).step_by(step as usize));
+    }
+
+    values.sort_unstable();
+    values.dedup();
+
+    Ok(values)
+}
+
+fn parse_number(value: &str) -> Result<u32, String> {
+    value.parse::<u32>()
+        .map_err(|_| format!("Invalid number in cron expression: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn time(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+            .and_hms_opt(h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn test_nightly_schedule() {
+        let schedule: Schedule = "0 3 * * *".parse().unwrap();
+
+        assert!(schedule.matches(&time(2019, 10, 1, 3, 0)));
+        assert!(!schedule.matches(&time(2019, 10, 1, 3, 1)));
+        assert!(!schedule.matches(&time(2019, 10, 1, 4, 0)));
+    }
+
+    #[test]
+    fn test_weekday_uses_crontab_numbering() {
+        // 2019-10-06 was a Sunday, 2019-10-07 a Monday
+        let sunday: Schedule = "0 0 * * 0".parse().unwrap();
+        let sunday_alias: Schedule = "0 0 * * 7".parse().unwrap();
+        let monday: Schedule = "0 0 * * 1".parse().unwrap();
+
+        assert!(sunday.matches(&time(2019, 10, 6, 0, 0)));
+        assert_eq!(sunday, sunday_alias);
+        assert!(monday.matches(&time(2019, 10, 7, 0, 0)));
+        assert!(!monday.matches(&time(2019, 10, 6, 0, 0)));
+    }
+
+    #[test]
+    fn test_lists_ranges_and_steps() {
+        let schedule: Schedule = "*/15 9-17 * * 1-5".parse().unwrap();
+
+        assert!(schedule.matches(&time(2019, 10, 7, 9, 45)));
+        assert!(!schedule.matches(&time(2019, 10, 7, 9, 50)));
+        assert!(!schedule.matches(&time(2019, 10, 7, 18, 0)));
+        assert!(!schedule.matches(&time(2019, 10, 6, 9, 0)));
+
+        let schedule: Schedule = "0 0 1,15 * *".parse().unwrap();
+        assert!(schedule.matches(&time(2019, 10, 15, 0, 0)));
+        assert!(!schedule.matches(&time(2019, 10, 14, 0, 0)));
+    }
+
+    #[test]
+    fn test_day_of_month_or_day_of_week() {
+        // first of the month or any Monday
+        let schedule: Schedule = "0 0 1 * 1".parse().unwrap();
+
+        assert!(schedule.matches(&time(2019, 10, 1, 0, 0)));
+        assert!(schedule.matches(&time(2019, 10, 7, 0, 0)));
+        assert!(!schedule.matches(&time(2019, 10, 8, 0, 0)));
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        assert!("0 3 * *".parse::<Schedule>().is_err());
+        assert!("60 * * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("a * * * *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_due_between_detects_missed_runs() {
+        let schedule: Schedule = "0 3 * * *".parse().unwrap();
+
+        assert_eq!(
+            schedule.next_until(&time(2019, 10, 1, 2, 59), &time(2019, 10, 2, 0, 0)),
+            Some(time(2019, 10, 1, 3, 0)));
+        assert_eq!(
+            schedule.next_until(&time(2019, 10, 1, 3, 0), &time(2019, 10, 2, 2, 59)),
+            None);
+
+        // scheduler was down from 1am to 5am
+        assert!(schedule.due_between(
+            &time(2019, 10, 1, 1, 0), &time(2019, 10, 1, 5, 0)));
+        assert!(!schedule.due_between(
+            &time(2019, 10, 1, 3, 0), &time(2019, 10, 1, 5, 0)));
+
+        // never matches, only the interval is searched
+        let never: Schedule = "0 0 30 2 *".parse().unwrap();
+        assert!(!never.due_between(&time(2019, 10, 1, 3, 0), &time(2020, 10, 1, 3, 0)));
+    }
+}
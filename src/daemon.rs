@@ -0,0 +1,415 @@
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use duct::{cmd, Handle};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ExecutionConfig;
+
+/// How often the spool directory is checked for new jobs
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A build waiting in the spool directory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Job {
+    pub repo_url: String,
+    pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub cinderella_filepath: Option<String>,
+    #[serde(default)]
+    pub pipelines: Vec<String>,
+    #[serde(default)]
+    pub skip_pipelines: Vec<String>,
+    /// Jobs with a higher priority are started first
+    #[serde(default)]
+    pub priority: i32,
+}
+
+impl Job {
+    pub fn new(exec_config: &ExecutionConfig, priority: i32) -> Job {
+        Job {
+            repo_url: exec_config.repo_url.clone(),
+            branch: exec_config.branch.clone(),
+            tag: exec_config.tag.clone(),
+            cinderella_filepath: exec_config.cinderella_filepath.clone(),
+            pipelines: exec_config.pipelines.clone(),
+            skip_pipelines: exec_config.skip_pipelines.clone(),
+            priority,
+        }
+    }
+
+    /// Arguments for `cinderella run` that execute the job
+    pub fn run_args(&self) -> Vec<String> {
+        let mut args = vec![String::from("run")];
+
+        let options = [
+            ("--branch", &self.branch),
+            ("--tag", &self.tag),
+            ("--file", &self.cinderella_filepath),
+        ];
+        for (option, value) in options.iter() {
+            if let Some(value) = value {
+                args.push(option.to_string());
+                args.push(value.clone());
+            }
+        }
+        for pipeline in &self.pipelines {
+            args.push(String::from("--pipeline"));
+            args.push(pipeline.clone());
+        }
+        for pipeline in &self.skip_pipelines {
+            args.push(String::from("--skip"));
+            args.push(pipeline.clone());
+        }
+
+        args.push(String::from("--"));
+        args.push(self.repo_url.clone());
+
+        args
+    }
+
+    pub fn describe(&self) -> String {
+        let git_ref = match (&self.branch, &self.tag) {
+            (Some(branch), _) => branch.clone(),
+            (None, Some(tag)) => format!("tag {}", tag),
+            (None, None) => String::from("default branch"),
+        };
+
+        format!("{} {} (priority {})", self.repo_url, git_ref, self.priority)
+    }
+}
+
+/// The spool directory of the daemon
+///
+/// New jobs are written to `tmp` and moved to `queue` when complete. The
+/// daemon claims a job by moving it to `running` and deletes it when the
+/// build has finished. The output of each build is written to `logs`.
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: &Path) -> Spool {
+        Spool {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn subdir(&self, name: &str) -> Result<PathBuf, String> {
+        let dir = self.dir.join(name);
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Could not create spool dir {:?}: {}", dir, e))?;
+
+        Ok(dir)
+    }
+
+    /// Adds a job to the queue and returns its id
+    pub fn enqueue(&self, job: &Job) -> Result<String, String> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or_default();
+        let id = format!("{:020}-{}", nanos, std::process::id());
+
+        let contents = toml::to_string(job)
+            .map_err(|e| format!("Could not serialize job: {}", e))?;
+        let tmp_path = self.subdir("tmp")?.join(format!("{}.toml", id));
+        fs::write(&tmp_path, contents)
+            .map_err(|e| format!("Could not write job {:?}: {}", tmp_path, e))?;
+
+        // the daemon must never see a partially written job
+        let queue_path = self.subdir("queue")?.join(format!("{}.toml", id));
+        fs::rename(&tmp_path, &queue_path)
+            .map_err(|e| format!("Could not enqueue job: {}", e))?;
+
+        Ok(id)
+    }
+
+    /// The queued jobs in the order they will be started
+    pub fn queued(&self) -> Result<Vec<(String, Job)>, String> {
+        let mut jobs = self.jobs("queue")?;
+
+        // ids sort in the order the jobs were enqueued
+        jobs.sort_by(|(id_a, job_a), (id_b, job_b)| {
+            job_b.priority.cmp(&job_a.priority).then(id_a.cmp(id_b))
+        });
+
+        Ok(jobs)
+    }
+
+    /// The jobs that are currently built
+    pub fn running(&self) -> Result<Vec<(String, Job)>, String> {
+        let mut jobs = self.jobs("running")?;
+        jobs.sort_by(|(id_a, _), (id_b, _)| id_a.cmp(id_b));
+
+        Ok(jobs)
+    }
+
+    fn jobs(&self, state: &str) -> Result<Vec<(String, Job)>, String> {
+        let dir = self.subdir(state)?;
+        let entries = fs::read_dir(&dir)
+            .map_err(|e| format!("Could not read spool dir {:?}: {}", dir, e))?;
+
+        let mut jobs = Vec::new();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let id = match path.file_stem() {
+                Some(id) if path.extension() == Some("toml".as_ref()) => {
+                    id.to_string_lossy().to_string()
+                },
+                _ => continue,
+            };
+
+            match fs::read_to_string(&path).map_err(|e| e.to_string())
+                .and_then(|contents| toml::from_str(&contents).map_err(|e| e.to_string()))
+            {
+                Ok(job) => jobs.push((id, job)),
+                Err(e) => eprintln!("Ignoring invalid job {:?}: {}", path, e),
+            }
+        }
+
+        Ok(jobs)
+    }
+
+    /// Moves a job between states, returns `false` if it does not exist
+    /// (anymore)
+    fn move_job(&self, id: &str, from: &str, to: &str) -> Result<bool, String> {
+        let file_name = format!("{}.toml", id);
+        let source = self.subdir(from)?.join(&file_name);
+        let target = self.subdir(to)?.join(&file_name);
+
+        match fs::rename(&source, &target) {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(format!("Could not move job {}: {}", id, e)),
+        }
+    }
+
+    fn claim(&self, id: &str) -> Result<bool, String> {
+        self.move_job(id, "queue", "running")
+    }
+
+    fn requeue(&self, id: &str) -> Result<bool, String> {
+        self.move_job(id, "running", "queue")
+    }
+
+    fn finish(&self, id: &str) {
+        let path = self.dir.join("running").join(format!("{}.toml", id));
+        if let Err(e) = fs::remove_file(&path) {
+            eprintln!("Could not remove finished job {:?}: {}", path, e);
+        }
+    }
+
+    /// Puts jobs that were running when the daemon stopped back into the
+    /// queue
+    fn recover(&self) -> Result<usize, String> {
+        let running = self.running()?;
+
+        for (id, _) in &running {
+            self.requeue(id)?;
+        }
+
+        Ok(running.len())
+    }
+
+    fn log_path(&self, id: &str) -> Result<PathBuf, String> {
+        Ok(self.subdir("logs")?.join(format!("{}.log", id)))
+    }
+}
+
+/// Number of SIGTERM or SIGINT signals the daemon received
+static SHUTDOWN_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN_REQUESTS.fetch_add(1, Ordering::SeqCst);
+}
+
+struct Worker {
+    id: String,
+    handle: Handle,
+}
+
+/// Runs the queued jobs with up to `workers` builds at the same time
+///
+/// Each build is executed by `cinderella run` in a child process, because
+/// a build changes the current directory of its process. On the first
+/// SIGTERM or SIGINT the daemon stops starting jobs and waits for the
+/// running builds. On the second signal it cancels them and puts them back
+/// into the queue, so that they are built again after a restart.
+pub fn run(spool: &Spool, workers: usize) {
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+
+    let executable = std::env::current_exe().expect("Could not determine executable");
+
+    match spool.recover() {
+        Ok(0) => (),
+        Ok(count) => println!("Queued {} interrupted jobs again", count),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return;
+        },
+    }
+    println!("Waiting for jobs in {:?} with {} workers", spool.dir, workers);
+
+    let mut running: Vec<Worker> = Vec::new();
+    let mut cancelled = false;
+
+    loop {
+        running.retain(|worker| !reap(spool, worker, cancelled));
+
+        let shutdown_requests = SHUTDOWN_REQUESTS.load(Ordering::SeqCst);
+        if shutdown_requests >= 2 && !cancelled {
+            println!("Cancelling {} running builds", running.len());
+            for worker in &running {
+                for pid in worker.handle.pids() {
+                    unsafe {
+                        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+                    }
+                }
+            }
+            cancelled = true;
+        }
+
+        if shutdown_requests > 0 {
+            if running.is_empty() {
+                println!("Daemon stopped");
+                return;
+            }
+        } else if running.len() < workers {
+            match start_jobs(spool, &executable, workers - running.len()) {
+                Ok(started) => running.extend(started),
+                Err(msg) => eprintln!("{}", msg),
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Removes the job of a finished build from the spool, returns whether the
+/// build has finished
+fn reap(spool: &Spool, worker: &Worker, cancelled: bool) -> bool {
+    let status = match worker.handle.try_wait() {
+        Ok(Some(output)) => output.status.to_string(),
+        Ok(None) => return false,
+        Err(e) => e.to_string(),
+    };
+
+    if cancelled {
+        println!("Build of job {} cancelled ({}), queued again", worker.id, status);
+        if let Err(msg) = spool.requeue(&worker.id) {
+            eprintln!("{}", msg);
+        }
+    } else {
+        println!("Build of job {} finished ({})", worker.id, status);
+        spool.finish(&worker.id);
+    }
+
+    true
+}
+
+fn start_jobs(spool: &Spool, executable: &Path, count: usize)
+    -> Result<Vec<Worker>, String>
+{
+    let mut started = Vec::new();
+
+    for (id, job) in spool.queued()? {
+        if started.len() == count {
+            break;
+        }
+        // another daemon might have claimed the job in the meantime
+        if !spool.claim(&id)? {
+            continue;
+        }
+
+        let log_path = spool.log_path(&id)?;
+        println!("Starting job {}: {}, log in {:?}", id, job.describe(), log_path);
+
+        // in its own process group, so that a Ctrl+C on the daemon does
+        // not cancel the builds
+        let result = cmd(executable, job.run_args())
+            .stderr_to_stdout()
+            .stdout_path(&log_path)
+            .stdin_null()
+            .unchecked()
+            .before_spawn(|cmd| {
+                cmd.process_group(0);
+                Ok(())
+            })
+            .start();
+
+        match result {
+            Ok(handle) => started.push(Worker { id, handle }),
+            Err(e) => {
+                eprintln!("Could not start build of job {}: {}", id, e);
+                spool.finish(&id);
+            },
+        }
+    }
+
+    Ok(started)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(repo_url: &str, priority: i32) -> Job {
+        Job {
+            repo_url: String::from(repo_url),
+            branch: Some(String::from("master")),
+            tag: None,
+            cinderella_filepath: None,
+            pipelines: vec![String::from("deploy")],
+            skip_pipelines: vec![],
+            priority,
+        }
+    }
+
+    #[test]
+    fn test_jobs_are_ordered_by_priority() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::new(dir.path());
+
+        let first = spool.enqueue(&job("/srv/git/first.git", 0)).unwrap();
+        let urgent = spool.enqueue(&job("/srv/git/urgent.git", 10)).unwrap();
+        let second = spool.enqueue(&job("/srv/git/second.git", 0)).unwrap();
+
+        let ids = spool.queued().unwrap().into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<String>>();
+        assert_eq!(ids, vec![urgent, first, second]);
+    }
+
+    #[test]
+    fn test_interrupted_jobs_are_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::new(dir.path());
+        let id = spool.enqueue(&job("/srv/git/website.git", 0)).unwrap();
+
+        assert!(spool.claim(&id).unwrap());
+        assert!(!spool.claim(&id).unwrap());
+        assert!(spool.queued().unwrap().is_empty());
+        assert_eq!(spool.running().unwrap()[0].1, job("/srv/git/website.git", 0));
+
+        assert_eq!(spool.recover().unwrap(), 1);
+        assert_eq!(spool.queued().unwrap()[0].0, id);
+        assert!(spool.running().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_run_args() {
+        assert_eq!(job("/srv/git/website.git", 5).run_args(), vec![
+            "run", "--branch", "master", "--pipeline", "deploy",
+            "--", "/srv/git/website.git",
+        ]);
+    }
+}
@@ -1,9 +1,11 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::prelude::Write;
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions};
 
 use log::{debug, info};
 
+use crate::execution::StepResult;
+
 pub enum BuildStatus {
     Success,
     Error(String),
@@ -39,10 +41,48 @@ pub fn generate_status_icon(project: &str, branch: &str, status: BuildStatus, di
     Ok(())
 }
 
+/// Appends the resource usage of all executed steps of a build to the
+/// history of the branch, a CSV file that can be used to spot trends
+pub fn append_step_history(project: &str, branch: &str, steps: &[StepResult], dir: &Path) -> Result<(), String> {
+    let mut path = dir.to_path_buf();
+    path.push(project);
+    let _ = fs::create_dir_all(path.as_path());
+    path.push(format!("{}-steps.csv", branch));
+
+    let is_new = !path.exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)
+        .map_err(|e| format!("{}, File: {}", e, path.to_string_lossy()))?;
+
+    let mut lines = String::new();
+    if is_new {
+        lines.push_str("time,command,success,wall_seconds,user_seconds,system_seconds,max_rss_bytes\n");
+    }
+
+    let time = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S");
+    for step in steps {
+        let usage = match &step.usage {
+            Some(usage) => format!(
+                "{:.3},{:.3},{:.3},{}",
+                usage.wall_time.as_secs_f64(), usage.user_time.as_secs_f64(),
+                usage.system_time.as_secs_f64(), usage.max_rss),
+            None => String::from(",,,"),
+        };
+
+        lines.push_str(&format!(
+            "{},\"{}\",{},{}\n",
+            time, step.command.replace('"', "\"\""), step.is_success(), usage));
+    }
+
+    file.write_all(lines.as_bytes())
+        .map_err(|e| format!("{}, File: {}", e, path.to_string_lossy()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::io::prelude::Read;
+    use std::time::Duration;
+    use crate::usage::Usage;
 
     #[test]
     fn generate_success_status_icon() {
@@ -81,4 +121,37 @@ mod tests {
 
         assert_eq!(buffer, ICON_ERROR);
     }
+
+    #[test]
+    fn append_to_step_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let pathbuf = dir.path().to_path_buf();
+        let steps = vec![
+            StepResult {
+                pipeline: String::from("test"),
+                command: String::from("cargo test --features \"a b\""),
+                output: String::new(),
+                truncated: false,
+                failure: None,
+                usage: Some(Usage {
+                    wall_time: Duration::from_millis(1500),
+                    user_time: Duration::from_millis(1200),
+                    system_time: Duration::from_millis(100),
+                    max_rss: 4096,
+                }),
+                log_file: None,
+            },
+            StepResult::not_executed(String::from("%MISSING"), String::from("Unknown variable")),
+        ];
+
+        append_step_history("myproject", "master", &steps, &pathbuf).unwrap();
+        append_step_history("myproject", "master", &steps[..1], &pathbuf).unwrap();
+
+        let history = fs::read_to_string(dir.path().join("myproject/master-steps.csv")).unwrap();
+        let lines: Vec<&str> = history.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("time,command,"));
+        assert!(lines[1].ends_with(",\"cargo test --features \"\"a b\"\"\",true,1.500,1.200,0.100,4096"));
+        assert!(lines[2].ends_with(",\"%MISSING\",false,,,,"));
+    }
 }
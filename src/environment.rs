@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use duct::cmd;
+
+use crate::config::{CinderellaConfig, ExecutionConfig};
+
+/// Variables set by the shell itself, which are not taken over from a
+/// sourced profile
+const SHELL_VARIABLES: &[&str] = &["_", "PWD", "OLDPWD", "SHLVL"];
+
+/// The environment variables for the commands of a build
+///
+/// Without an environment policy in the Cinderella configuration, the
+/// commands inherit Cinderella's environment. With a policy they only get
+/// the passed through and fixed variables and a fresh `HOME` and `TMPDIR`,
+/// which are deleted when the value is dropped. The home must not exist
+/// yet, so that an unrelated directory is never taken over and deleted.
+pub struct Environment {
+    vars: HashMap<String, String>,
+    home: Option<PathBuf>,
+}
+
+impl Environment {
+    /// Cinderella's own environment
+    pub fn inherited() -> Environment {
+        Environment {
+            vars: env::vars().collect(),
+            home: None,
+        }
+    }
+
+    pub fn from_config(
+        cinderella_config: &CinderellaConfig,
+        exec_config: &ExecutionConfig,
+        workdir: &Path,
+        home: &Path) -> Result<Environment, String>
+    {
+        let mut environment = match &cinderella_config.environment {
+            None => Environment::inherited(),
+            Some(policy) => {
+                let vars = env::vars()
+                    .filter(|(name, _)| policy.pass.contains(name))
+                    .chain(policy.set.clone())
+                    .collect();
+
+                Environment {
+                    vars,
+                    home: None,
+                }
+            },
+        };
+
+        if cinderella_config.environment.is_some() {
+            create_private_dir(home)
+                .map_err(|e| format!("Could not create build home {:?}: {}", home, e))?;
+            // only a home created here is deleted again
+            environment.home = Some(home.to_path_buf());
+
+            let tmp = home.join("tmp");
+            create_private_dir(&tmp)
+                .map_err(|e| format!("Could not create build home: {}", e))?;
+
+            environment.set("HOME", &home.to_string_lossy());
+            environment.set("TMPDIR", &tmp.to_string_lossy());
+        }
+
+        let build_variables = build_variables(exec_config, workdir);
+        environment.vars.extend(build_variables.clone());
+
+        let profile = cinderella_config.project(&exec_config.repo_url)
+            .and_then(|project| project.profile.as_ref());
+        if let Some(profile) = profile {
+            println!("Sourcing profile {}", profile);
+            environment.vars = source_profile(profile, &environment.vars)?;
+
+            // the profile cannot change the description of the build
+            environment.vars.extend(build_variables);
+        }
+
+        Ok(environment)
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.to_string(), value.to_string());
+    }
+
+    /// All variables for the commands of a pipeline
+    pub fn for_pipeline(&self, pipeline: &str) -> HashMap<String, String> {
+        let mut vars = self.vars.clone();
+        vars.insert(String::from("CINDERELLA_PIPELINE"), pipeline.to_string());
+
+        vars
+    }
+}
+
+impl Drop for Environment {
+    fn drop(&mut self) {
+        if let Some(home) = &self.home {
+            if let Err(e) = fs::remove_dir_all(home) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    eprintln!("Could not delete build home {:?}: {}", home, e);
+                }
+            }
+        }
+    }
+}
+
+/// The fresh home directory of a build in a cloned work dir
+///
+/// It is next to the work dir, so that the build cannot delete it. Builds
+/// of a local working tree get a home in a random temporary directory
+/// instead.
+pub fn home_dir(workdir: &Path) -> Result<PathBuf, String> {
+    let mut home_name = workdir.file_name()
+        .ok_or_else(|| format!("Invalid work dir: {:?}", workdir))?
+        .to_os_string();
+    home_name.push("-home");
+
+    Ok(workdir.with_file_name(home_name))
+}
+
+/// Creates a directory that only the owner can access, it must not exist
+/// yet
+///
+/// Missing parents are created as well, e.g. `/tmp/cinderella` for the
+/// home of a local build.
+fn create_private_dir(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::create_dir(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))
+}
+
+/// Variables that describe the build
+fn build_variables(exec_config: &ExecutionConfig, workdir: &Path)
+    -> HashMap<String, String>
+{
+    let reftype = match (&exec_config.branch, &exec_config.tag) {
+        (_, Some(_)) => "tag",
+        (Some(_), None) => "branch",
+        (None, None) => "",
+    };
+
+    vec![
+        ("CINDERELLA_PROJECT", exec_config.name()),
+        ("CINDERELLA_REPO_URL", exec_config.repo_url.clone()),
+        ("CINDERELLA_REFTYPE", reftype.to_string()),
+        ("CINDERELLA_BRANCH", exec_config.branch.clone().unwrap_or_default()),
+        ("CINDERELLA_TAG", exec_config.tag.clone().unwrap_or_default()),
+        ("CINDERELLA_TRIGGER", exec_config.trigger.as_str().to_string()),
+        ("CINDERELLA_WORKDIR", workdir.to_string_lossy().to_string()),
+    ].into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+}
+
+/// Sources a profile with bash and returns the resulting environment
+fn source_profile(profile: &str, vars: &HashMap<String, String>)
+    -> Result<HashMap<String, String>, String>
+{
+    // the output of the profile goes to stderr, so that it cannot be
+    // mistaken for variables
+    let output = cmd("bash", &["-c", ". \"$1\" >&2 && env -0", "bash", profile])
+        .full_env(vars)
+        .stdout_capture()
+        .unchecked()
+        .run()
+        .map_err(|e| format!("Could not source profile {}: {}", profile, e))?;
+
+    if !output.status.success() {
+        return Err(format!("Could not source profile {}: {}", profile, output.status));
+    }
+
+    Ok(output.stdout.split(|b| *b == 0)
+        .filter_map(|entry| {
+            let entry = String::from_utf8_lossy(entry);
+            let (name, value) = entry.split_once('=')?;
+
+            if name.is_empty() || SHELL_VARIABLES.contains(&name) {
+                None
+            } else {
+                Some((name.to_string(), value.to_string()))
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{EnvironmentPolicy, Project};
+
+    #[test]
+    fn test_policy_builds_clean_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let workdir = dir.path().join("build");
+        fs::create_dir(&workdir).unwrap();
+        env::set_var("CINDERELLA_ENV_TEST_PASSED", "passed");
+        env::set_var("CINDERELLA_ENV_TEST_HIDDEN", "hidden");
+
+        let mut set = HashMap::new();
+        set.insert(String::from("CI"), String::from("true"));
+        let config = CinderellaConfig {
+            environment: Some(EnvironmentPolicy {
+                pass: vec![String::from("CINDERELLA_ENV_TEST_PASSED")],
+                set,
+            }),
+            ..CinderellaConfig::default()
+        };
+
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let environment = Environment::from_config(
+            &config, &exec_config, &workdir, &home_dir(&workdir).unwrap()).unwrap();
+
+        assert_eq!(environment.vars.get("CINDERELLA_ENV_TEST_PASSED").unwrap(), "passed");
+        assert!(environment.vars.get("CINDERELLA_ENV_TEST_HIDDEN").is_none());
+        assert!(environment.vars.get("PATH").is_none());
+        assert_eq!(environment.vars.get("CI").unwrap(), "true");
+        assert_eq!(environment.vars.get("CINDERELLA_PROJECT").unwrap(), "website.git");
+        assert_eq!(environment.vars.get("CINDERELLA_BRANCH").unwrap(), "master");
+        assert_eq!(environment.vars.get("CINDERELLA_REFTYPE").unwrap(), "branch");
+
+        let home = dir.path().join("build-home");
+        assert_eq!(environment.vars.get("HOME").unwrap(), &home.to_string_lossy());
+        assert!(home.join("tmp").is_dir());
+
+        let vars = environment.for_pipeline("test");
+        assert_eq!(vars["CINDERELLA_PIPELINE"], "test");
+
+        drop(environment);
+        assert!(!home.exists());
+    }
+
+    #[test]
+    fn test_without_policy_environment_is_inherited() {
+        env::set_var("CINDERELLA_ENV_TEST_INHERITED", "inherited");
+        let config = CinderellaConfig::default();
+
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let environment = Environment::from_config(
+            &config, &exec_config, Path::new("/tmp/work"), Path::new("/tmp/work-home")).unwrap();
+
+        assert_eq!(environment.vars.get("CINDERELLA_ENV_TEST_INHERITED").unwrap(), "inherited");
+        assert_eq!(environment.vars.get("CINDERELLA_TRIGGER").unwrap(), "push");
+        assert!(environment.home.is_none());
+        assert!(!Path::new("/tmp/work-home").exists());
+    }
+
+    #[test]
+    fn test_existing_home_is_not_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let workdir = dir.path().join("build");
+        let existing = dir.path().join("build-home");
+        fs::create_dir(&existing).unwrap();
+        fs::write(existing.join("notes.txt"), "keep me").unwrap();
+
+        let config = CinderellaConfig {
+            environment: Some(EnvironmentPolicy {
+                pass: vec![],
+                set: HashMap::new(),
+            }),
+            ..CinderellaConfig::default()
+        };
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+
+        let result = Environment::from_config(&config, &exec_config, &workdir, &existing);
+
+        assert!(result.is_err());
+        drop(result);
+        assert_eq!(fs::read_to_string(existing.join("notes.txt")).unwrap(), "keep me");
+    }
+
+    #[test]
+    fn test_home_in_missing_parent_is_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let workdir = dir.path().join("build");
+        let home = dir.path().join("cinderella").join("random");
+
+        let config = CinderellaConfig {
+            environment: Some(EnvironmentPolicy {
+                pass: vec![],
+                set: HashMap::new(),
+            }),
+            ..CinderellaConfig::default()
+        };
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+
+        let environment = Environment::from_config(&config, &exec_config, &workdir, &home).unwrap();
+
+        let mode = fs::metadata(&home).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        drop(environment);
+        assert!(!home.exists());
+    }
+
+    #[test]
+    fn test_profile_is_sourced() {
+        let dir = tempfile::tempdir().unwrap();
+        let profile = dir.path().join("profile");
+        fs::write(&profile, "echo 'profile output'\n\
+                             export PATH=\"/opt/toolchain/bin:$PATH\"\n\
+                             export CINDERELLA_BRANCH=changed\n").unwrap();
+
+        let config = CinderellaConfig {
+            environment: Some(EnvironmentPolicy {
+                pass: vec![String::from("PATH")],
+                set: HashMap::new(),
+            }),
+            projects: vec![Project {
+                repo_url: String::from("/srv/git/website.git"),
+                branch: None,
+                cinderella_filepath: None,
+                run_as: None,
+                profile: Some(profile.to_string_lossy().to_string()),
+                concurrency: None,
+                priority: None,
+            }],
+            ..CinderellaConfig::default()
+        };
+        let workdir = dir.path().join("build");
+
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let environment = Environment::from_config(
+            &config, &exec_config, &workdir, &home_dir(&workdir).unwrap()).unwrap();
+
+        assert!(environment.vars.get("PATH").unwrap().starts_with("/opt/toolchain/bin:"));
+        assert_eq!(environment.vars.get("CINDERELLA_BRANCH").unwrap(), "master");
+        assert!(environment.vars.get("SHLVL").is_none());
+    }
+}
@@ -1,12 +1,26 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader};
 use std::env;
-
-use evalexpr;
-use duct::cmd;
-
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::condition;
+use crate::config::CinderellaConfig;
+use crate::environment::Environment;
+use crate::executor::{Command, Executor, LocalExecutor, SshExecutor};
+use crate::locking;
+use crate::logs::{BuildLog, StepLog};
+use crate::masking;
 use crate::parser;
 use crate::pipeline;
+use crate::privileges::RunAs;
+use crate::sandbox::SandboxExecutor;
+use crate::template;
+use crate::test_results::{self, TestResults};
+use crate::usage::Usage;
+
+/// Shells that replace the `$NAME` placeholders of secrets in `-c` scripts
+const SHELLS: &[&str] = &["sh", "bash", "dash", "zsh", "ksh"];
 
 pub enum ExecutionResult {
     NoExecution,
@@ -14,95 +28,211 @@ pub enum ExecutionResult {
     Error(Vec<StepResult>),
 }
 
-pub enum StepResult {
-    Success(String, String),
-    Error(String, String, Option<i32>),
-}
-
-struct Command {
-    command: String,
-    args: Vec<String>,
+/// The outcome of a single command of a pipeline
+pub struct StepResult {
+    /// Name of the pipeline the step belongs to, filled in by `execute`
+    pub pipeline: String,
+    pub command: String,
+    /// The tail of the output, the complete output is in the step log
+    pub output: String,
+    /// Whether lines at the beginning of the output were dropped
+    pub truncated: bool,
+    /// Why the step failed, `None` if it succeeded
+    pub failure: Option<Failure>,
+    /// Resources used by the command, `None` if it was not executed
+    pub usage: Option<Usage>,
+    /// The step log with the complete output
+    pub log_file: Option<PathBuf>,
 }
 
-impl Command {
-    fn command_string(&self) -> String {
-        let mut parts = self.args.clone();
-        parts.insert(0, String::from(&self.command));
-
-        let command = parts.iter().map(|arg| {
-            if arg.contains(" ") {
-                format!("\"{}\"", arg)
-            } else {
-                String::from(arg)
-            }
-        }).collect::<Vec<String>>().join(" ");
+impl StepResult {
+    /// A step that failed before its command could be executed
+    pub fn not_executed(command: String, msg: String) -> StepResult {
+        StepResult {
+            pipeline: String::new(),
+            command: masking::mask(&command),
+            output: masking::mask(&msg),
+            truncated: false,
+            failure: Some(Failure::NotExecuted),
+            usage: None,
+            log_file: None,
+        }
+    }
 
-        command
+    pub fn is_success(&self) -> bool {
+        self.failure.is_none()
     }
+}
 
-    fn execute(&self) -> StepResult {
-        let reader = cmd(&self.command, &self.args).stderr_to_stdout()
-            .reader().unwrap();
-        let f = BufReader::new(&reader);
+/// Why a step failed
+#[derive(Debug, PartialEq)]
+pub enum Failure {
+    /// The command was invalid or could not be started
+    NotExecuted,
+    /// The command was killed because its output could not be read
+    UnreadableOutput,
+    ExitCode(i32),
+    /// The command was terminated by a signal
+    Signal(i32),
+    /// The command was killed because it exceeded a resource limit
+    LimitExceeded(String),
+    /// The build was cancelled, e.g. by a newer build
+    Cancelled,
+}
 
-        let mut outtext = String::new();
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::NotExecuted => write!(f, "command was not executed"),
+            Failure::UnreadableOutput => write!(f, "output could not be read"),
+            Failure::ExitCode(code) => write!(f, "exit code {}", code),
+            Failure::Signal(signal) => write!(f, "killed by signal {}", signal),
+            Failure::LimitExceeded(reason) => {
+                write!(f, "resource limit exceeded: {}", reason)
+            },
+            Failure::Cancelled => write!(f, "build was cancelled"),
+        }
+    }
+}
 
-        for line in f.lines() {
-            match line {
-                Ok(line) => {
-                    println!("{}", line);
+/// Why a pipeline was or was not executed
+#[derive(Debug, PartialEq)]
+pub struct PipelineDecision {
+    pub pipeline: String,
+    /// Why the pipeline was skipped, `None` if it was executed
+    pub skip_reason: Option<String>,
+    /// The value of the `when` condition, `None` if the pipeline has no
+    /// condition or it could not be evaluated
+    pub condition: Option<bool>,
+}
 
-                    // TODO: Newline style should be system dependent
-                    outtext.push_str(&line);
-                    outtext.push_str("\n");
-                },
-                _ => {
-                    reader.kill().expect("Could not kill reader");
-                    return StepResult::Error(
-                        self.command_string(),
-                        outtext,
-                        // TODO: How can we get the correct code here?
-                        None
-                    );
-                },
-            }
-        }
+/// Settings of a build that influence how its pipelines are executed
+pub struct ExecutionContext<'a> {
+    pub cinderella_config: &'a CinderellaConfig,
+    pub selection: pipeline::Selection,
+    pub run_as: Option<RunAs>,
+    /// Dropped after the build user, which hands back the build home
+    pub environment: Environment,
+    /// The decrypted secrets of the project
+    pub secrets: HashMap<String, String>,
+    /// Keeps the secrets masked in all output until the build is done
+    pub masked: masking::Registration,
+    pub log: BuildLog,
+    /// Filled by `execute` for every pipeline it got to
+    pub decisions: RefCell<Vec<PipelineDecision>>,
+    /// Filled by `execute` for every executed pipeline that declares test
+    /// reports or a test output format
+    pub test_results: RefCell<HashMap<String, TestResults>>,
+}
 
-        // guaranteed to be Ok(Some(_)) after EOF
-        let output = reader.try_wait().unwrap().unwrap();
-        match output.status.success() {
-            true => StepResult::Success(self.command_string(), outtext),
-            false => {
-                StepResult::Error(
-                    self.command_string(),
-                    outtext,
-                    output.status.code()
-                )
-            },
+impl<'a> ExecutionContext<'a> {
+    pub fn new(cinderella_config: &'a CinderellaConfig) -> ExecutionContext<'a> {
+        ExecutionContext {
+            cinderella_config,
+            selection: pipeline::Selection::default(),
+            run_as: None,
+            environment: Environment::inherited(),
+            secrets: HashMap::new(),
+            masked: masking::Registration::default(),
+            log: BuildLog::disabled(),
+            decisions: RefCell::new(Vec::new()),
+            test_results: RefCell::new(HashMap::new()),
         }
     }
 }
 
 pub fn execute(
     pipelines: &Vec<pipeline::Pipeline>,
-    variables: &HashMap<String, String>) -> ExecutionResult
+    variables: &HashMap<String, String>,
+    context: &ExecutionContext) -> ExecutionResult
 {
     let mut done_steps = Vec::new();
 
     for pipeline in pipelines {
+        let decide = |skip_reason: Option<&str>, condition: Option<bool>| {
+            context.decisions.borrow_mut().push(PipelineDecision {
+                pipeline: pipeline.name.clone(),
+                skip_reason: skip_reason.map(String::from),
+                condition,
+            });
+        };
+
+        if let Some(reason) = context.selection.skip_reason(pipeline) {
+            println!("Skipping pipeline {} ({})", pipeline.name, reason);
+            decide(Some(reason), None);
+            continue;
+        }
+
+        let mut env = context.environment.for_pipeline(&pipeline.name);
+        let template_env = template_env(pipeline, &env);
+
         let execute = match &pipeline.when {
             Some(when) => {
-                execute_test(&when, &variables)
+                match execute_test(when, variables, &template_env) {
+                    Ok(execute) => {
+                        if execute {
+                            decide(None, Some(true));
+                        } else {
+                            decide(Some("when condition is false"), Some(false));
+                        }
+                        execute
+                    },
+                    Err(msg) => {
+                        eprintln!("{}", msg);
+                        decide(None, None);
+                        let result = context.log.record(&pipeline.name, StepResult {
+                            pipeline: pipeline.name.clone(),
+                            ..StepResult::not_executed(format!("when = {}", when), msg)
+                        });
+                        done_steps.push(result);
+                        return ExecutionResult::Error(done_steps);
+                    },
+                }
             }
-            None => true,
+            None => {
+                decide(None, None);
+                true
+            },
         };
 
         if execute {
-            let res = execute_pipeline(pipeline, &variables);
+            match secrets_env(pipeline, &context.secrets) {
+                Ok(secrets) => env.extend(secrets),
+                Err(msg) => {
+                    eprintln!("{}", msg);
+                    let result = context.log.record(&pipeline.name, StepResult {
+                        pipeline: pipeline.name.clone(),
+                        ..StepResult::not_executed(
+                            format!("prepare pipeline {}", pipeline.name), msg)
+                    });
+                    done_steps.push(result);
+                    return ExecutionResult::Error(done_steps);
+                },
+            }
+
+            let mut executor = match build_executor(pipeline, context, env) {
+                Ok(executor) => executor,
+                Err(msg) => {
+                    eprintln!("{}", msg);
+                    let result = context.log.record(&pipeline.name, StepResult {
+                        pipeline: pipeline.name.clone(),
+                        ..StepResult::not_executed(
+                            format!("prepare pipeline {}", pipeline.name), msg)
+                    });
+                    done_steps.push(result);
+                    return ExecutionResult::Error(done_steps);
+                },
+            };
+
+            let res = execute_pipeline(
+                pipeline, variables, &template_env, context, executor.as_mut());
 
             match res {
                 ExecutionResult::Success(steps) => done_steps.extend(steps),
-                ExecutionResult::Error(_) => return res,
+                ExecutionResult::Error(steps) => {
+                    done_steps.extend(steps);
+                    return ExecutionResult::Error(done_steps);
+                },
                 ExecutionResult::NoExecution => (),
             }
         }
@@ -115,81 +245,428 @@ pub fn execute(
     }
 }
 
+/// Describes what `execute` would do without executing anything
+///
+/// Lists which pipelines would run or be skipped and why, the `when`
+/// conditions before and after substitution, and the commands as they
+/// would be executed, split into their arguments and with secrets masked.
+/// Unlike `execute` it continues after errors, so that all of them are
+/// shown at once.
+pub fn plan(
+    pipelines: &[pipeline::Pipeline],
+    variables: &HashMap<String, String>,
+    context: &ExecutionContext) -> Plan
+{
+    let mut plan = Plan::default();
+
+    for pipeline in pipelines {
+        if let Some(reason) = context.selection.skip_reason(pipeline) {
+            plan.line(format!("Pipeline {}: skipped ({})", pipeline.name, reason));
+            continue;
+        }
+
+        let env = context.environment.for_pipeline(&pipeline.name);
+        let template_env = template_env(pipeline, &env);
+
+        let execute = match &pipeline.when {
+            Some(when) => {
+                let result = execute_test(when, variables, &template_env);
+
+                match &result {
+                    Ok(true) => plan.line(format!("Pipeline {}: runs", pipeline.name)),
+                    Ok(false) => plan.line(format!(
+                        "Pipeline {}: skipped (when condition is false)", pipeline.name)),
+                    Err(_) => plan.line(format!("Pipeline {}: fails", pipeline.name)),
+                }
+                plan.line(format!("  when: {}", when));
+                match result {
+                    Ok(execute) => {
+                        // for display only, the condition itself binds the
+                        // values without pasting them into the expression
+                        if let Ok(substituted) = template::render(when, variables, &template_env) {
+                            plan.line(format!("  substituted: {}", masking::mask(&substituted)));
+                        }
+                        plan.line(format!("  result: {}", execute));
+                        execute
+                    },
+                    Err(msg) => {
+                        plan.error(format!("  error: {}", masking::mask(&msg)));
+                        false
+                    },
+                }
+            },
+            None => {
+                plan.line(format!("Pipeline {}: runs", pipeline.name));
+                true
+            },
+        };
+
+        if !execute {
+            continue;
+        }
+
+        if let Some(host) = &pipeline.runs_on {
+            plan.line(format!("  runs on: {}", host));
+        }
+        if let Some(sandbox) = &pipeline.sandbox {
+            plan.line(format!("  sandbox: {}", sandbox));
+        }
+        if let Err(msg) = secrets_env(pipeline, &context.secrets) {
+            plan.error(format!("  error: {}", msg));
+        }
+
+        for cmd in &pipeline.commands {
+            plan.line(format!("  command: {}", masking::mask(cmd)));
+
+            let parts = template::render(cmd, variables, &template_env)
+                .and_then(|cmd| {
+                    parser::parse_command(&cmd)
+                        .map_err(|e| format!("Invalid command: {}", e))
+                });
+            let parts = parts.and_then(|parts| {
+                if !parts.is_empty() {
+                    check_secret_placeholders(&parts, &pipeline.secrets_env)?;
+                }
+                Ok(parts)
+            });
+            match parts {
+                Ok(ref parts) if parts.is_empty() => plan.error(String::from("    error: Empty command")),
+                Ok(parts) => {
+                    plan.line(format!("    arguments: {}", masking::mask(&format!("{:?}", parts))));
+                },
+                Err(msg) => plan.error(format!("    error: {}", masking::mask(&msg))),
+            }
+        }
+    }
+
+    plan
+}
+
+/// The result of `plan`
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub text: String,
+    /// Number of problems that would fail the build before a command of the
+    /// affected pipeline is executed
+    pub errors: usize,
+}
+
+impl Plan {
+    fn line(&mut self, line: String) {
+        self.text.push_str(&line);
+        self.text.push('\n');
+    }
+
+    fn error(&mut self, line: String) {
+        self.errors += 1;
+        self.line(line);
+    }
+}
+
+/// The environment that placeholders in the conditions and commands of a
+/// pipeline are resolved against
+///
+/// Secrets in the environment are left to the shell, so that their values
+/// never end up in the rendered commands.
+fn template_env(pipeline: &pipeline::Pipeline, env: &HashMap<String, String>)
+    -> HashMap<String, String>
+{
+    let mut template_env = env.clone();
+    for name in &pipeline.secrets_env {
+        template_env.insert(name.clone(), format!("${{{}}}", name));
+    }
+
+    template_env
+}
+
+/// Looks up the secrets a pipeline passes to its commands as environment
+/// variables
+fn secrets_env(pipeline: &pipeline::Pipeline, secrets: &HashMap<String, String>)
+    -> Result<HashMap<String, String>, String>
+{
+    pipeline.secrets_env.iter()
+        .map(|name| {
+            template::lookup(secrets, name)
+                .map(|value| (name.clone(), value.clone()))
+                .ok_or_else(|| format!(
+                    "Unknown secret {} for pipeline {}", name, pipeline.name))
+        })
+        .collect()
+}
+
+fn build_executor<'a>(
+    pipeline: &pipeline::Pipeline,
+    context: &'a ExecutionContext,
+    env: HashMap<String, String>) -> Result<Box<dyn Executor + 'a>, String>
+{
+    let limits = pipeline.limits.or(&context.cinderella_config.limits);
+    if !limits.is_empty() {
+        println!("Resource limits: {}", limits);
+    }
+
+    match (&pipeline.runs_on, &pipeline.sandbox) {
+        (None, None) => Ok(Box::new(LocalExecutor {
+            run_as: context.run_as.as_ref(),
+            limits,
+            env,
+        })),
+        (Some(name), None) => {
+            let host = context.cinderella_config.hosts.get(name).ok_or_else(|| {
+                format!("Unknown host {} for pipeline {}", name, pipeline.name)
+            })?;
+            if !limits.is_empty() {
+                eprintln!("Warning: resource limits are not applied on build hosts");
+            }
+            if !pipeline.secrets_env.is_empty() {
+                return Err(format!(
+                    "Pipeline {} cannot pass secrets_env to build host {}",
+                    pipeline.name, name));
+            }
+            if !pipeline.test_reports.is_empty() {
+                // the reports are written on the build host and never
+                // copied back to the server
+                return Err(format!(
+                    "Pipeline {} cannot collect test_reports from build host {}",
+                    pipeline.name, name));
+            }
+
+            Ok(Box::new(SshExecutor::new(host, &current_dir()?)))
+        },
+        (None, Some(name)) => {
+            let sandbox = context.cinderella_config.sandboxes.get(name).ok_or_else(|| {
+                format!("Unknown sandbox {} for pipeline {}", name, pipeline.name)
+            })?;
+
+            Ok(Box::new(SandboxExecutor::new(
+                sandbox, &current_dir()?, pipeline.network, limits, env,
+                context.run_as.as_ref())?))
+        },
+        (Some(_), Some(_)) => Err(format!(
+            "Pipeline {} cannot use runs_on and sandbox at the same time",
+            pipeline.name)),
+    }
+}
+
+fn current_dir() -> Result<PathBuf, String> {
+    env::current_dir()
+        .map_err(|e| format!("Could not determine work dir: {}", e))
+}
+
 fn execute_pipeline(
     pipeline: &pipeline::Pipeline,
-    variables: &HashMap<String, String>) -> ExecutionResult
+    variables: &HashMap<String, String>,
+    env: &HashMap<String, String>,
+    context: &ExecutionContext,
+    executor: &mut dyn Executor) -> ExecutionResult
 {
+    if let Err(msg) = executor.prepare() {
+        eprintln!("{}", msg);
+        executor.cleanup();
+        let result = context.log.record(&pipeline.name, StepResult {
+            pipeline: pipeline.name.clone(),
+            ..StepResult::not_executed(
+                format!("prepare pipeline {}", pipeline.name), msg)
+        });
+        return ExecutionResult::Error(vec![result]);
+    }
+
     let res = pipeline.commands.iter()
         .try_fold(Vec::<StepResult>::new(), |mut step_results, cmd| {
-            let result = execute_step(&cmd, variables);
-
-            match result {
-                StepResult::Success(_, _) => {
-                    step_results.push(result);
-                    Ok(step_results)
-                },
-                StepResult::Error(_, _, _) => {
-                    step_results.push(result);
-                    Err(step_results)
-                }
+            let mut log = context.log.step(&pipeline.name);
+            let mut result = StepResult {
+                pipeline: pipeline.name.clone(),
+                ..execute_step(
+                    cmd, variables, env, &context.secrets, &pipeline.secrets_env,
+                    &*executor, &mut log)
+            };
+            log.end(&result);
+            result.log_file = log.path().map(Path::to_path_buf);
+            let success = result.is_success();
+            step_results.push(result);
+
+            if success {
+                Ok(step_results)
+            } else {
+                Err(step_results)
             }
         });
 
-    match res {
-        Ok(step_results) => ExecutionResult::Success(step_results),
-        Err(step_results) => ExecutionResult::Error(step_results),
+    executor.cleanup();
+
+    // tests are collected from failed pipelines as well, that is where
+    // they matter most
+    let (step_results, success) = match res {
+        Ok(step_results) => (step_results, true),
+        Err(step_results) => (step_results, false),
+    };
+    collect_test_results(pipeline, &step_results, context);
+
+    if success {
+        ExecutionResult::Success(step_results)
+    } else {
+        ExecutionResult::Error(step_results)
+    }
+}
+
+/// Parses the test results of a pipeline from the output of its steps and
+/// from the test reports its commands wrote
+fn collect_test_results(
+    pipeline: &pipeline::Pipeline,
+    steps: &[StepResult],
+    context: &ExecutionContext)
+{
+    if pipeline.test_output.is_none() && pipeline.test_reports.is_empty() {
+        return;
     }
+
+    let mut results = TestResults::default();
+    if let Some(format) = pipeline.test_output {
+        for step in steps.iter().filter(|step| step.usage.is_some()) {
+            // the tail in memory would miss the first tests of long runs
+            let output = step.log_file.as_ref()
+                .and_then(|path| StepLog::read_output(path).ok())
+                .unwrap_or_else(|| step.output.clone());
+            results.merge(test_results::parse_output(format, &output));
+        }
+    }
+    if !pipeline.test_reports.is_empty() {
+        match current_dir() {
+            Ok(dir) => results.merge(test_results::collect_reports(&pipeline.test_reports, &dir)),
+            Err(msg) => eprintln!("{}", msg),
+        }
+    }
+
+    println!("Tests of pipeline {}: {}", pipeline.name, results.describe());
+    context.test_results.borrow_mut().insert(pipeline.name.clone(), results);
 }
 
-fn execute_step(cmd: &str, variables: &HashMap<String, String>) -> StepResult {
-    let cmd = replace_variables(&cmd, &variables);
-    let parts = parser::parse_command(&cmd);
+fn execute_step(
+    cmd: &str,
+    variables: &HashMap<String, String>,
+    env: &HashMap<String, String>,
+    secrets: &HashMap<String, String>,
+    secrets_env: &[String],
+    executor: &dyn Executor,
+    log: &mut StepLog) -> StepResult
+{
+    if locking::is_cancelled() {
+        return StepResult {
+            failure: Some(Failure::Cancelled),
+            ..StepResult::not_executed(cmd.to_string(), String::from("Build was cancelled"))
+        };
+    }
+
+    warn_about_secrets_in_arguments(cmd, secrets);
+
+    let cmd = match template::render(cmd, variables, env) {
+        Ok(cmd) => cmd,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return StepResult::not_executed(cmd.to_string(), msg);
+        },
+    };
+    let parts = match parser::parse_command(&cmd) {
+        Ok(ref parts) if parts.is_empty() => {
+            let msg = String::from("Empty command");
+            eprintln!("{}", msg);
+            return StepResult::not_executed(cmd, msg);
+        },
+        Ok(parts) => parts,
+        Err(e) => {
+            let msg = format!("Invalid command: {}", e);
+            eprintln!("{}", msg);
+            return StepResult::not_executed(cmd, msg);
+        },
+    };
+    if let Err(msg) = check_secret_placeholders(&parts, secrets_env) {
+        eprintln!("{}", msg);
+        return StepResult::not_executed(cmd, msg);
+    }
 
     let cmd = Command {
         command: String::from(&parts[0]),
         args: parts[1..].to_vec(),
     };
 
-    cmd.execute()
+    log.begin(&masking::mask(&cmd.command_string()));
+    executor.execute(&cmd, log)
 }
-fn execute_test(test: &str, variables: &HashMap<String, String>) -> bool {
-    // not possible to use evalexpr Context, because evalexpr only handles
-    // standard variable names without special characters (percentage
-    // symbol cannot be used)
-    let test = replace_variables(test, variables);
 
-    match evalexpr::eval_boolean(&test) {
-        Ok(true) => true,
-        _ => false,
-    }
-}
-
-fn replace_variables(command: &str, variables: &HashMap<String, String>)
-        -> String
+/// Makes sure that `$NAME` placeholders of secrets are only used in shell
+/// commands
+///
+/// The placeholders of secrets are kept in the rendered command (see
+/// `template_env`), so only a shell called with `-c` replaces them with
+/// their values. Any other program would get the literal `${NAME}`.
+fn check_secret_placeholders(parts: &[String], secrets_env: &[String])
+    -> Result<(), String>
 {
-    let mut res = String::from(command);
+    let program = parts[0].rsplit('/').next().unwrap_or_default();
+    let is_shell = SHELLS.contains(&program) && parts[1..].iter().any(|arg| arg == "-c");
+    if is_shell {
+        return Ok(());
+    }
 
-    for (original, replacement) in variables {
-        // replace "%VARNAME" with replacement value
-        let varname = format!("%{}", original.to_uppercase());
-        res = res.replace(&varname, replacement);
+    for name in secrets_env {
+        let placeholder = format!("${{{}}}", name);
+        if parts.iter().any(|part| part.contains(&placeholder)) {
+            return Err(format!(
+                "Secret ${} can only be used in a shell command, e.g. sh -c '... ${}'",
+                name, name));
+        }
     }
 
-    let res = replace_envvars(&res);
+    Ok(())
+}
+
+/// Warns if secrets are substituted into the arguments of a command, where
+/// other users can read them in the process list
+fn warn_about_secrets_in_arguments(cmd: &str, secrets: &HashMap<String, String>) {
+    let tokens = match template::tokenize(cmd) {
+        Ok(tokens) => tokens,
+        // the error is reported when rendering the command
+        Err(_) => return,
+    };
 
-    res
+    for token in tokens {
+        if let template::Token::Placeholder(placeholder) = token {
+            if placeholder.source == template::Source::Variable
+                && template::lookup(secrets, &placeholder.name).is_some()
+            {
+                eprintln!(
+                    "Warning: secret %{} is passed on the command line of: {}\n\
+                     Use secrets_env to pass it as an environment variable",
+                    placeholder.name, cmd);
+            }
+        }
+    }
 }
 
-fn replace_envvars(command: &str) -> String
-{
-    let mut res = String::from(command);
+/// Lists the outcome and resource usage of all executed steps
+pub fn summary(steps: &[StepResult]) -> String {
+    let mut summary = String::new();
+
+    for step in steps {
+        let status = match &step.failure {
+            None => String::from("ok"),
+            Some(failure) => format!("failed ({})", failure),
+        };
+        let usage = step.usage
+            .map(|usage| format!(" [{}]", usage))
+            .unwrap_or_default();
 
-    for (key, value) in env::vars() {
-        let varname = format!("${}", key.to_uppercase());
-        res = res.replace(&varname, &value);
+        summary.push_str(&format!("{}: {}{}\n", step.command, status, usage));
     }
 
-    res
+    summary
+}
+
+fn execute_test(
+    test: &str,
+    variables: &HashMap<String, String>,
+    env: &HashMap<String, String>) -> Result<bool, String>
+{
+    condition::evaluate(test, variables, env)
 }
 
 
@@ -197,11 +674,15 @@ fn replace_envvars(command: &str) -> String
 mod tests {
     use super::*;
     use std::collections::HashMap;
-    use crate::pipeline::Pipeline;
+    use crate::config::Host;
+    use crate::pipeline::{Pipeline, Selection};
+    use crate::test_results::TestOutput;
 
     fn execute_stringout(pipeline: Pipeline,
                          variables: HashMap<String, String>) -> String {
-        let res = execute(&vec![pipeline], &variables);
+        let config = CinderellaConfig::default();
+        let res = execute(&vec![pipeline], &variables,
+                          &ExecutionContext::new(&config));
 
         let mut out = String::new();
         match res {
@@ -209,11 +690,7 @@ mod tests {
                 | ExecutionResult::Error(steps) =>
             {
                 for step in steps {
-                    let text = match step {
-                        StepResult::Success(_command, out) => out,
-                        StepResult::Error(_command, out, _code) => out,
-                    };
-                    out.push_str(&text);
+                    out.push_str(&step.output);
                 }
             },
             _ => (),
@@ -227,7 +704,7 @@ mod tests {
         let pipeline = Pipeline {
             name: String::from("my-test"),
             commands: vec!["echo 'this is my test'".to_string()],
-            when: None,
+            ..Pipeline::default()
         };
         let variables = HashMap::new();
 
@@ -241,19 +718,19 @@ mod tests {
         let pipeline = Pipeline {
             name: String::from("error-test"),
             commands: vec!["bash -c \"exit 1\"".to_string()],
-            when: None,
+            ..Pipeline::default()
         };
         let variables = HashMap::new();
 
-        let result = execute(&vec![pipeline], &variables);
+        let config = CinderellaConfig::default();
+        let result = execute(&vec![pipeline], &variables,
+                             &ExecutionContext::new(&config));
 
         match result {
             ExecutionResult::Error(steps) => {
-                if let StepResult::Error(cmd, _out, _code) = &steps[0] {
-                    assert_eq!(cmd, "bash -c \"exit 1\"");
-                } else {
-                    assert!(false);
-                }
+                assert_eq!(steps[0].command, "bash -c \"exit 1\"");
+                assert_eq!(steps[0].failure, Some(Failure::ExitCode(1)));
+                assert!(steps[0].usage.is_some());
             },
             // fail if something different from error is returned
             _ => assert!(false),
@@ -266,7 +743,7 @@ mod tests {
         let pipeline = Pipeline {
             name: String::from("my-test"),
             commands: vec!["echo '%MYVAR'".to_string()],
-            when: None,
+            ..Pipeline::default()
         };
         let mut variables = HashMap::new();
         variables.insert(String::from("myvar"), String::from("some value"));
@@ -282,6 +759,7 @@ mod tests {
             name: String::from("my-test"),
             commands: vec!["echo 'Building non-master'".to_string()],
             when: Some(String::from("\"%BRANCH\" != \"master\"")),
+            ..Pipeline::default()
         };
         let mut variables = HashMap::new();
         variables.insert(String::from("branch"), String::from("master"));
@@ -297,6 +775,7 @@ mod tests {
             name: String::from("my-test"),
             commands: vec!["echo 'Building master'".to_string()],
             when: Some(String::from("\"%BRANCH\" == \"master\"")),
+            ..Pipeline::default()
         };
         let mut variables = HashMap::new();
         variables.insert(String::from("branch"), String::from("master"));
@@ -305,4 +784,238 @@ mod tests {
 
         assert!(result.contains("Building master"));
     }
+
+    #[test]
+    fn test_unknown_host_fails_pipeline() {
+        let pipeline = Pipeline {
+            name: String::from("remote"),
+            commands: vec!["echo 'Building remote'".to_string()],
+            runs_on: Some(String::from("missing-host")),
+            ..Pipeline::default()
+        };
+
+        let result = execute_stringout(pipeline, HashMap::new());
+
+        assert!(result.contains("Unknown host missing-host"));
+    }
+
+    fn config_with_host(name: &str) -> CinderellaConfig {
+        let mut config = CinderellaConfig::default();
+        config.hosts.insert(String::from(name), Host {
+            address: String::from("builder.example.com"),
+            user: None,
+            port: None,
+            identity_file: None,
+            workdir: None,
+        });
+
+        config
+    }
+
+    #[test]
+    fn test_secrets_on_build_host_fail_pipeline() {
+        let pipeline = Pipeline {
+            name: String::from("deploy"),
+            commands: vec!["make deploy".to_string()],
+            runs_on: Some(String::from("builder")),
+            secrets_env: vec![String::from("PASSWORD")],
+            ..Pipeline::default()
+        };
+        let config = config_with_host("builder");
+        let mut context = ExecutionContext::new(&config);
+        context.secrets.insert(String::from("password"), String::from("hunter2"));
+
+        match execute(&vec![pipeline], &HashMap::new(), &context) {
+            ExecutionResult::Error(steps) => {
+                assert!(steps[0].output.contains("cannot pass secrets_env to build host builder"));
+            },
+            _ => panic!("secrets on a build host must fail the build"),
+        }
+    }
+
+    #[test]
+    fn test_test_reports_on_build_host_fail_pipeline() {
+        let pipeline = Pipeline {
+            name: String::from("test"),
+            commands: vec!["make test".to_string()],
+            runs_on: Some(String::from("builder")),
+            test_reports: vec![String::from("reports/*.xml")],
+            ..Pipeline::default()
+        };
+        let config = config_with_host("builder");
+        let context = ExecutionContext::new(&config);
+
+        match execute(&vec![pipeline], &HashMap::new(), &context) {
+            ExecutionResult::Error(steps) => {
+                assert!(steps[0].output.contains("cannot collect test_reports from build host"));
+            },
+            _ => panic!("test reports on a build host must fail the build"),
+        }
+        assert!(context.test_results.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_conditional_pipeline_with_undefined_variable() {
+        let pipeline = Pipeline {
+            name: String::from("my-test"),
+            commands: vec!["echo 'Building master'".to_string()],
+            when: Some(String::from("\"%BRANCH\" == \"master\"")),
+            ..Pipeline::default()
+        };
+        let mut variables = HashMap::new();
+        variables.insert(String::from("tag"), String::from("1.0.0"));
+
+        let config = CinderellaConfig::default();
+        let result = execute(&vec![pipeline], &variables,
+                             &ExecutionContext::new(&config));
+
+        match result {
+            ExecutionResult::Error(steps) => {
+                assert!(steps[0].output.contains("%BRANCH"));
+                assert_eq!(steps[0].failure, Some(Failure::NotExecuted));
+            },
+            _ => panic!("undefined variable in condition must fail the build"),
+        }
+    }
+
+    #[test]
+    fn test_manual_pipeline_only_runs_when_selected() {
+        let pipeline = || Pipeline {
+            name: String::from("deploy"),
+            commands: vec!["echo 'Deploying'".to_string()],
+            manual: true,
+            ..Pipeline::default()
+        };
+        let variables = HashMap::new();
+
+        let result = execute_stringout(pipeline(), variables.clone());
+        assert!(!result.contains("Deploying"));
+
+        let config = CinderellaConfig::default();
+        let mut context = ExecutionContext::new(&config);
+        context.selection = Selection {
+            pipelines: vec![String::from("deploy")],
+            skip: vec![],
+            scheduled_only: false,
+        };
+        let res = execute(&vec![pipeline()], &variables, &context);
+        match res {
+            ExecutionResult::Success(_) => (),
+            _ => panic!("selected manual pipeline was not executed"),
+        }
+    }
+
+    #[test]
+    fn test_secrets_are_passed_in_environment() {
+        let pipeline = || Pipeline {
+            name: String::from("upload"),
+            commands: vec!["sh -c 'echo \"secret: $PASSWORD\"'".to_string()],
+            secrets_env: vec![String::from("PASSWORD")],
+            ..Pipeline::default()
+        };
+        let config = CinderellaConfig::default();
+        let mut context = ExecutionContext::new(&config);
+
+        let res = execute(&vec![pipeline()], &HashMap::new(), &context);
+        match res {
+            ExecutionResult::Error(steps) => {
+                assert!(steps[0].output.contains("Unknown secret PASSWORD"));
+            },
+            _ => panic!("missing secret must fail the build"),
+        }
+
+        context.secrets.insert(String::from("password"), String::from("hunter2"));
+        let res = execute(&vec![pipeline()], &HashMap::new(), &context);
+        match res {
+            ExecutionResult::Success(steps) => {
+                assert!(steps[0].command.contains("${PASSWORD}"));
+                assert!(steps[0].output.contains("secret: hunter2"));
+            },
+            _ => panic!("pipeline with secret was not executed"),
+        }
+    }
+
+    #[test]
+    fn test_secrets_outside_of_shell_fail_step() {
+        let pipeline = Pipeline {
+            name: String::from("upload"),
+            commands: vec!["echo $PASSWORD".to_string()],
+            secrets_env: vec![String::from("PASSWORD")],
+            ..Pipeline::default()
+        };
+        let config = CinderellaConfig::default();
+        let mut context = ExecutionContext::new(&config);
+        context.secrets.insert(String::from("password"), String::from("hunter2"));
+
+        match execute(&vec![pipeline], &HashMap::new(), &context) {
+            ExecutionResult::Error(steps) => {
+                assert!(steps[0].usage.is_none());
+                assert!(steps[0].output.contains("can only be used in a shell command"));
+            },
+            _ => panic!("secret outside of a shell must fail the step"),
+        }
+    }
+
+    #[test]
+    fn test_test_results_are_collected_from_failed_pipeline() {
+        let pipeline = Pipeline {
+            name: String::from("test"),
+            commands: vec![
+                "sh -c 'echo \"test parse ... ok\"; echo \"test login ... FAILED\"; exit 101'"
+                    .to_string(),
+            ],
+            test_output: Some(TestOutput::Libtest),
+            ..Pipeline::default()
+        };
+        let config = CinderellaConfig::default();
+        let context = ExecutionContext::new(&config);
+
+        match execute(&vec![pipeline], &HashMap::new(), &context) {
+            ExecutionResult::Error(_) => (),
+            _ => panic!("pipeline should fail"),
+        }
+
+        let results = context.test_results.borrow();
+        assert_eq!(results["test"].describe(), "1 passed, 1 failed, 0 skipped");
+        assert_eq!(results["test"].failures[0].name, "login");
+    }
+
+    #[test]
+    fn test_plan_shows_conditions_and_commands() {
+        let pipeline = |name: &str, commands: Vec<&str>, when: Option<&str>, manual: bool| Pipeline {
+            name: String::from(name),
+            commands: commands.into_iter().map(String::from).collect(),
+            when: when.map(String::from),
+            manual,
+            ..Pipeline::default()
+        };
+        let pipelines = vec![
+            pipeline("test", vec!["cargo test --features \"%FEATURES\""], None, false),
+            pipeline("docs", vec!["make docs"], Some("\"%BRANCH\" == \"gh-pages\""), false),
+            pipeline("deploy", vec!["make deploy"], None, true),
+            pipeline("upload", vec!["upload --target %{TARGET}"], None, false),
+        ];
+        let mut variables = HashMap::new();
+        variables.insert(String::from("branch"), String::from("master"));
+        variables.insert(String::from("features"), String::from("tls json"));
+        let config = CinderellaConfig::default();
+        let context = ExecutionContext::new(&config);
+
+        let plan = plan(&pipelines, &variables, &context);
+
+        assert_eq!(plan.text,
+            "Pipeline test: runs\n\
+             \x20 command: cargo test --features \"%FEATURES\"\n\
+             \x20   arguments: [\"cargo\", \"test\", \"--features\", \"tls json\"]\n\
+             Pipeline docs: skipped (when condition is false)\n\
+             \x20 when: \"%BRANCH\" == \"gh-pages\"\n\
+             \x20 substituted: \"master\" == \"gh-pages\"\n\
+             \x20 result: false\n\
+             Pipeline deploy: skipped (manual pipeline)\n\
+             Pipeline upload: runs\n\
+             \x20 command: upload --target %{TARGET}\n\
+             \x20   error: Unknown variable %{TARGET} in: upload --target %{TARGET}\n");
+        assert_eq!(plan.errors, 1);
+        assert!(context.decisions.borrow().is_empty());
+    }
 }
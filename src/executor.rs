@@ -0,0 +1,564 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use duct::{cmd, Expression};
+
+use crate::config::Host;
+use crate::execution::{Failure, StepResult};
+use crate::limits::{self, Limits};
+use crate::locking;
+use crate::logs::StepLog;
+use crate::masking;
+use crate::privileges::RunAs;
+use crate::usage;
+
+/// Longer lines of the output are split, so that output without newlines
+/// cannot fill the memory
+const MAX_LINE_LENGTH: u64 = 64 * 1024;
+
+pub struct Command {
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+impl Command {
+    pub fn command_string(&self) -> String {
+        let mut parts = self.args.clone();
+        parts.insert(0, String::from(&self.command));
+
+        let command = parts.iter().map(|arg| {
+            if arg.contains(" ") {
+                format!("\"{}\"", arg)
+            } else {
+                String::from(arg)
+            }
+        }).collect::<Vec<String>>().join(" ");
+
+        command
+    }
+}
+
+/// Runs the steps of a pipeline in some environment
+///
+/// `prepare` is called once before the first step of a pipeline and
+/// `cleanup` once after the last step, even if a step failed.
+pub trait Executor {
+    fn prepare(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn execute(&self, command: &Command, log: &mut StepLog) -> StepResult;
+
+    fn cleanup(&mut self) {
+    }
+}
+
+/// Executes commands as child processes on the Cinderella server,
+/// optionally as a dedicated build user
+pub struct LocalExecutor<'a> {
+    pub run_as: Option<&'a RunAs>,
+    pub limits: Limits,
+    /// The complete environment of the commands
+    pub env: HashMap<String, String>,
+}
+
+impl<'a> Executor for LocalExecutor<'a> {
+    fn execute(&self, command: &Command, log: &mut StepLog) -> StepResult {
+        let expression = match self.run_as {
+            Some(run_as) => run_as.expression(command, &self.env),
+            None => cmd(&command.command, &command.args),
+        };
+        let expression = self.limits.apply(expression.full_env(&self.env));
+
+        let oom_kills = limits::oom_kills();
+        let result = run_streamed(expression, command.command_string(), log);
+        check_limits(result, &self.limits, oom_kills)
+    }
+}
+
+/// Executes commands on a build host via `ssh`
+///
+/// The work dir is copied to the build host with `rsync` before the first
+/// step and removed from the build host after the last step.
+pub struct SshExecutor {
+    host: Host,
+    workdir: PathBuf,
+    remote_dir: String,
+}
+
+impl SshExecutor {
+    pub fn new(host: &Host, workdir: &Path) -> SshExecutor {
+        let base = host.workdir.clone()
+            .unwrap_or_else(|| String::from("/tmp/cinderella"));
+        let remote_dir = crate::random_dir(&base).to_string_lossy().to_string();
+
+        SshExecutor {
+            host: host.clone(),
+            workdir: workdir.to_path_buf(),
+            remote_dir,
+        }
+    }
+
+    fn destination(&self) -> String {
+        match &self.host.user {
+            Some(user) => format!("{}@{}", user, self.host.address),
+            None => self.host.address.clone(),
+        }
+    }
+
+    /// Options for `ssh` that are shared by all invocations
+    fn ssh_options(&self) -> Vec<String> {
+        let mut options = vec![
+            String::from("-o"), String::from("BatchMode=yes"),
+        ];
+
+        if let Some(port) = self.host.port {
+            options.push(String::from("-p"));
+            options.push(port.to_string());
+        }
+        if let Some(identity) = &self.host.identity_file {
+            options.push(String::from("-i"));
+            options.push(identity.clone());
+        }
+
+        options
+    }
+
+    fn ssh_args(&self, remote_command: &str) -> Vec<String> {
+        let mut args = self.ssh_options();
+        args.push(self.destination());
+        args.push(String::from("--"));
+        args.push(remote_command.to_string());
+
+        args
+    }
+
+    fn remote_command(&self, command: &Command) -> String {
+        let mut parts = vec![quote(&command.command)];
+        parts.extend(command.args.iter().map(|arg| quote(arg)));
+
+        format!("cd {} && {}", quote(&self.remote_dir), parts.join(" "))
+    }
+
+    fn rsync_args(&self) -> Vec<String> {
+        let ssh = std::iter::once(String::from("ssh"))
+            .chain(self.ssh_options().iter().map(|o| quote(o)))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let mut source = self.workdir.to_string_lossy().to_string();
+        if !source.ends_with('/') {
+            source.push('/');
+        }
+
+        vec![
+            String::from("-az"),
+            String::from("--delete"),
+            String::from("-e"),
+            ssh,
+            source,
+            format!("{}:{}/", self.destination(), self.remote_dir),
+        ]
+    }
+}
+
+impl Executor for SshExecutor {
+    fn prepare(&mut self) -> Result<(), String> {
+        println!("Copying work dir to {}:{}", self.host.address, self.remote_dir);
+
+        let mkdir = format!("mkdir -p {}", quote(&self.remote_dir));
+        cmd("ssh", self.ssh_args(&mkdir)).run()
+            .map_err(|e| format!("Could not create remote work dir: {}", e))?;
+
+        cmd("rsync", self.rsync_args()).run()
+            .map_err(|e| format!("Could not copy work dir to build host: {}", e))?;
+
+        Ok(())
+    }
+
+    fn execute(&self, command: &Command, log: &mut StepLog) -> StepResult {
+        let remote_command = self.remote_command(command);
+
+        run_streamed(
+            cmd("ssh", self.ssh_args(&remote_command)),
+            command.command_string(),
+            log)
+    }
+
+    fn cleanup(&mut self) {
+        let rm = format!("rm -rf {}", quote(&self.remote_dir));
+
+        if let Err(e) = cmd("ssh", self.ssh_args(&rm)).run() {
+            eprintln!("Could not delete remote work dir {}: {}", self.remote_dir, e);
+        }
+    }
+}
+
+/// Quotes an argument for a POSIX shell, e.g. the login shell on the build
+/// host
+pub fn quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        String::from(arg)
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Runs the expression, echoes its output and streams it to the step log
+///
+/// Only the tail of the output kept by the step log is part of the result.
+/// Secrets are masked in the command and in each line of the output. The
+/// command runs in its own process group, which is killed if the build is
+/// cancelled.
+pub fn run_streamed(expression: Expression, command_string: String, log: &mut StepLog)
+    -> StepResult
+{
+    let command_string = masking::mask(&command_string);
+    let started = Instant::now();
+    let (reader, writer) = match os_pipe::pipe() {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            let msg = format!("Could not create pipe for output: {}", e);
+            eprintln!("{}", msg);
+            return StepResult::not_executed(command_string, msg);
+        },
+    };
+
+    // unchecked, so that waiting does not fail on a non-zero exit code
+    // and we can report the code. The expression holding the write end of
+    // the pipe is dropped right after starting, so that we get an EOF
+    // once the command has finished.
+    let expression = expression.before_spawn(|cmd| {
+        cmd.process_group(0);
+        Ok(())
+    });
+    let handle = match expression.stderr_to_stdout().stdout_file(writer).unchecked().start() {
+        Ok(handle) => handle,
+        Err(e) => {
+            let msg = format!("Could not start command: {}", e);
+            eprintln!("{}", msg);
+            return StepResult::not_executed(command_string, msg);
+        },
+    };
+    let pid = handle.pids().first().cloned();
+
+    let finished = AtomicBool::new(false);
+    let output = thread::scope(|scope| {
+        if let Some(pid) = pid {
+            let finished = &finished;
+            scope.spawn(move || locking::kill_when_cancelled(pid, finished));
+        }
+
+        let output = read_output(BufReader::new(reader), log);
+        finished.store(true, Ordering::SeqCst);
+        output
+    });
+
+    let (outtext, truncated) = log.take_output();
+    if let Err(e) = output {
+        eprintln!("Could not read output: {}", e);
+        // the command might have exited already
+        if let Err(e) = handle.kill() {
+            eprintln!("Could not kill command: {}", e);
+        }
+        return StepResult {
+            pipeline: String::new(),
+            command: command_string,
+            output: outtext,
+            truncated,
+            failure: Some(Failure::UnreadableOutput),
+            usage: None,
+            log_file: None,
+        };
+    }
+
+    // the usage has to be read before duct reaps the process
+    let usage = pid.and_then(|pid| {
+        usage::wait_for_exit(pid, started)
+            .map_err(|e| eprintln!("Could not determine resource usage: {}", e))
+            .ok()
+    });
+
+    let failure = match handle.wait() {
+        Ok(output) if output.status.success() => None,
+        Ok(output) => match (output.status.code(), output.status.signal()) {
+            (Some(code), _) => Some(Failure::ExitCode(code)),
+            (None, Some(signal)) => Some(Failure::Signal(signal)),
+            (None, None) => Some(Failure::NotExecuted),
+        },
+        Err(e) => {
+            eprintln!("Could not wait for command: {}", e);
+            Some(Failure::NotExecuted)
+        },
+    };
+    let failure = match failure {
+        Some(_) if locking::is_cancelled() => Some(Failure::Cancelled),
+        failure => failure,
+    };
+
+    StepResult {
+        pipeline: String::new(),
+        command: command_string,
+        output: outtext,
+        truncated,
+        failure,
+        usage,
+        log_file: None,
+    }
+}
+
+/// Echoes each line of the output and passes it on to the step log
+fn read_output(mut reader: BufReader<os_pipe::PipeReader>, log: &mut StepLog)
+    -> io::Result<()>
+{
+    let mut buffer = Vec::new();
+
+    loop {
+        buffer.clear();
+        let read = (&mut reader).take(MAX_LINE_LENGTH).read_until(b'\n', &mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        if buffer.last() == Some(&b'\n') {
+            buffer.pop();
+        }
+
+        let line = masking::mask(&String::from_utf8_lossy(&buffer));
+        println!("{}", line);
+        log.line(&line);
+    }
+}
+
+/// Reports a command that was killed because of a resource limit as such
+///
+/// `oom_kills` is the count of `limits::oom_kills` from before the command
+/// was started. Exit codes above 128 are checked as well, because a shell
+/// reports the signal that killed its last command this way.
+pub fn check_limits(mut result: StepResult, limits: &Limits, oom_kills: Option<u64>)
+    -> StepResult
+{
+    let signal = match result.failure {
+        Some(Failure::Signal(signal)) => Some(signal),
+        Some(Failure::ExitCode(code)) => limits::signal_of_exit_code(code),
+        _ => None,
+    };
+
+    if let Some(signal) = signal {
+        let oom_killed = signal == libc::SIGKILL && match (oom_kills, limits::oom_kills()) {
+            (Some(before), Some(after)) => after > before,
+            _ => false,
+        };
+
+        if let Some(reason) = limits.exceeded(signal, oom_killed) {
+            eprintln!("Command exceeded a resource limit: {}", reason);
+            result.failure = Some(Failure::LimitExceeded(reason));
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn local_executor(limits: Limits) -> LocalExecutor<'static> {
+        LocalExecutor {
+            run_as: None,
+            limits,
+            env: std::env::vars().collect(),
+        }
+    }
+
+    #[test]
+    fn test_local_executor_uses_only_given_environment() {
+        let command = Command {
+            command: String::from("/bin/sh"),
+            args: vec![String::from("-c"), String::from("echo \"$CI:$CINDERELLA_ENV_UNSET\"")],
+        };
+        std::env::set_var("CINDERELLA_ENV_UNSET", "leaked");
+        let mut env = HashMap::new();
+        env.insert(String::from("CI"), String::from("true"));
+        let executor = LocalExecutor { run_as: None, limits: Limits::default(), env };
+
+        assert_eq!(executor.execute(&command, &mut StepLog::disabled()).output, "true:\n");
+    }
+
+    #[test]
+    fn test_secrets_are_masked_in_output() {
+        let _registration = masking::register("executor-masking-secret");
+        let command = Command {
+            command: String::from("echo"),
+            args: vec![String::from("executor-masking-secret")],
+        };
+
+        let result = local_executor(Limits::default()).execute(&command, &mut StepLog::disabled());
+
+        assert_eq!(result.command, "echo ***");
+        assert_eq!(result.output, "***\n");
+    }
+
+    fn host() -> Host {
+        Host {
+            address: String::from("build.example.com"),
+            user: Some(String::from("ci")),
+            port: Some(2222),
+            identity_file: None,
+            workdir: Some(String::from("/srv/builds")),
+        }
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("cargo"), "cargo");
+        assert_eq!(quote("--name=x"), "--name=x");
+        assert_eq!(quote("exit 1"), "'exit 1'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn test_ssh_remote_command() {
+        let executor = SshExecutor::new(&host(), Path::new("/tmp/work"));
+        let command = Command {
+            command: String::from("bash"),
+            args: vec![String::from("-c"), String::from("exit 1")],
+        };
+
+        let remote = executor.remote_command(&command);
+        assert!(remote.starts_with("cd /srv/builds/"));
+        assert!(remote.ends_with(" && bash -c 'exit 1'"));
+
+        let args = executor.ssh_args(&remote);
+        assert_eq!(&args[..6], &[
+            "-o", "BatchMode=yes", "-p", "2222", "ci@build.example.com", "--",
+        ]);
+        assert_eq!(args[6], remote);
+    }
+
+    #[test]
+    fn test_rsync_args() {
+        let executor = SshExecutor::new(&host(), Path::new("/tmp/work"));
+
+        let args = executor.rsync_args();
+
+        assert_eq!(args[3], "ssh -o BatchMode=yes -p 2222");
+        assert_eq!(args[4], "/tmp/work/");
+        assert_eq!(args[5], format!("ci@build.example.com:{}/", executor.remote_dir));
+    }
+
+    /// Needs an SSH server on localhost that accepts the key of the current
+    /// user and rsync on both ends, run it with `cargo test -- --ignored`.
+    /// `CINDERELLA_TEST_SSH_HOST`, `_USER` and `_PORT` select another host.
+    #[test]
+    #[ignore]
+    fn test_ssh_executor_runs_steps_on_build_host() {
+        let var = |name: &str| std::env::var(name).ok();
+        let host = Host {
+            address: var("CINDERELLA_TEST_SSH_HOST").unwrap_or_else(|| String::from("localhost")),
+            user: var("CINDERELLA_TEST_SSH_USER"),
+            port: var("CINDERELLA_TEST_SSH_PORT").map(|port| port.parse().unwrap()),
+            identity_file: None,
+            workdir: None,
+        };
+        let workdir = tempfile::tempdir().unwrap();
+        std::fs::write(workdir.path().join("Cargo.toml"), "[package]\n").unwrap();
+
+        let mut executor = SshExecutor::new(&host, workdir.path());
+        executor.prepare().unwrap();
+
+        let command = Command {
+            command: String::from("sh"),
+            args: vec![String::from("-c"), String::from("cat Cargo.toml; pwd; exit 3")],
+        };
+        let result = executor.execute(&command, &mut StepLog::disabled());
+        executor.cleanup();
+
+        assert!(result.output.contains(&format!("[package]\n{}\n", executor.remote_dir)));
+        assert_eq!(result.failure, Some(Failure::ExitCode(3)));
+
+        // the remote work dir is gone, so the step cannot change into it
+        let result = executor.execute(&command, &mut StepLog::disabled());
+        assert!(!result.output.contains("[package]"));
+        assert!(result.failure.is_some());
+    }
+
+    #[test]
+    fn test_local_executor_reports_exit_code() {
+        let command = Command {
+            command: String::from("bash"),
+            args: vec![String::from("-c"), String::from("echo out; exit 3")],
+        };
+
+        let executor = local_executor(Limits::default());
+        let result = executor.execute(&command, &mut StepLog::disabled());
+
+        assert_eq!(result.output, "out\n");
+        assert_eq!(result.failure, Some(Failure::ExitCode(3)));
+    }
+
+    #[test]
+    fn test_output_and_usage_are_collected() {
+        let command = Command {
+            command: String::from("bash"),
+            args: vec![String::from("-c"), String::from("echo out; echo err >&2; sleep 0.2")],
+        };
+        let executor = local_executor(Limits::default());
+
+        let result = executor.execute(&command, &mut StepLog::disabled());
+
+        assert!(result.is_success());
+        assert_eq!(result.output, "out\nerr\n");
+        assert!(result.usage.unwrap().wall_time >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_cpu_time_limit_is_reported() {
+        let command = Command {
+            command: String::from("bash"),
+            args: vec![String::from("-c"), String::from("while true; do :; done")],
+        };
+        let executor = local_executor(Limits {
+            cpu_time: Some(1),
+            ..Limits::default()
+        });
+
+        let result = executor.execute(&command, &mut StepLog::disabled());
+
+        assert_eq!(result.failure, Some(Failure::LimitExceeded(
+            String::from("CPU time limit of 1s exceeded"))));
+        let usage = result.usage.unwrap();
+        // the accounting of CPU time is not exact
+        assert!(usage.user_time + usage.system_time >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn test_limit_exceeded_in_shell_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = Command {
+            command: String::from("bash"),
+            args: vec![
+                String::from("-c"),
+                format!("dd if=/dev/zero of={}/big bs=4096 count=1 2>/dev/null; exit $?",
+                        dir.path().display()),
+            ],
+        };
+        let executor = local_executor(Limits {
+            file_size: Some(1024),
+            ..Limits::default()
+        });
+
+        let result = executor.execute(&command, &mut StepLog::disabled());
+
+        assert_eq!(result.failure, Some(Failure::LimitExceeded(
+            String::from("file size limit of 1K exceeded"))));
+    }
+}
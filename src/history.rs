@@ -0,0 +1,690 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ExecutionConfig, Trigger};
+use crate::execution::{Failure, StepResult};
+use crate::locking;
+use crate::test_results::TestResults;
+use crate::usage::format_duration;
+
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Status of a build that has not finished (yet)
+pub const RUNNING: &str = "running";
+
+/// A build in the history, stored in `<dir>/<project>-<hash>/<number>.toml`
+/// (see `locking::project_key`)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BuildRecord {
+    /// Number of the build, counted per project starting with 1
+    pub number: u64,
+    pub project: String,
+    pub repo_url: String,
+    pub branch: Option<String>,
+    pub tag: Option<String>,
+    /// The id of the built commit
+    pub commit: Option<String>,
+    pub trigger: String,
+    /// Pipelines selected with `--pipeline`
+    #[serde(default)]
+    pub selected_pipelines: Vec<String>,
+    /// Pipelines skipped with `--skip`
+    #[serde(default)]
+    pub skipped_pipelines: Vec<String>,
+    /// The CI configuration file set with `--file`
+    pub cinderella_file: Option<String>,
+    /// Number of the build that was repeated by this build
+    pub rerun_of: Option<u64>,
+    pub started: String,
+    pub finished: Option<String>,
+    /// `running`, `success`, `failed`, `cancelled` or `no pipeline executed`
+    pub status: String,
+    pub log_dir: Option<String>,
+    #[serde(default)]
+    pub pipelines: Vec<PipelineRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PipelineRecord {
+    pub name: String,
+    pub success: bool,
+    pub steps: Vec<StepRecord>,
+    /// The parsed test results, if the pipeline declares test reports or
+    /// a test output format
+    #[serde(default)]
+    pub tests: Option<TestResults>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StepRecord {
+    pub command: String,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    /// Why the step failed, e.g. `killed by signal 9`
+    pub failure: Option<String>,
+    /// Wall time of the command in seconds
+    pub duration: Option<f64>,
+    /// The step log with the complete output
+    pub log_file: Option<String>,
+}
+
+impl From<&StepResult> for StepRecord {
+    fn from(step: &StepResult) -> StepRecord {
+        StepRecord {
+            command: step.command.clone(),
+            success: step.is_success(),
+            exit_code: match step.failure {
+                None => Some(0),
+                Some(Failure::ExitCode(code)) => Some(code),
+                Some(_) => None,
+            },
+            failure: step.failure.as_ref().map(|failure| failure.to_string()),
+            duration: step.usage.map(|usage| usage.wall_time.as_secs_f64()),
+            log_file: step.log_file.as_ref().map(|path| path.to_string_lossy().to_string()),
+        }
+    }
+}
+
+impl BuildRecord {
+    /// The built branch or tag
+    pub fn git_ref(&self) -> &str {
+        match (&self.branch, &self.tag) {
+            (Some(branch), _) => branch,
+            (None, Some(tag)) => tag,
+            (None, None) => "default",
+        }
+    }
+
+    /// The configuration that repeats the build with the same commit
+    pub fn rerun_config(&self) -> Result<ExecutionConfig, String> {
+        let commit = self.commit.clone()
+            .ok_or_else(|| format!("The commit of build #{} is unknown", self.number))?;
+
+        Ok(ExecutionConfig {
+            repo_url: self.repo_url.clone(),
+            branch: self.branch.clone(),
+            tag: self.tag.clone(),
+            cinderella_filepath: self.cinderella_file.clone(),
+            pipelines: self.selected_pipelines.clone(),
+            skip_pipelines: self.skipped_pipelines.clone(),
+            trigger: self.trigger.parse::<Trigger>()?,
+            commit: Some(commit),
+            rerun_of: Some(self.number),
+        })
+    }
+
+    /// How long the build took, `None` if it has not finished
+    pub fn duration(&self) -> Option<Duration> {
+        let parse = |time: &str| NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok();
+
+        let started = parse(&self.started)?;
+        let finished = parse(self.finished.as_ref()?)?;
+        (finished - started).to_std().ok()
+    }
+
+    /// The pipeline and the step that made the build fail
+    pub fn failed_step(&self) -> Option<(&PipelineRecord, &StepRecord)> {
+        self.pipelines.iter()
+            .flat_map(|pipeline| pipeline.steps.iter().map(move |step| (pipeline, step)))
+            .find(|(_, step)| !step.success)
+    }
+
+    /// A one-line summary of the build, e.g. for the build lists
+    pub fn describe(&self) -> String {
+        let mut description = format!("#{} {} {}, started {}",
+                                      self.number, self.git_ref(), self.status, self.started);
+
+        if let Some(duration) = self.duration() {
+            description.push_str(&format!(", took {}", format_duration(duration)));
+        }
+        if let Some(commit) = &self.commit {
+            description.push_str(&format!(", commit {}", short_commit(commit)));
+        }
+        if let Some(rerun_of) = self.rerun_of {
+            description.push_str(&format!(", rerun of #{}", rerun_of));
+        }
+        if let Some((pipeline, step)) = self.failed_step() {
+            description.push_str(&format!(", failed in {}: {}", pipeline.name, step.command));
+
+            if let Some(tests) = pipeline.tests.as_ref().filter(|tests| tests.failed > 0) {
+                description.push_str(&format!(
+                    " ({} of {} tests failed)", tests.failed, tests.total()));
+            }
+        }
+
+        description
+    }
+
+    /// All information about the build, one line per field and step
+    pub fn details(&self) -> String {
+        let optional = |value: &Option<String>| value.clone().unwrap_or_else(|| String::from("-"));
+        let mut details = format!("Build #{} of {}\n", self.number, self.project);
+
+        details.push_str(&format!("Repository: {}\n", self.repo_url));
+        match &self.tag {
+            Some(tag) => details.push_str(&format!("Tag: {}\n", tag)),
+            None => details.push_str(&format!("Branch: {}\n", self.git_ref())),
+        }
+        details.push_str(&format!("Commit: {}\n", optional(&self.commit)));
+        details.push_str(&format!("Trigger: {}\n", self.trigger));
+        if !self.selected_pipelines.is_empty() {
+            details.push_str(&format!("Selected pipelines: {}\n", self.selected_pipelines.join(", ")));
+        }
+        if !self.skipped_pipelines.is_empty() {
+            details.push_str(&format!("Skipped pipelines: {}\n", self.skipped_pipelines.join(", ")));
+        }
+        if let Some(cinderella_file) = &self.cinderella_file {
+            details.push_str(&format!("CI configuration: {}\n", cinderella_file));
+        }
+        if let Some(rerun_of) = self.rerun_of {
+            details.push_str(&format!("Rerun of: #{}\n", rerun_of));
+        }
+        details.push_str(&format!("Started: {}\n", self.started));
+        details.push_str(&format!("Finished: {}\n", optional(&self.finished)));
+        if let Some(duration) = self.duration() {
+            details.push_str(&format!("Duration: {}\n", format_duration(duration)));
+        }
+        details.push_str(&format!("Status: {}\n", self.status));
+        details.push_str(&format!("Log: {}\n", optional(&self.log_dir)));
+
+        for pipeline in &self.pipelines {
+            details.push_str(&format!("\nPipeline {}: {}\n", pipeline.name,
+                                      if pipeline.success { "ok" } else { "failed" }));
+
+            for step in &pipeline.steps {
+                let outcome = match &step.failure {
+                    Some(failure) => failure.clone(),
+                    None => String::from("ok"),
+                };
+                let duration = step.duration
+                    .map(|duration| format!(" [{}]", format_duration(Duration::from_secs_f64(duration))))
+                    .unwrap_or_default();
+
+                details.push_str(&format!("  {}: {}{}\n", step.command, outcome, duration));
+            }
+
+            if let Some(tests) = &pipeline.tests {
+                details.push_str(&format!("  Tests: {}\n", tests.describe()));
+                for line in tests.failure_summary().lines() {
+                    details.push_str(&format!("    {}\n", line));
+                }
+            }
+        }
+
+        details
+    }
+
+    /// Marks the build as finished and stores the results of its steps and
+    /// the test results of its pipelines
+    pub fn finish(
+        &mut self,
+        dir: &Path,
+        status: &str,
+        steps: &[StepResult],
+        tests: &HashMap<String, TestResults>) -> Result<(), String>
+    {
+        self.finished = Some(now());
+        self.status = status.to_string();
+        self.pipelines = pipeline_records(steps, tests);
+
+        self.save(dir)
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), String> {
+        let path = record_path(&dir.join(locking::project_key(&self.repo_url)), self.number);
+        let contents = toml::to_string(self)
+            .map_err(|e| format!("Could not serialize build record: {}", e))?;
+
+        // written to a temporary file first, so that readers never see a
+        // partially written record
+        let tmp_path = path.with_extension("toml.tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &path))
+            .map_err(|e| format!("Could not write build record {:?}: {}", path, e))
+    }
+}
+
+/// Records the start of a build and assigns the next build number of the
+/// project to it
+pub fn start(dir: &Path, exec_config: &ExecutionConfig, commit: Option<String>)
+    -> Result<BuildRecord, String>
+{
+    let project_dir = dir.join(locking::project_key(&exec_config.repo_url));
+    fs::create_dir_all(&project_dir)
+        .map_err(|e| format!("Could not create history dir {:?}: {}", project_dir, e))?;
+
+    let mut number = latest_number(&project_dir)? + 1;
+    // the file is created exclusively to reserve the number, concurrent
+    // builds of the same project take the next one
+    loop {
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(record_path(&project_dir, number));
+
+        match created {
+            Ok(_) => break,
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => number += 1,
+            Err(e) => return Err(format!("Could not create build record: {}", e)),
+        }
+    }
+
+    let record = BuildRecord {
+        number,
+        project: exec_config.name(),
+        repo_url: exec_config.repo_url.clone(),
+        branch: exec_config.branch.clone(),
+        tag: exec_config.tag.clone(),
+        commit,
+        trigger: exec_config.trigger.as_str().to_string(),
+        selected_pipelines: exec_config.pipelines.clone(),
+        skipped_pipelines: exec_config.skip_pipelines.clone(),
+        cinderella_file: exec_config.cinderella_filepath.clone(),
+        rerun_of: exec_config.rerun_of,
+        started: now(),
+        finished: None,
+        status: String::from(RUNNING),
+        log_dir: None,
+        pipelines: vec![],
+    };
+    record.save(dir)?;
+
+    Ok(record)
+}
+
+/// Loads all builds of a project, the latest build first
+///
+/// The project is given by its name or, if several projects have the same
+/// name, by its repository URL.
+pub fn list(dir: &Path, project: &str) -> Result<Vec<BuildRecord>, String> {
+    let project_dirs = project_dirs(dir, Some(project))?;
+
+    match project_dirs.as_slice() {
+        [] => Ok(vec![]),
+        [project_dir] => records(project_dir),
+        _ => {
+            let urls: Vec<String> = project_dirs.iter()
+                .filter_map(|project_dir| records(project_dir).ok()?.into_iter().next())
+                .map(|record| record.repo_url)
+                .collect();
+            Err(format!("Several projects are called {}, use the repository URL instead: {}",
+                        project, urls.join(", ")))
+        },
+    }
+}
+
+/// Loads all builds in the directory of a project, the latest build first
+fn records(project_dir: &Path) -> Result<Vec<BuildRecord>, String> {
+    let mut records = Vec::new();
+    for number in numbers(project_dir)? {
+        let path = record_path(project_dir, number);
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read build record {:?}: {}", path, e))?;
+
+        // a reserved number whose record has not been written yet
+        if contents.is_empty() {
+            continue;
+        }
+
+        match toml::from_str(&contents) {
+            Ok(record) => records.push(record),
+            Err(e) => eprintln!("Invalid build record {:?}: {}", path, e),
+        }
+    }
+
+    records.sort_by_key(|record: &BuildRecord| std::cmp::Reverse(record.number));
+    Ok(records)
+}
+
+/// Loads a single build of a project
+pub fn find(dir: &Path, project: &str, number: u64) -> Result<BuildRecord, String> {
+    list(dir, project)?.into_iter()
+        .find(|record| record.number == number)
+        .ok_or_else(|| format!("No build #{} of {}", number, project))
+}
+
+/// The latest build of each project and branch or tag
+///
+/// Only the given project and ref are included if they are set.
+pub fn latest(dir: &Path, project: Option<&str>, git_ref: Option<&str>)
+    -> Result<Vec<BuildRecord>, String>
+{
+    let mut latest: Vec<BuildRecord> = Vec::new();
+    for project_dir in project_dirs(dir, project)? {
+        for record in records(&project_dir)? {
+            let is_included = git_ref.is_none() || git_ref == Some(record.git_ref());
+            let is_known = latest.iter().any(|known| {
+                known.repo_url == record.repo_url && known.git_ref() == record.git_ref()
+            });
+
+            if is_included && !is_known {
+                latest.push(record);
+            }
+        }
+    }
+
+    Ok(latest)
+}
+
+/// The directories of all projects with builds in the history, sorted by
+/// name, or only of the given project
+fn project_dirs(dir: &Path, project: Option<&str>) -> Result<Vec<PathBuf>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("Could not read history dir {:?}: {}", dir, e)),
+    };
+
+    let mut project_dirs: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter(|entry| project.iter().all(|project| {
+            locking::is_project_key(&entry.file_name().to_string_lossy(), project)
+        }))
+        .map(|entry| entry.path())
+        .collect();
+    project_dirs.sort();
+
+    Ok(project_dirs)
+}
+
+fn short_commit(commit: &str) -> &str {
+    &commit[..commit.len().min(7)]
+}
+
+fn record_path(project_dir: &Path, number: u64) -> PathBuf {
+    project_dir.join(format!("{}.toml", number))
+}
+
+fn latest_number(project_dir: &Path) -> Result<u64, String> {
+    Ok(numbers(project_dir)?.into_iter().max().unwrap_or(0))
+}
+
+/// The numbers of all builds with a record in the project directory
+fn numbers(project_dir: &Path) -> Result<Vec<u64>, String> {
+    let entries = match fs::read_dir(project_dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("Could not read history dir {:?}: {}", project_dir, e)),
+    };
+
+    Ok(entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            name.strip_suffix(".toml")?.parse().ok()
+        })
+        .collect())
+}
+
+/// Groups the steps by pipeline in the order they were executed
+fn pipeline_records(steps: &[StepResult], tests: &HashMap<String, TestResults>)
+    -> Vec<PipelineRecord>
+{
+    let mut pipelines: Vec<PipelineRecord> = Vec::new();
+
+    for step in steps {
+        let record = StepRecord::from(step);
+
+        match pipelines.last_mut() {
+            Some(pipeline) if pipeline.name == step.pipeline => {
+                pipeline.success &= record.success;
+                pipeline.steps.push(record);
+            },
+            _ => pipelines.push(PipelineRecord {
+                name: step.pipeline.clone(),
+                success: record.success,
+                steps: vec![record],
+                tests: tests.get(&step.pipeline).cloned(),
+            }),
+        }
+    }
+
+    pipelines
+}
+
+fn now() -> String {
+    chrono::Local::now().format(TIME_FORMAT).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CinderellaConfig, Trigger};
+    use crate::execution::{self, ExecutionContext, ExecutionResult};
+    use crate::pipeline::Pipeline;
+    use crate::test_results::TestFailure;
+
+    fn step(pipeline: &str, command: &str, failure: Option<Failure>) -> StepResult {
+        StepResult {
+            pipeline: String::from(pipeline),
+            failure,
+            ..StepResult::not_executed(String::from(command), String::new())
+        }
+    }
+
+    /// Runs a passing pipeline and a failing one after it
+    fn execute_failed_build(context: &ExecutionContext) -> Vec<StepResult> {
+        let pipelines = vec![
+            Pipeline {
+                name: String::from("build"),
+                commands: vec![String::from("echo build")],
+                ..Pipeline::default()
+            },
+            Pipeline {
+                name: String::from("test"),
+                commands: vec![String::from("bash -c \"exit 3\"")],
+                ..Pipeline::default()
+            },
+        ];
+
+        match execution::execute(&pipelines, &HashMap::new(), context) {
+            ExecutionResult::Error(steps) => steps,
+            _ => panic!("build should fail"),
+        }
+    }
+
+    #[test]
+    fn test_build_numbers_are_counted_per_project() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let website = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let blog = ExecutionConfig::for_branch("/srv/git/blog.git", "master");
+
+        let first = start(dir.path(), &website, None).unwrap();
+        let second = start(dir.path(), &website, None).unwrap();
+        let other = start(dir.path(), &blog, None).unwrap();
+
+        assert_eq!(first.number, 1);
+        assert_eq!(second.number, 2);
+        assert_eq!(other.number, 1);
+    }
+
+    #[test]
+    fn test_projects_with_the_same_name_are_kept_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let website = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let fork = ExecutionConfig::for_branch("/srv/forks/website.git", "master");
+
+        start(dir.path(), &website, None).unwrap();
+        start(dir.path(), &website, None).unwrap();
+        let other = start(dir.path(), &fork, None).unwrap();
+
+        assert_eq!(other.number, 1);
+        assert_eq!(list(dir.path(), "/srv/git/website.git").unwrap().len(), 2);
+        assert_eq!(find(dir.path(), "/srv/forks/website.git", 1).unwrap().repo_url,
+                   "/srv/forks/website.git");
+        assert!(list(dir.path(), "website.git").unwrap_err()
+            .starts_with("Several projects are called website.git"));
+        assert_eq!(latest(dir.path(), None, None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_finished_build_is_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let commit = Some(String::from("0123456789abcdef"));
+
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let mut record = start(dir.path(), &exec_config, commit).unwrap();
+        assert_eq!(list(dir.path(), "website.git").unwrap()[0].status, RUNNING);
+
+        let steps = vec![
+            step("build", "cargo build", None),
+            step("test", "cargo test", None),
+            step("test", "cargo clippy", Some(Failure::ExitCode(101))),
+        ];
+        let mut tests = HashMap::new();
+        tests.insert(String::from("test"), TestResults {
+            passed: 12,
+            failed: 1,
+            skipped: 0,
+            failures: vec![TestFailure {
+                name: String::from("tests::test_login"),
+                message: String::from("assertion failed"),
+            }],
+        });
+        record.finish(dir.path(), "failed", &steps, &tests).unwrap();
+
+        let records = list(dir.path(), "website.git").unwrap();
+        assert_eq!(records, vec![record]);
+
+        let record = &records[0];
+        assert_eq!(record.status, "failed");
+        assert_eq!(record.commit, Some(String::from("0123456789abcdef")));
+        assert!(record.finished.is_some());
+        assert_eq!(record.pipelines.len(), 2);
+        assert!(record.pipelines[0].success);
+        assert!(!record.pipelines[1].success);
+        assert_eq!(record.pipelines[1].steps[0].exit_code, Some(0));
+        assert_eq!(record.pipelines[1].steps[1].exit_code, Some(101));
+        assert_eq!(record.pipelines[1].steps[1].failure, Some(String::from("exit code 101")));
+        assert_eq!(record.pipelines[0].tests, None);
+        assert_eq!(record.pipelines[1].tests.as_ref().unwrap().failures[0].name,
+                   "tests::test_login");
+        assert!(record.describe().ends_with(
+            "failed in test: cargo clippy (1 of 13 tests failed)"));
+        assert!(record.details().contains(
+            "  Tests: 12 passed, 1 failed, 0 skipped\n    tests::test_login\n        assertion failed\n"));
+    }
+
+    #[test]
+    fn test_passed_pipelines_of_failed_build_are_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let mut record = start(dir.path(), &exec_config, None).unwrap();
+        let config = CinderellaConfig::default();
+        let context = ExecutionContext::new(&config);
+
+        let steps = execute_failed_build(&context);
+        record.finish(dir.path(), "failed", &steps, &context.test_results.borrow()).unwrap();
+
+        let record = &list(dir.path(), "website.git").unwrap()[0];
+        assert_eq!(record.pipelines.len(), 2);
+        assert_eq!(record.pipelines[0].name, "build");
+        assert!(record.pipelines[0].success);
+        assert_eq!(record.pipelines[0].steps[0].command, "echo build");
+        assert_eq!(record.pipelines[0].steps[0].exit_code, Some(0));
+        assert_eq!(record.pipelines[1].name, "test");
+        assert!(!record.pipelines[1].success);
+        assert_eq!(record.pipelines[1].steps[0].exit_code, Some(3));
+    }
+
+    #[test]
+    fn test_list_latest_build_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        for _ in 0..10 {
+            start(dir.path(), &exec_config, None).unwrap();
+        }
+
+        let numbers: Vec<u64> = list(dir.path(), "website.git").unwrap()
+            .iter().map(|record| record.number).collect();
+
+        assert_eq!(numbers, (1..=10).rev().collect::<Vec<u64>>());
+        assert!(list(dir.path(), "unknown.git").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_latest_build_per_ref() {
+        let dir = tempfile::tempdir().unwrap();
+        let website = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let mut release = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        release.branch = None;
+        release.tag = Some(String::from("v1.0"));
+
+        start(dir.path(), &website, None).unwrap();
+        start(dir.path(), &release, None).unwrap();
+        start(dir.path(), &website, None).unwrap();
+        start(dir.path(), &ExecutionConfig::for_branch("/srv/git/blog.git", "master"), None).unwrap();
+
+        let builds: Vec<(String, u64)> = latest(dir.path(), None, None).unwrap().iter()
+            .map(|record| (format!("{} {}", record.project, record.git_ref()), record.number))
+            .collect();
+        assert_eq!(builds, vec![
+            (String::from("blog.git master"), 1),
+            (String::from("website.git master"), 3),
+            (String::from("website.git v1.0"), 2),
+        ]);
+
+        let builds = latest(dir.path(), Some("website.git"), Some("v1.0")).unwrap();
+        assert_eq!(builds.len(), 1);
+        assert_eq!(builds[0].number, 2);
+    }
+
+    #[test]
+    fn test_describe_failed_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let commit = Some(String::from("0123456789abcdef"));
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let mut record = start(dir.path(), &exec_config, commit).unwrap();
+        record.finish(dir.path(), "failed", &[
+            step("test", "cargo test", Some(Failure::ExitCode(101))),
+        ], &HashMap::new()).unwrap();
+        record.started = String::from("2020-03-01T12:00:00");
+        record.finished = Some(String::from("2020-03-01T12:01:05"));
+
+        assert_eq!(record.duration(), Some(Duration::from_secs(65)));
+        assert_eq!(record.describe(),
+                   "#1 master failed, started 2020-03-01T12:00:00, took 65.0s, \
+                    commit 0123456, failed in test: cargo test");
+
+        let details = record.details();
+        assert!(details.starts_with("Build #1 of website.git\n"));
+        assert!(details.contains("\nPipeline test: failed\n  cargo test: exit code 101\n"));
+    }
+
+    #[test]
+    fn test_rerun_config_repeats_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut original = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        original.pipelines = vec![String::from("deploy")];
+        original.skip_pipelines = vec![String::from("lint")];
+        original.cinderella_filepath = Some(String::from("ci/release.toml"));
+        original.trigger = Trigger::Schedule;
+
+        let commit = Some(String::from("0123456789abcdef"));
+        let record = start(dir.path(), &original, commit).unwrap();
+        let rerun = record.rerun_config().unwrap();
+
+        assert_eq!(rerun.repo_url, "/srv/git/website.git");
+        assert_eq!(rerun.branch, Some(String::from("master")));
+        assert_eq!(rerun.commit, Some(String::from("0123456789abcdef")));
+        assert_eq!(rerun.pipelines, vec![String::from("deploy")]);
+        assert_eq!(rerun.skip_pipelines, vec![String::from("lint")]);
+        assert_eq!(rerun.cinderella_filepath, Some(String::from("ci/release.toml")));
+        assert_eq!(rerun.trigger, Trigger::Schedule);
+        assert_eq!(rerun.rerun_of, Some(1));
+
+        let record = start(dir.path(), &rerun, None).unwrap();
+        assert_eq!(record.rerun_of, Some(1));
+        assert!(record.describe().contains(", rerun of #1"));
+        assert!(record.rerun_config().is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use crate::execution::{Failure, StepResult};
+use crate::test_results::TestResults;
+
+/// Renders the steps of a build as JUnit XML, with one test suite per
+/// pipeline and one test case per step
+///
+/// Failed steps of pipelines with failed tests list these tests instead of
+/// the tail of their output.
+pub fn render(project: &str, steps: &[StepResult], tests: &HashMap<String, TestResults>)
+    -> String
+{
+    let mut suites: Vec<(&str, Vec<&StepResult>)> = Vec::new();
+    for step in steps {
+        match suites.last_mut() {
+            Some((pipeline, steps)) if *pipeline == step.pipeline => steps.push(step),
+            _ => suites.push((&step.pipeline, vec![step])),
+        }
+    }
+
+    let all: Vec<&StepResult> = steps.iter().collect();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n",
+        escape(project), all.len(), count(&all, is_failure), count(&all, is_error),
+        time(&all)));
+
+    for (pipeline, steps) in suites {
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n",
+            escape(pipeline), steps.len(), count(&steps, is_failure),
+            count(&steps, is_error), time(&steps)));
+
+        for step in steps {
+            xml.push_str(&test_case(project, step, tests.get(pipeline)));
+        }
+
+        xml.push_str("  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn test_case(project: &str, step: &StepResult, tests: Option<&TestResults>) -> String {
+    let mut xml = format!(
+        "    <testcase name=\"{}\" classname=\"{}.{}\" time=\"{:.3}\"",
+        escape(&step.command), escape(project), escape(&step.pipeline), time(&[step]));
+
+    let failure = match &step.failure {
+        None => return xml + "/>\n",
+        Some(failure) => failure,
+    };
+
+    // steps that could not be executed are errors, all others failures
+    let element = if is_error(step) { "error" } else { "failure" };
+    let failure_type = match failure {
+        Failure::NotExecuted => "NotExecuted",
+        Failure::UnreadableOutput => "UnreadableOutput",
+        Failure::ExitCode(_) => "ExitCode",
+        Failure::Signal(_) => "Signal",
+        Failure::LimitExceeded(_) => "LimitExceeded",
+        Failure::Cancelled => "Cancelled",
+    };
+    let mut output = String::new();
+    match tests.filter(|tests| !tests.failures.is_empty()) {
+        Some(tests) => {
+            output.push_str(&format!("Tests: {}\n", tests.describe()));
+            output.push_str(&tests.failure_summary());
+        },
+        None => {
+            if step.truncated {
+                output.push_str("[output truncated, only the last lines are shown]\n");
+            }
+            output.push_str(&step.output);
+        },
+    }
+
+    xml.push_str(&format!(
+        ">\n      <{} message=\"{}\" type=\"{}\">{}</{}>\n    </testcase>\n",
+        element, escape(&failure.to_string()), failure_type, escape(&output), element));
+    xml
+}
+
+fn is_failure(step: &StepResult) -> bool {
+    !step.is_success() && !is_error(step)
+}
+
+fn is_error(step: &StepResult) -> bool {
+    step.failure == Some(Failure::NotExecuted)
+}
+
+fn count(steps: &[&StepResult], filter: fn(&StepResult) -> bool) -> usize {
+    steps.iter().filter(|step| filter(step)).count()
+}
+
+/// The wall time of the steps in seconds
+fn time(steps: &[&StepResult]) -> f64 {
+    steps.iter()
+        .filter_map(|step| step.usage)
+        .map(|usage| usage.wall_time.as_secs_f64())
+        .sum()
+}
+
+/// Escapes text for attributes and element content, characters that are
+/// not allowed in XML 1.0 (like the escape sequences of colored output)
+/// are dropped
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' || c == '\u{fffe}' || c == '\u{ffff}' => (),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::config::CinderellaConfig;
+    use crate::execution::{self, ExecutionContext, ExecutionResult};
+    use crate::pipeline::Pipeline;
+    use crate::test_results::TestFailure;
+    use crate::usage::Usage;
+
+    fn step(pipeline: &str, command: &str, output: &str, failure: Option<Failure>) -> StepResult {
+        StepResult {
+            pipeline: String::from(pipeline),
+            command: String::from(command),
+            output: String::from(output),
+            truncated: false,
+            failure,
+            usage: Some(Usage {
+                wall_time: Duration::from_millis(1500),
+                user_time: Duration::from_millis(1000),
+                system_time: Duration::from_millis(100),
+                max_rss: 4096,
+            }),
+            log_file: None,
+        }
+    }
+
+    /// Runs a passing pipeline and a failing one after it
+    fn execute_failed_build(context: &ExecutionContext) -> Vec<StepResult> {
+        let pipelines = vec![
+            Pipeline {
+                name: String::from("build"),
+                commands: vec![String::from("echo build")],
+                ..Pipeline::default()
+            },
+            Pipeline {
+                name: String::from("test"),
+                commands: vec![String::from("bash -c \"exit 3\"")],
+                ..Pipeline::default()
+            },
+        ];
+
+        match execution::execute(&pipelines, &HashMap::new(), context) {
+            ExecutionResult::Error(steps) => steps,
+            _ => panic!("build should fail"),
+        }
+    }
+
+    #[test]
+    fn test_render_suite_per_pipeline() {
+        let steps = vec![
+            step("build", "cargo build", "Compiling\n", None),
+            step("test", "cargo test", "test result: \u{1b}[31mFAILED\u{1b}[0m <1 failed>\n",
+                 Some(Failure::ExitCode(101))),
+            StepResult {
+                pipeline: String::from("test"),
+                ..StepResult::not_executed(String::from("%MISSING"), String::from("Unknown variable"))
+            },
+        ];
+
+        let xml = render("website.git", &steps, &HashMap::new());
+
+        assert!(xml.contains(
+            "<testsuites name=\"website.git\" tests=\"3\" failures=\"1\" errors=\"1\" time=\"3.000\">"));
+        assert!(xml.contains(
+            "  <testsuite name=\"build\" tests=\"1\" failures=\"0\" errors=\"0\" time=\"1.500\">\n\
+             \x20   <testcase name=\"cargo build\" classname=\"website.git.build\" time=\"1.500\"/>\n\
+             \x20 </testsuite>\n"));
+        assert!(xml.contains(
+            "<failure message=\"exit code 101\" type=\"ExitCode\">\
+             test result: [31mFAILED[0m &lt;1 failed&gt;\n</failure>"));
+        assert!(xml.contains(
+            "<error message=\"command was not executed\" type=\"NotExecuted\">Unknown variable</error>"));
+    }
+
+    #[test]
+    fn test_render_passed_pipelines_of_failed_build() {
+        let config = CinderellaConfig::default();
+        let context = ExecutionContext::new(&config);
+
+        let steps = execute_failed_build(&context);
+        let xml = render("website.git", &steps, &context.test_results.borrow());
+
+        assert!(xml.contains("tests=\"2\" failures=\"1\" errors=\"0\""));
+        assert!(xml.contains("<testsuite name=\"build\" tests=\"1\" failures=\"0\" errors=\"0\""));
+        assert!(xml.contains("<testcase name=\"echo build\" classname=\"website.git.build\""));
+        assert!(xml.contains("<testsuite name=\"test\" tests=\"1\" failures=\"1\" errors=\"0\""));
+    }
+
+    #[test]
+    fn test_render_failed_tests_instead_of_output() {
+        let steps = vec![
+            step("test", "cargo test", "running 13 tests\n", Some(Failure::ExitCode(101))),
+        ];
+        let mut tests = HashMap::new();
+        tests.insert(String::from("test"), TestResults {
+            passed: 12,
+            failed: 1,
+            skipped: 0,
+            failures: vec![TestFailure {
+                name: String::from("tests::test_login"),
+                message: String::from("assertion failed: 1 < 0"),
+            }],
+        });
+
+        let xml = render("website.git", &steps, &tests);
+
+        assert!(xml.contains(
+            "<failure message=\"exit code 101\" type=\"ExitCode\">\
+             Tests: 12 passed, 1 failed, 0 skipped\n\
+             tests::test_login\n    assertion failed: 1 &lt; 0\n</failure>"));
+        assert!(!xml.contains("running 13 tests"));
+    }
+}
@@ -10,15 +10,37 @@ mod vcs;
 mod parser;
 mod pipeline;
 mod execution;
+mod executor;
 mod mail;
 mod crypto;
 mod variables;
 mod dashboard;
+mod privileges;
+mod environment;
+mod limits;
+mod locking;
+mod logs;
+mod history;
+mod report;
+mod junit;
+mod test_results;
+mod masking;
+mod usage;
+mod sandbox;
+mod cron;
+mod daemon;
+mod condition;
+mod template;
+mod scheduler;
 
-pub use crate::config::ExecutionConfig;
+pub use crate::config::{ExecutionConfig, OutputFormat, Trigger};
+pub use crate::report::{Outcome, Report};
 
-use crate::config::{CinderellaConfig, Configs};
-use crate::execution::{ExecutionResult, StepResult};
+use crate::config::{CinderellaConfig, Configs, Secrets};
+use crate::execution::{ExecutionContext, ExecutionResult, StepResult};
+use crate::environment::Environment;
+use crate::logs::BuildLog;
+use crate::privileges::RunAs;
 use crate::vcs::CodeSource;
 use crate::vcs::WorkingCopy;
 use crate::dashboard::BuildStatus;
@@ -35,69 +57,377 @@ fn random_dir(base_path: &str) -> PathBuf {
     tempdir
 }
 
-fn appconfig_file() -> PathBuf {
+fn application_file(filename: &str) -> PathBuf {
     let mut application_path = env::current_exe().unwrap();
     application_path.pop();
-    application_path.push("config.toml");
+    application_path.push(filename);
 
     application_path
 }
 
-pub fn run(exec_config: &ExecutionConfig) {
-    let cinderella_config = CinderellaConfig::from_file(appconfig_file());
-    let configs = Configs {
-        cinderella_config: &cinderella_config,
-        execution_config: exec_config,
-    };
+fn appconfig_file() -> PathBuf {
+    application_file("config.toml")
+}
+
+fn spool_dir(cinderella_config: &CinderellaConfig) -> PathBuf {
+    match cinderella_config.daemon.as_ref().and_then(|daemon| daemon.spool_dir.as_ref()) {
+        Some(spool_dir) => PathBuf::from(spool_dir),
+        None => application_file("spool"),
+    }
+}
 
+/// Clones the repository into a new work dir, checks out the revision to
+/// build and switches to the work dir, so that all commands are executed
+/// there
+fn checkout(exec_config: &ExecutionConfig) -> Result<vcs::GitWorkingCopy, String> {
     let repo = vcs::GitSource {
         src: exec_config.repo_url.clone(),
     };
 
     // generate a temp unique work dir
     let tempdir = random_dir("/tmp/cinderella");
-    let workdir = repo.fetch(&tempdir).expect("could not clone repo");
+    let workdir = repo.fetch(&tempdir)
+        .map_err(|e| format!("Could not clone repo: {}", e))?;
 
     println!("Workdir is at {:?}", workdir.path);
 
     // checkout the branch if a branch was provided
     if let Some(branch) = &exec_config.branch {
         println!("Switching to branch {}", branch);
-        workdir.checkout_branch(&branch);
+        workdir.checkout_branch(branch)?;
     } else if let Some(tag) = &exec_config.tag {
         println!("Switching to tag {}", tag);
-        workdir.checkout_tag(&tag);
+        workdir.checkout_tag(tag)?;
+    }
+    if let Some(commit) = &exec_config.commit {
+        println!("Switching to commit {}", commit);
+        workdir.checkout_commit(commit)?;
     }
 
-    // Switch to the exported work dir so that all commands
-    // are executed there
     assert!(env::set_current_dir(&workdir.path).is_ok());
 
-    let cinderella_file = exec_config.cinderella_file(&workdir.path);
-    if let Some(pipelines) = pipeline::load_pipeline(&cinderella_file) {
+    Ok(workdir)
+}
+
+fn log_root(cinderella_config: &CinderellaConfig) -> PathBuf {
+    match cinderella_config.logs.as_ref().and_then(|logs| logs.root.as_ref()) {
+        Some(root) => PathBuf::from(root),
+        None => application_file("logs"),
+    }
+}
+
+fn history_dir(cinderella_config: &CinderellaConfig) -> PathBuf {
+    match &cinderella_config.history {
+        Some(history) => PathBuf::from(&history.dir),
+        None => application_file("history"),
+    }
+}
+
+fn lock_dir(cinderella_config: &CinderellaConfig) -> PathBuf {
+    match &cinderella_config.concurrency.lock_dir {
+        Some(lock_dir) => PathBuf::from(lock_dir),
+        None => application_file("locks"),
+    }
+}
+
+/// Executes a build and returns what happened as a report
+pub fn run(exec_config: &ExecutionConfig) -> Report {
+    let cinderella_config = CinderellaConfig::from_file(appconfig_file());
+
+    let policy = cinderella_config.project(&exec_config.repo_url)
+        .and_then(|project| project.concurrency)
+        .unwrap_or(cinderella_config.concurrency.policy);
+
+    // declared before the work dir, so that the next build only starts
+    // once the work dir is deleted
+    let _lock = match locking::acquire(&lock_dir(&cinderella_config), exec_config, policy) {
+        Ok(Some(lock)) => lock,
+        Ok(None) => return Report::new(exec_config, Outcome::Skipped),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return Report::error(exec_config, msg);
+        },
+    };
+
+    let workdir = match checkout(exec_config) {
+        Ok(workdir) => workdir,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return Report::error(exec_config, msg);
+        },
+    };
+
+    let history_dir = history_dir(&cinderella_config);
+    let home = match environment::home_dir(&workdir.path) {
+        Ok(home) => home,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return Report::error(exec_config, msg);
+        },
+    };
+
+    build(exec_config, &cinderella_config, &workdir.path, &home,
+          workdir.head_commit(), Some(history_dir))
+}
+
+/// Executes a build of the working tree of a local repository, including
+/// its uncommitted changes
+///
+/// The CI configuration is taken from the working tree and the branch from
+/// the HEAD of the repository. The build runs in a copy of the working tree
+/// unless `in_place` is set. Nobody is notified about a local build and it
+/// is not recorded in the history. If the repository has secrets and no
+/// password is given or configured, the password is asked for.
+pub fn exec(mut exec_config: ExecutionConfig, in_place: bool, password: Option<String>) -> Report {
+    let local = match vcs::LocalRepository::discover(Path::new(&exec_config.repo_url)) {
+        Ok(local) => local,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return Report::error(&exec_config, msg);
+        },
+    };
+    exec_config.repo_url = local.path.to_string_lossy().trim_end_matches('/').to_string();
+    exec_config.branch = local.branch();
+
+    let mut cinderella_config = CinderellaConfig::from_file(appconfig_file());
+    cinderella_config.email = None;
+    cinderella_config.dashboard = None;
+
+    // the build user would take over the developer's working tree
+    let has_run_as = cinderella_config.run_as.is_some()
+        || cinderella_config.projects.iter().any(|project| project.run_as.is_some());
+    if in_place && has_run_as {
+        println!("Ignoring run_as, the working tree is not handed over with --in-place");
+        cinderella_config.run_as = None;
+        for project in cinderella_config.projects.iter_mut() {
+            project.run_as = None;
+        }
+    }
+
+    let has_secrets = exec_config.secrets_file(&local.path).exists();
+    if let Some(password) = password {
+        cinderella_config.secrets = Some(Secrets { password });
+    } else if has_secrets && cinderella_config.secrets.is_none() {
+        match rpassword::read_password_from_tty(Some("Password of the secrets: ")) {
+            Ok(password) => cinderella_config.secrets = Some(Secrets { password }),
+            Err(e) => eprintln!("Could not read password, building without secrets: {}", e),
+        }
+    }
+
+    let copy = if in_place {
+        None
+    } else {
+        match local.copy_to(&random_dir("/tmp/cinderella")) {
+            Ok(copy) => Some(copy),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                return Report::error(&exec_config, msg);
+            },
+        }
+    };
+    let workdir = copy.as_ref().map_or(&local.path, |copy| &copy.path);
+
+    println!("Workdir is at {:?}", workdir);
+    if let Some(branch) = &exec_config.branch {
+        println!("Building the working tree of branch {}", branch);
+    }
+    assert!(env::set_current_dir(workdir).is_ok());
+
+    // the home is not put next to the working tree, where it could
+    // clash with a directory of the developer
+    let home = random_dir("/tmp/cinderella");
+
+    build(&exec_config, &cinderella_config, workdir, &home, local.head_commit(), None)
+}
+
+/// Executes the pipelines of a checked out work dir, builds are recorded
+/// in the history if a history dir is given
+fn build(
+    exec_config: &ExecutionConfig,
+    cinderella_config: &CinderellaConfig,
+    workdir: &PathBuf,
+    home: &Path,
+    commit: Option<String>,
+    history_dir: Option<PathBuf>) -> Report
+{
+    let configs = Configs {
+        cinderella_config,
+        execution_config: exec_config,
+    };
+
+    let mut report = Report {
+        commit,
+        ..Report::new(exec_config, Outcome::NoExecution)
+    };
+
+    let cinderella_file = exec_config.cinderella_file(workdir);
+    let pipelines = match pipeline::load_pipeline(&cinderella_file) {
+        Ok(pipelines) => pipelines,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return Report { outcome: Outcome::Error, error: Some(msg), ..report };
+        },
+    };
+    if let Some(pipelines) = pipelines {
+        let selection = exec_config.pipeline_selection();
+        let unknown = selection.unknown_pipelines(&pipelines);
+        if !unknown.is_empty() {
+            let msg = format!("Unknown pipelines: {}", unknown.join(", "));
+            eprintln!("{}", msg);
+            return Report { outcome: Outcome::Error, error: Some(msg), ..report };
+        }
+        println!("Pipeline selection: {}", selection.describe());
+
+        // the build user is dropped before the work dir, so that its home
+        // is deleted and the work dir is handed back first
+        let run_as = match RunAs::from_config(cinderella_config, exec_config, workdir, home) {
+            Ok(run_as) => run_as,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                return Report { outcome: Outcome::Error, error: Some(msg), ..report };
+            },
+        };
+        // creates the build home, which the build user takes over
+        let environment = match Environment::from_config(cinderella_config, exec_config, workdir, home) {
+            Ok(environment) => environment,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                return Report { outcome: Outcome::Error, error: Some(msg), ..report };
+            },
+        };
+        if let Some(run_as) = &run_as {
+            println!("Running build as user {}", run_as.user().name);
+
+            if let Err(msg) = run_as.prepare() {
+                eprintln!("{}", msg);
+                return Report { outcome: Outcome::Error, error: Some(msg), ..report };
+            }
+        }
+
         // TODO: Check if execution was successful. If not and if email is
         // configured, send a mail
-        let variables = variables::load(&workdir.path, &configs);
-        let res = execution::execute(&pipelines, &variables);
+        let secrets = variables::load_secrets_from_file(workdir, &configs);
+        let mut variables = variables::load(&configs, &secrets);
+        let mut context = ExecutionContext::new(cinderella_config);
+        context.selection = selection;
+        context.masked = masking::register_secrets(&secrets);
+        context.secrets = secrets;
+        context.run_as = run_as;
+        context.environment = environment;
+        let tail_lines = cinderella_config.logs.as_ref()
+            .map(|logs| logs.tail_lines)
+            .unwrap_or(logs::DEFAULT_TAIL_LINES);
+        context.log = match BuildLog::create(&log_root(cinderella_config), exec_config, tail_lines) {
+            Ok(log) => {
+                println!("Writing build log to {:?}", log.dir().unwrap());
+                log
+            },
+            Err(msg) => {
+                eprintln!("{}", msg);
+                BuildLog::disabled()
+            },
+        };
+
+        let started = history_dir.as_ref()
+            .map(|dir| history::start(dir, exec_config, report.commit.clone()));
+        let mut record = match started {
+            Some(Ok(record)) => {
+                println!("Build number {}", record.number);
+                report.build_number = Some(record.number);
+                Some(record)
+            },
+            Some(Err(msg)) => {
+                eprintln!("{}", msg);
+                None
+            },
+            None => None,
+        };
+        // builds that are not recorded, like local builds, get the number 0
+        let number = record.as_ref().map_or(0, |record| record.number).to_string();
+        variables.insert(String::from("build_number"), number.clone());
+        context.environment.set("CINDERELLA_BUILD_NUMBER", &number);
+
+        let res = execution::execute(&pipelines, &variables, &context);
+
+        report.outcome = match &res {
+            ExecutionResult::Success(_) => Outcome::Success,
+            ExecutionResult::Error(_) if locking::is_cancelled() => Outcome::Cancelled,
+            ExecutionResult::Error(_) => Outcome::Failed,
+            ExecutionResult::NoExecution => Outcome::NoExecution,
+        };
+        context.log.finish(report.outcome.as_str());
+
+        let steps = match &res {
+            ExecutionResult::Success(steps) | ExecutionResult::Error(steps) => &steps[..],
+            ExecutionResult::NoExecution => &[],
+        };
+        report.log_dir = context.log.dir().map(|dir| dir.to_string_lossy().to_string());
+        report.add_pipelines(&pipelines, &context, steps);
+
+        if let (Some(record), Some(history_dir)) = (&mut record, &history_dir) {
+            record.log_dir = report.log_dir.clone();
+            let tests = context.test_results.borrow();
+            if let Err(msg) = record.finish(history_dir, report.outcome.as_str(), steps, &tests) {
+                eprintln!("{}", msg);
+            }
+        }
+
+        if let ExecutionResult::Success(steps) | ExecutionResult::Error(steps) = &res {
+            println!("\nStep summary:\n{}", execution::summary(steps));
+            write_step_history(steps, exec_config, cinderella_config);
+            write_junit_report(steps, &context, exec_config, cinderella_config);
+        }
 
         match res {
             ExecutionResult::Success(_) => {
-                write_build_status(BuildStatus::Success, exec_config, &cinderella_config);
+                write_build_status(BuildStatus::Success, exec_config, cinderella_config);
+            },
+            // the build that cancelled this one reports its own result
+            ExecutionResult::Error(_) if locking::is_cancelled() => {
+                println!("Build was cancelled");
             },
             ExecutionResult::Error(steps) => {
                 let mut output = String::new();
 
-                for step in steps {
-                    match step {
-                        StepResult::Success(command, out)
-                            | StepResult::Error(command, out, _) =>
-                        {
-                            output.push_str(&command);
-                            // TODO: newline should be system-dependent
-                            output.push_str("\n");
-                            output.push_str(&out);
+                if !context.selection.is_default() {
+                    output.push_str(&format!(
+                        "Pipeline selection: {}\n\n", context.selection.describe()));
+                }
+
+                output.push_str(&format!("Steps:\n{}\n", execution::summary(&steps)));
+
+                if let Some(dir) = context.log.dir() {
+                    output.push_str(&format!("Full log: {}\n\n", dir.to_string_lossy()));
+                }
+
+                // the steps of the earlier pipelines passed, only the
+                // failed pipeline is shown in detail
+                let failed_pipeline = steps.last().map(|step| step.pipeline.clone());
+                let tests = context.test_results.borrow();
+                for step in steps.into_iter().filter(|step| Some(&step.pipeline) == failed_pipeline.as_ref()) {
+                    output.push_str(&step.command);
+                    // TODO: newline should be system-dependent
+                    output.push('\n');
+
+                    // the failed tests say more than the tail of the output
+                    let failed_tests = tests.get(&step.pipeline)
+                        .filter(|tests| !step.is_success() && !tests.failures.is_empty());
+                    match failed_tests {
+                        Some(tests) => {
+                            output.push_str(&format!("Tests: {}\n", tests.describe()));
+                            output.push_str(&format!("Failed tests:\n{}", tests.failure_summary()));
+                        },
+                        None => {
+                            if step.truncated {
+                                output.push_str("[output truncated, only the last lines are shown]\n");
+                            }
+                            output.push_str(&step.output);
                         },
                     }
+
+                    if let Some(failure) = step.failure {
+                        output.push_str(&format!("Failed: {}\n", failure));
+                    }
                 }
 
                 let mailer = mail::build_mailer(&cinderella_config.email);
@@ -105,13 +435,249 @@ pub fn run(exec_config: &ExecutionConfig) {
                     &exec_config.name(),
                     &format!("Build failed:\n\n{}", output));
 
-                write_build_status(BuildStatus::Error("Build failed".to_string()), exec_config, &cinderella_config);
+                write_build_status(BuildStatus::Error("Build failed".to_string()), exec_config, cinderella_config);
             },
             _ => (),
         }
     } else {
         println!("No Cinderella configuration found");
     }
+
+    report
+}
+
+/// Shows what a build would do without executing anything
+///
+/// The repository is cloned and checked out as for a build, but no lock is
+/// taken and nothing is written to the logs or the history. Fails if the
+/// build could not be started or would fail before executing a command.
+pub fn dry_run(exec_config: &ExecutionConfig) -> Result<(), String> {
+    let cinderella_config = CinderellaConfig::from_file(appconfig_file());
+    let configs = Configs {
+        cinderella_config: &cinderella_config,
+        execution_config: exec_config,
+    };
+
+    let workdir = checkout(exec_config)?;
+    if let Some(commit) = workdir.head_commit() {
+        println!("Commit {}", commit);
+    }
+
+    let cinderella_file = exec_config.cinderella_file(&workdir.path);
+    let pipelines = pipeline::load_pipeline(&cinderella_file)?
+        .ok_or_else(|| String::from("No Cinderella configuration found"))?;
+    let selection = exec_config.pipeline_selection();
+    let unknown = selection.unknown_pipelines(&pipelines);
+    if !unknown.is_empty() {
+        return Err(format!("Unknown pipelines: {}", unknown.join(", ")));
+    }
+    println!("Pipeline selection: {}", selection.describe());
+
+    let secrets = variables::load_secrets_from_file(&workdir.path, &configs);
+    let mut variables = variables::load(&configs, &secrets);
+    let mut context = ExecutionContext::new(&cinderella_config);
+    context.selection = selection;
+    context.masked = masking::register_secrets(&secrets);
+    context.secrets = secrets;
+    let home = environment::home_dir(&workdir.path)?;
+    context.environment = Environment::from_config(
+        &cinderella_config, exec_config, &workdir.path, &home)?;
+
+    // the number the build would get if it was started now
+    let number = history::list(&history_dir(&cinderella_config), &exec_config.repo_url)?
+        .first()
+        .map_or(1, |record| record.number + 1)
+        .to_string();
+    variables.insert(String::from("build_number"), number.clone());
+    context.environment.set("CINDERELLA_BUILD_NUMBER", &number);
+
+    let plan = execution::plan(&pipelines, &variables, &context);
+    print!("\n{}", plan.text);
+
+    if plan.errors > 0 {
+        Err(format!("The build would fail, {} errors found", plan.errors))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn scheduler() {
+    let cinderella_config = CinderellaConfig::from_file(appconfig_file());
+
+    let state_file = match &cinderella_config.scheduler {
+        Some(scheduler) if scheduler.state_file.is_some() =>
+            PathBuf::from(scheduler.state_file.as_ref().unwrap()),
+        _ => application_file("scheduler-state.toml"),
+    };
+
+    scheduler::run(&cinderella_config, &state_file);
+}
+
+/// Adds a build to the queue of the daemon
+///
+/// Without an explicit priority the priority of the project is used.
+pub fn enqueue(exec_config: &ExecutionConfig, priority: Option<i32>) -> Result<(), String> {
+    let cinderella_config = CinderellaConfig::from_file(appconfig_file());
+    let priority = priority
+        .or_else(|| cinderella_config.project(&exec_config.repo_url)
+             .and_then(|project| project.priority))
+        .unwrap_or(0);
+
+    let spool = daemon::Spool::new(&spool_dir(&cinderella_config));
+    let id = spool.enqueue(&daemon::Job::new(exec_config, priority))?;
+    println!("Enqueued job {}", id);
+
+    Ok(())
+}
+
+/// Runs the builds enqueued with `enqueue`
+pub fn daemon() {
+    let cinderella_config = CinderellaConfig::from_file(appconfig_file());
+    let workers = cinderella_config.daemon.as_ref()
+        .map(|daemon| daemon.workers)
+        .unwrap_or(1);
+
+    daemon::run(&daemon::Spool::new(&spool_dir(&cinderella_config)), workers);
+}
+
+/// Prints the enqueued, running and waiting builds
+pub fn queue() {
+    let cinderella_config = CinderellaConfig::from_file(appconfig_file());
+    let spool = daemon::Spool::new(&spool_dir(&cinderella_config));
+
+    let mut builds = Vec::new();
+    let jobs = spool.queued().and_then(|queued| {
+        Ok((queued, spool.running()?))
+    });
+    match jobs {
+        Ok((queued, running)) => {
+            for (id, job) in running {
+                builds.push(format!("started: job {}: {}", id, job.describe()));
+            }
+            for (id, job) in queued {
+                builds.push(format!("enqueued: job {}: {}", id, job.describe()));
+            }
+        },
+        Err(msg) => eprintln!("{}", msg),
+    }
+
+    match locking::list(&lock_dir(&cinderella_config)) {
+        Ok(locked) => builds.extend(locked),
+        Err(msg) => eprintln!("{}", msg),
+    }
+
+    if builds.is_empty() {
+        println!("No running or waiting builds");
+    }
+    for build in builds {
+        println!("{}", build);
+    }
+}
+
+/// Lists, prints, follows or searches the build logs
+///
+/// `args` are the arguments after `logs`, e.g. `show website.git master`.
+pub fn logs(args: &[String]) -> Result<(), String> {
+    let cinderella_config = CinderellaConfig::from_file(appconfig_file());
+    let root = log_root(&cinderella_config);
+    let arg = |index: usize| args.get(index).map(|arg| arg.as_str());
+    let required = |index: usize| arg(index)
+        .ok_or_else(|| String::from("Missing arguments, see cinderella logs --help"));
+
+    match required(0)? {
+        "list" => {
+            for build in logs::list(&root, arg(1), arg(2))? {
+                println!("{} {} {} {}", build.project, build.git_ref, build.build,
+                         build.status.as_deref().unwrap_or("running"));
+            }
+        },
+        "show" => {
+            let build = logs::find(&root, required(1)?, required(2)?, arg(3))?;
+            logs::print(&build, &mut std::io::stdout())?;
+        },
+        "follow" => {
+            let build = logs::find(&root, required(1)?, required(2)?, arg(3))?;
+            logs::follow(&build, &mut std::io::stdout())?;
+        },
+        "grep" => {
+            let builds = logs::list(&root, arg(2), arg(3))?;
+            for line in logs::grep(&builds, required(1)?)? {
+                println!("{}", line);
+            }
+        },
+        action => return Err(format!("Unknown logs command: {}", action)),
+    }
+
+    Ok(())
+}
+
+/// Prints the latest build of each project and branch or tag
+pub fn status(project: Option<&str>, git_ref: Option<&str>, format: OutputFormat)
+    -> Result<(), String>
+{
+    let cinderella_config = CinderellaConfig::from_file(appconfig_file());
+    let records = history::latest(&history_dir(&cinderella_config), project, git_ref)?;
+
+    match format {
+        OutputFormat::Json => print_json(&records)?,
+        OutputFormat::Text if records.is_empty() => println!("No builds found"),
+        OutputFormat::Text => {
+            for record in records {
+                println!("{}: {}", record.project, record.describe());
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// Prints the last `count` builds of a project or the details of one build
+pub fn history(project: &str, number: Option<u64>, count: usize, format: OutputFormat)
+    -> Result<(), String>
+{
+    let cinderella_config = CinderellaConfig::from_file(appconfig_file());
+    let dir = history_dir(&cinderella_config);
+
+    if let Some(number) = number {
+        let record = history::find(&dir, project, number)?;
+        match format {
+            OutputFormat::Json => print_json(&record)?,
+            OutputFormat::Text => print!("{}", record.details()),
+        }
+        return Ok(());
+    }
+
+    let records: Vec<_> = history::list(&dir, project)?.into_iter().take(count).collect();
+    match format {
+        OutputFormat::Json => print_json(&records)?,
+        OutputFormat::Text if records.is_empty() => println!("No builds of {} found", project),
+        OutputFormat::Text => {
+            for record in records {
+                println!("{}", record.describe());
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// Runs a build from the history again with the same commit, pipeline
+/// selection and CI configuration file
+pub fn rerun(project: &str, number: u64) -> Result<Report, String> {
+    let cinderella_config = CinderellaConfig::from_file(appconfig_file());
+    let record = history::find(&history_dir(&cinderella_config), project, number)?;
+    let exec_config = record.rerun_config()?;
+
+    println!("Rerunning build #{} of {}", number, project);
+    Ok(run(&exec_config))
+}
+
+fn print_json<T: serde::Serialize>(value: &T) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(value)
+        .map_err(|e| format!("Could not serialize to JSON: {}", e))?;
+    println!("{}", json);
+
+    Ok(())
 }
 
 pub fn encrypt(plainpath: &Path, cipherpath: &Path, password: &str) {
@@ -131,6 +697,50 @@ pub fn decrypt(cipherpath: &Path, plainpath: &Path, password: &str) {
     }
 }
 
+fn write_step_history(steps: &[StepResult], exec_config: &ExecutionConfig, cinderella_config: &CinderellaConfig) {
+    let branch = match &exec_config.branch {
+        Some(branch) => branch,
+        None => "master",
+    };
+
+    if let Some(dashboard) = &cinderella_config.dashboard {
+        let project = &exec_config.name();
+        let outdir = PathBuf::from(&dashboard.folder);
+        if let Err(msg) = dashboard::append_step_history(project, branch, steps, &outdir) {
+            eprintln!("Could not write step history: {}", msg);
+        }
+    }
+}
+
+/// Writes the JUnit XML report of a build into its log directory and, if a
+/// dashboard is configured, next to the badge of the branch
+fn write_junit_report(
+    steps: &[StepResult],
+    context: &ExecutionContext,
+    exec_config: &ExecutionConfig,
+    cinderella_config: &CinderellaConfig)
+{
+    let project = exec_config.name();
+    let xml = junit::render(&project, steps, &context.test_results.borrow());
+
+    let mut paths = Vec::new();
+    if let Some(log_dir) = context.log.dir() {
+        paths.push(log_dir.join("junit.xml"));
+    }
+    if let Some(dashboard) = &cinderella_config.dashboard {
+        let branch = exec_config.branch.as_deref().unwrap_or("master");
+        let dir = PathBuf::from(&dashboard.folder).join(&project);
+        let _ = fs::create_dir_all(&dir);
+        paths.push(dir.join(format!("{}-junit.xml", branch)));
+    }
+
+    for path in paths {
+        if let Err(e) = fs::write(&path, &xml) {
+            eprintln!("Could not write JUnit report to {:?}: {}", path, e);
+        }
+    }
+}
+
 fn write_build_status(status: BuildStatus, exec_config: &ExecutionConfig, cinderella_config: &CinderellaConfig) {
     let branch = match &exec_config.branch {
         Some(branch) => branch,
@@ -0,0 +1,327 @@
+use std::fmt;
+use std::os::unix::process::CommandExt;
+
+use duct::Expression;
+use serde::{Deserialize, Deserializer};
+
+/// Resource limits for the commands of a pipeline
+///
+/// All limits apply to each process separately and are inherited by its
+/// children.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// Maximum size of the virtual memory in bytes
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub memory: Option<u64>,
+    /// Maximum CPU time in seconds
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub cpu_time: Option<u64>,
+    /// Maximum number of processes of the build user
+    #[serde(default)]
+    pub processes: Option<u64>,
+    /// Maximum size of a written file in bytes
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub file_size: Option<u64>,
+}
+
+impl Limits {
+    /// Uses the given defaults for all limits that are not set
+    pub fn or(self, defaults: &Limits) -> Limits {
+        Limits {
+            memory: self.memory.or(defaults.memory),
+            cpu_time: self.cpu_time.or(defaults.cpu_time),
+            processes: self.processes.or(defaults.processes),
+            file_size: self.file_size.or(defaults.file_size),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    /// Makes the expression set the limits right before executing the
+    /// command
+    pub fn apply(&self, expression: Expression) -> Expression {
+        if self.is_empty() {
+            return expression;
+        }
+
+        let limits = *self;
+        expression.before_spawn(move |cmd| {
+            unsafe {
+                cmd.pre_exec(move || limits.set());
+            }
+            Ok(())
+        })
+    }
+
+    fn set(&self) -> std::io::Result<()> {
+        let resources = [
+            (libc::RLIMIT_AS, self.memory),
+            (libc::RLIMIT_CPU, self.cpu_time),
+            (libc::RLIMIT_NPROC, self.processes),
+            (libc::RLIMIT_FSIZE, self.file_size),
+        ];
+
+        for (resource, limit) in resources.iter() {
+            if let Some(limit) = limit {
+                // the hard CPU limit kills processes that ignore SIGXCPU
+                let hard = match *resource {
+                    libc::RLIMIT_CPU => limit + 1,
+                    _ => *limit,
+                };
+                let rlimit = libc::rlimit {
+                    rlim_cur: *limit as libc::rlim_t,
+                    rlim_max: hard as libc::rlim_t,
+                };
+
+                if unsafe { libc::setrlimit(*resource, &rlimit) } == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Determines which limit a command exceeded from the signal that
+    /// terminated it
+    ///
+    /// The kernel signals exceeded CPU time and file size limits. The
+    /// memory limit only makes allocations fail, and a program crashing
+    /// because of that cannot be told apart from any other crash, so only
+    /// a kill by the OOM killer is attributed to memory.
+    pub fn exceeded(&self, signal: i32, oom_killed: bool) -> Option<String> {
+        match signal {
+            libc::SIGXCPU => self.cpu_time.map(|seconds| {
+                format!("CPU time limit of {}s exceeded", seconds)
+            }),
+            libc::SIGXFSZ => self.file_size.map(|bytes| {
+                format!("file size limit of {} exceeded", format_size(bytes))
+            }),
+            libc::SIGKILL if oom_killed => self.memory.map(|bytes| format!(
+                "memory limit of {} exceeded (killed by the OOM killer)", format_size(bytes))),
+            _ => None,
+        }
+    }
+}
+
+/// The signal that killed the last command of a shell, which shells
+/// report as exit code 128+n
+pub fn signal_of_exit_code(code: i32) -> Option<i32> {
+    if code > 128 && code < 128 + 64 {
+        Some(code - 128)
+    } else {
+        None
+    }
+}
+
+/// Number of processes the kernel OOM killer has killed since boot, `None`
+/// if the kernel does not report it
+pub fn oom_kills() -> Option<u64> {
+    let vmstat = std::fs::read_to_string("/proc/vmstat").ok()?;
+
+    vmstat.lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+impl fmt::Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+
+        if let Some(memory) = self.memory {
+            parts.push(format!("memory {}", format_size(memory)));
+        }
+        if let Some(cpu_time) = self.cpu_time {
+            parts.push(format!("CPU time {}s", cpu_time));
+        }
+        if let Some(processes) = self.processes {
+            parts.push(format!("{} processes", processes));
+        }
+        if let Some(file_size) = self.file_size {
+            parts.push(format!("file size {}", format_size(file_size)));
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Parses a size like `512M` or `2G` (binary units) into bytes
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, factor) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 1 << 30),
+        Some('T') | Some('t') => (&size[..size.len() - 1], 1 << 40),
+        _ => (size, 1),
+    };
+
+    number.trim().parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(factor))
+        .ok_or_else(|| format!("Invalid size: {}", size))
+}
+
+/// Parses a duration like `90s`, `30m` or `2h` into seconds
+pub fn parse_duration(duration: &str) -> Result<u64, String> {
+    let duration = duration.trim();
+    let (number, factor) = match duration.chars().last() {
+        Some('s') => (&duration[..duration.len() - 1], 1),
+        Some('m') => (&duration[..duration.len() - 1], 60),
+        Some('h') => (&duration[..duration.len() - 1], 60 * 60),
+        _ => (duration, 1),
+    };
+
+    number.trim().parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(factor))
+        .ok_or_else(|| format!("Invalid duration: {}", duration))
+}
+
+fn format_size(bytes: u64) -> String {
+    let units = [(1 << 40, "T"), (1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")];
+
+    units.iter()
+        .find(|(factor, _)| bytes >= *factor && bytes & (factor - 1) == 0)
+        .map(|(factor, unit)| format!("{}{}", bytes / factor, unit))
+        .unwrap_or_else(|| format!("{} bytes", bytes))
+}
+
+/// A limit can be given as a plain number or as a string with a unit
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LimitValue {
+    Number(u64),
+    Text(String),
+}
+
+fn deserialize_with_unit<'de, D>(
+    deserializer: D,
+    parse: fn(&str) -> Result<u64, String>) -> Result<Option<u64>, D::Error>
+    where D: Deserializer<'de>
+{
+    match LimitValue::deserialize(deserializer)? {
+        LimitValue::Number(number) => Ok(Some(number)),
+        LimitValue::Text(text) => parse(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+    where D: Deserializer<'de>
+{
+    deserialize_with_unit(deserializer, parse_size)
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+    where D: Deserializer<'de>
+{
+    deserialize_with_unit(deserializer, parse_duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use duct::cmd;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn test_parse_units() {
+        assert_eq!(parse_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size("100"), Ok(100));
+        assert!(parse_size("2 GB").is_err());
+        assert!(parse_size("-1").is_err());
+
+        assert_eq!(parse_duration("30m"), Ok(1800));
+        assert_eq!(parse_duration("2h"), Ok(7200));
+        assert_eq!(parse_duration("45"), Ok(45));
+        assert!(parse_duration("soon").is_err());
+    }
+
+    #[test]
+    fn test_deserialize_limits() {
+        let value: toml::Value = toml::from_str(
+            "memory = \"2G\"\ncpu_time = \"30m\"\nprocesses = 256\nfile_size = 1024"
+        ).unwrap();
+        let limits: Limits = value.try_into().unwrap();
+
+        assert_eq!(limits, Limits {
+            memory: Some(2 << 30),
+            cpu_time: Some(1800),
+            processes: Some(256),
+            file_size: Some(1024),
+        });
+        assert_eq!(limits.to_string(),
+                   "memory 2G, CPU time 1800s, 256 processes, file size 1K");
+
+        let typo: toml::Value = toml::from_str("memroy = \"2G\"").unwrap();
+        assert!(typo.try_into::<Limits>().is_err());
+    }
+
+    #[test]
+    fn test_pipeline_limits_override_defaults() {
+        let defaults = Limits {
+            memory: Some(1 << 30),
+            cpu_time: Some(600),
+            processes: None,
+            file_size: None,
+        };
+        let pipeline = Limits {
+            memory: Some(4 << 30),
+            ..Limits::default()
+        };
+
+        let limits = pipeline.or(&defaults);
+
+        assert_eq!(limits.memory, Some(4 << 30));
+        assert_eq!(limits.cpu_time, Some(600));
+        assert_eq!(limits.processes, None);
+    }
+
+    #[test]
+    fn test_file_size_limit_kills_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = Limits {
+            file_size: Some(1024),
+            ..Limits::default()
+        };
+
+        let expression = cmd("dd", &["if=/dev/zero", "of=big", "bs=4096", "count=1"])
+            .dir(dir.path())
+            .stderr_null()
+            .unchecked();
+        let status = limits.apply(expression).run().unwrap().status;
+
+        let signal = status.signal().unwrap();
+        assert_eq!(signal, libc::SIGXFSZ);
+        assert_eq!(limits.exceeded(signal, false),
+                   Some(String::from("file size limit of 1K exceeded")));
+        assert_eq!(std::fs::metadata(dir.path().join("big")).unwrap().len(), 1024);
+    }
+
+    #[test]
+    fn test_crashes_are_not_attributed_to_memory() {
+        let limits = Limits {
+            memory: Some(1 << 30),
+            ..Limits::default()
+        };
+
+        assert_eq!(limits.exceeded(libc::SIGSEGV, false), None);
+        assert_eq!(limits.exceeded(libc::SIGABRT, false), None);
+        assert_eq!(limits.exceeded(libc::SIGKILL, false), None);
+        assert_eq!(limits.exceeded(libc::SIGKILL, true),
+                   Some(String::from("memory limit of 1G exceeded (killed by the OOM killer)")));
+    }
+
+    #[test]
+    fn test_signal_of_exit_code() {
+        assert_eq!(signal_of_exit_code(128 + libc::SIGXCPU), Some(libc::SIGXCPU));
+        assert_eq!(signal_of_exit_code(1), None);
+        assert_eq!(signal_of_exit_code(128), None);
+        assert_eq!(signal_of_exit_code(255), None);
+    }
+}
@@ -0,0 +1,499 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::config::{self, ExecutionConfig};
+
+/// What happens to a build while another build of the same project and ref
+/// is running
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Wait until all earlier builds have finished
+    #[default]
+    Queue,
+    /// Cancel the running build and all waiting builds
+    Cancel,
+    /// Wait, but give up as soon as a newer build is queued
+    Skip,
+}
+
+/// Set when the build was cancelled by SIGTERM or SIGINT
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_cancel(_signal: libc::c_int) {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Whether the running build was cancelled
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Makes SIGTERM and SIGINT cancel the build instead of killing Cinderella,
+/// so that the running command can be stopped and the work dir cleaned up
+///
+/// The default handlers are restored when the value is dropped.
+struct CancelHandler;
+
+impl CancelHandler {
+    fn install() -> CancelHandler {
+        CANCELLED.store(false, Ordering::SeqCst);
+
+        let handler = request_cancel as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        unsafe {
+            libc::signal(libc::SIGTERM, handler);
+            libc::signal(libc::SIGINT, handler);
+        }
+
+        CancelHandler
+    }
+}
+
+impl Drop for CancelHandler {
+    fn drop(&mut self) {
+        unsafe {
+            libc::signal(libc::SIGTERM, libc::SIG_DFL);
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+}
+
+/// Kills the process group of a command as soon as the build is cancelled
+///
+/// Returns once the command is killed or `finished` is set.
+pub fn kill_when_cancelled(pid: u32, finished: &AtomicBool) {
+    while !finished.load(Ordering::SeqCst) {
+        if is_cancelled() {
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+            return;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Exclusive right to build a project and ref, released when dropped
+pub struct BuildLock {
+    file: File,
+    _cancel_handler: CancelHandler,
+}
+
+impl Drop for BuildLock {
+    fn drop(&mut self) {
+        // the lock itself is released when the file is closed, the
+        // description is removed so that nobody signals a reused pid
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// A waiting build, its file is removed when dropped
+///
+/// The build holds a lock on the ticket while it waits, so that a ticket
+/// left behind by a crashed build is never mistaken for a live one, even if
+/// its pid was reused.
+struct Ticket {
+    path: PathBuf,
+    _file: File,
+}
+
+impl Ticket {
+    /// Creates a locked ticket in the queue
+    ///
+    /// The ticket is locked before it is moved into the queue, so that
+    /// other builds never see it unlocked.
+    fn create(dir: &Path, queue_dir: &Path, description: &str) -> io::Result<Ticket> {
+        let name = ticket_name();
+        let new_path = dir.join(format!("{}.new", name));
+
+        let mut file = OpenOptions::new().create_new(true).write(true).open(&new_path)?;
+        let written = try_lock(&file, libc::LOCK_EX)
+            .and_then(|locked| if locked {
+                Ok(())
+            } else {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "ticket is locked"))
+            })
+            .and_then(|_| file.write_all(description.as_bytes()))
+            .and_then(|_| fs::rename(&new_path, queue_dir.join(&name)));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&new_path);
+            return Err(e);
+        }
+
+        Ok(Ticket {
+            path: queue_dir.join(name),
+            _file: file,
+        })
+    }
+
+    fn name(&self) -> String {
+        self.path.file_name().unwrap().to_string_lossy().to_string()
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Waits until the build may run according to the policy
+///
+/// Returns `None` if the build was skipped or cancelled while waiting. The
+/// build lock is held by the running build in `<lock_dir>/<key>/lock`, the
+/// waiting builds have a ticket in `<lock_dir>/<key>/queue`. Both files
+/// contain the pid and a description of the build.
+pub fn acquire(lock_dir: &Path, exec_config: &ExecutionConfig, policy: Policy)
+    -> Result<Option<BuildLock>, String>
+{
+    let cancel_handler = CancelHandler::install();
+
+    let dir = lock_dir.join(key(exec_config));
+    let queue_dir = dir.join("queue");
+    fs::create_dir_all(&queue_dir)
+        .map_err(|e| format!("Could not create lock dir {:?}: {}", queue_dir, e))?;
+
+    let since = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    let description = format!("{pid}\n{} (pid {pid}, since {})\n",
+                              describe(exec_config), since, pid = std::process::id());
+    let ticket = Ticket::create(&dir, &queue_dir, &description)
+        .map_err(|e| format!("Could not queue build: {}", e))?;
+
+    let lock_path = dir.join("lock");
+    let mut file = OpenOptions::new().create(true).truncate(false).read(true).write(true)
+        .open(&lock_path)
+        .map_err(|e| format!("Could not open lock file {:?}: {}", lock_path, e))?;
+
+    if policy == Policy::Cancel {
+        cancel_earlier_builds(&mut file, &queue_dir, &ticket.name());
+    }
+
+    let mut waiting = false;
+    loop {
+        if is_cancelled() {
+            println!("Build was cancelled while waiting");
+            return Ok(None);
+        }
+
+        let tickets = live_tickets(&queue_dir)
+            .map_err(|e| format!("Could not read build queue: {}", e))?;
+        let position = tickets.iter().position(|name| *name == ticket.name());
+
+        if policy == Policy::Skip && position.map(|p| p + 1 < tickets.len()) == Some(true) {
+            println!("Skipping build, a newer build of {} is queued", describe(exec_config));
+            return Ok(None);
+        }
+
+        if position == Some(0) && try_lock(&file, libc::LOCK_EX)
+            .map_err(|e| format!("Could not lock {:?}: {}", lock_path, e))?
+        {
+            file.set_len(0)
+                .and_then(|_| file.seek(SeekFrom::Start(0)))
+                .and_then(|_| file.write_all(description.as_bytes()))
+                .map_err(|e| format!("Could not write lock file: {}", e))?;
+
+            return Ok(Some(BuildLock {
+                file,
+                _cancel_handler: cancel_handler,
+            }));
+        }
+
+        if !waiting {
+            println!("Waiting for earlier builds of {} to finish", describe(exec_config));
+            waiting = true;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Lists the running and waiting builds of all projects
+pub fn list(lock_dir: &Path) -> Result<Vec<String>, String> {
+    let mut builds = Vec::new();
+
+    let dirs = match fs::read_dir(lock_dir) {
+        Ok(dirs) => dirs,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(builds),
+        Err(e) => return Err(format!("Could not read lock dir {:?}: {}", lock_dir, e)),
+    };
+
+    let mut dirs = dirs.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect::<Vec<PathBuf>>();
+    dirs.sort();
+
+    for dir in dirs {
+        if let Some(description) = running_build(&dir.join("lock")) {
+            builds.push(format!("running: {}", description));
+        }
+
+        let queue_dir = dir.join("queue");
+        let tickets = live_tickets(&queue_dir)
+            .map_err(|e| format!("Could not read build queue {:?}: {}", queue_dir, e))?;
+        for name in tickets {
+            if let Ok(content) = fs::read_to_string(queue_dir.join(name)) {
+                builds.push(format!("waiting: {}", parse_description(&content).1));
+            }
+        }
+    }
+
+    Ok(builds)
+}
+
+/// The description of the build that holds the lock, if any
+fn running_build(lock_path: &Path) -> Option<String> {
+    let mut file = File::open(lock_path).ok()?;
+
+    // a shared lock cannot be acquired while a build holds the lock
+    if try_lock(&file, libc::LOCK_SH).ok()? {
+        return None;
+    }
+
+    let mut content = String::new();
+    file.read_to_string(&mut content).ok()?;
+
+    Some(parse_description(&content).1)
+}
+
+/// Sends SIGTERM to the running build and all builds waiting before this
+/// one, so that they stop and clean up
+fn cancel_earlier_builds(lock_file: &mut File, queue_dir: &Path, own_ticket: &str) {
+    let mut pids = Vec::new();
+
+    if !try_lock(lock_file, libc::LOCK_SH).unwrap_or(true) {
+        let mut content = String::new();
+        if lock_file.read_to_string(&mut content).is_ok() {
+            pids.extend(parse_description(&content).0);
+        }
+    }
+
+    for name in live_tickets(queue_dir).unwrap_or_default() {
+        if name.as_str() >= own_ticket {
+            continue;
+        }
+        if let Ok(content) = fs::read_to_string(queue_dir.join(&name)) {
+            pids.extend(parse_description(&content).0);
+        }
+    }
+
+    for pid in pids {
+        println!("Cancelling build with pid {}", pid);
+        unsafe {
+            libc::kill(pid, libc::SIGTERM);
+        }
+    }
+}
+
+/// Tries to lock the file without blocking, a shared lock is released
+/// right away
+fn try_lock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+        if operation == libc::LOCK_SH {
+            unsafe {
+                libc::flock(file.as_raw_fd(), libc::LOCK_UN);
+            }
+        }
+        return Ok(true);
+    }
+
+    let error = io::Error::last_os_error();
+    if error.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(error)
+    }
+}
+
+/// The tickets of the waiting builds, oldest first
+///
+/// Tickets that are not locked by their build anymore are removed.
+fn live_tickets(queue_dir: &Path) -> io::Result<Vec<String>> {
+    let mut tickets = Vec::new();
+
+    for entry in fs::read_dir(queue_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        // the ticket might have been removed in the meantime
+        let file = match File::open(entry.path()) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        match try_lock(&file, libc::LOCK_SH) {
+            Ok(false) => tickets.push(name),
+            _ => {
+                let _ = fs::remove_file(entry.path());
+            },
+        }
+    }
+
+    tickets.sort();
+
+    Ok(tickets)
+}
+
+/// Ticket names sort in the order the builds were queued
+fn ticket_name() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+
+    format!("{:020}-{}", nanos, std::process::id())
+}
+
+/// Splits the content of a lock file or ticket into pid and description
+fn parse_description(content: &str) -> (Option<libc::pid_t>, String) {
+    let mut lines = content.lines();
+    let pid = lines.next().and_then(|line| line.parse().ok());
+
+    (pid, lines.next().unwrap_or_default().to_string())
+}
+
+fn describe(exec_config: &ExecutionConfig) -> String {
+    format!("{} {}", exec_config.name(), git_ref(exec_config))
+}
+
+fn git_ref(exec_config: &ExecutionConfig) -> String {
+    match (&exec_config.branch, &exec_config.tag) {
+        (Some(branch), _) => branch.clone(),
+        (None, Some(tag)) => format!("tag {}", tag),
+        (None, None) => String::from("default branch"),
+    }
+}
+
+/// Name of the lock directory for a project and ref
+///
+/// The hash of the repository URL distinguishes projects with the same
+/// name.
+fn key(exec_config: &ExecutionConfig) -> String {
+    format!("{}-{}-{:08x}", sanitize(&exec_config.name()),
+            sanitize(&git_ref(exec_config)), url_hash(&exec_config.repo_url))
+}
+
+/// Name of the directory of a project, e.g. in the build history
+///
+/// Like the lock directories it contains the hash of the repository URL,
+/// so that projects with the same name do not share a directory.
+pub fn project_key(repo_url: &str) -> String {
+    format!("{}-{:08x}", sanitize(&config::project_name(repo_url)), url_hash(repo_url))
+}
+
+/// Whether a directory named with `project_key` belongs to the project,
+/// which is given by its name or its repository URL
+pub fn is_project_key(key: &str, project: &str) -> bool {
+    key == project_key(project)
+        || matches!(key.rsplit_once('-'), Some((name, _)) if name == sanitize(project))
+}
+
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+/// FNV-1a, which is stable across Rust versions
+fn url_hash(repo_url: &str) -> u32 {
+    repo_url.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        let key = key(&ExecutionConfig::for_branch("/srv/git/website.git", "feature/login"));
+
+        assert!(key.starts_with("website.git-feature_login-"));
+        assert_ne!(key, super::key(&ExecutionConfig {
+            repo_url: String::from("/srv/other/website.git"),
+            ..ExecutionConfig::for_branch("/srv/git/website.git", "feature/login")
+        }));
+    }
+
+    #[test]
+    fn test_project_key() {
+        let key = project_key("/srv/git/website.git");
+
+        assert!(key.starts_with("website.git-"));
+        assert_ne!(key, project_key("/srv/other/website.git"));
+        assert!(is_project_key(&key, "website.git"));
+        assert!(is_project_key(&key, "/srv/git/website.git"));
+        assert!(!is_project_key(&key, "/srv/other/website.git"));
+        assert!(!is_project_key(&key, "blog.git"));
+    }
+
+    #[test]
+    fn test_lock_is_exclusive_and_listed() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let lock = acquire(dir.path(), &exec_config, Policy::Queue).unwrap().unwrap();
+
+        let builds = list(dir.path()).unwrap();
+        assert_eq!(builds.len(), 1);
+        assert!(builds[0].starts_with("running: website.git master (pid "));
+
+        let lock_path = dir.path().join(key(&exec_config)).join("lock");
+        let other = File::open(&lock_path).unwrap();
+        assert!(!try_lock(&other, libc::LOCK_EX).unwrap());
+
+        drop(lock);
+        assert!(list(dir.path()).unwrap().is_empty());
+        assert!(try_lock(&other, libc::LOCK_EX).unwrap());
+    }
+
+    #[test]
+    fn test_skip_if_newer_build_is_queued() {
+        let dir = tempfile::tempdir().unwrap();
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let queue_dir = dir.path().join(key(&exec_config)).join("queue");
+        fs::create_dir_all(&queue_dir).unwrap();
+
+        // a build queued far in the future that is still waiting
+        let newer = format!("{:020}-{}", u128::MAX / 2, std::process::id());
+        fs::write(queue_dir.join(&newer), "1\nnewer build\n").unwrap();
+        let newer_file = File::open(queue_dir.join(&newer)).unwrap();
+        assert!(try_lock(&newer_file, libc::LOCK_EX).unwrap());
+
+        let lock = acquire(dir.path(), &exec_config, Policy::Skip).unwrap();
+
+        assert!(lock.is_none());
+        assert_eq!(live_tickets(&queue_dir).unwrap(), vec![newer]);
+    }
+
+    #[test]
+    fn test_stale_tickets_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        // a crashed build whose pid now belongs to a live process
+        let reused = format!("{:020}-{}", 1, std::process::id());
+        fs::write(dir.path().join(reused), format!("{}\ncrashed build\n", std::process::id()))
+            .unwrap();
+
+        assert!(live_tickets(dir.path()).unwrap().is_empty());
+        assert!(fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_waiting_build_holds_ticket_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue_dir = dir.path().join("queue");
+        fs::create_dir(&queue_dir).unwrap();
+
+        let ticket = Ticket::create(dir.path(), &queue_dir, "1\nwaiting build\n").unwrap();
+        assert_eq!(live_tickets(&queue_dir).unwrap(), vec![ticket.name()]);
+        assert_eq!(fs::read_to_string(&ticket.path).unwrap(), "1\nwaiting build\n");
+
+        drop(ticket);
+        assert!(live_tickets(&queue_dir).unwrap().is_empty());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
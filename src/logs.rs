@@ -0,0 +1,572 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+
+use crate::config::ExecutionConfig;
+use crate::execution::StepResult;
+use crate::locking;
+
+/// Name of the file with the outcome of a finished build
+const STATUS_FILE: &str = "status";
+
+/// Number of lines at the end of the output of a step that are kept in
+/// memory for notifications
+pub const DEFAULT_TAIL_LINES: usize = 1000;
+
+/// Characters that are kept as they are in the directory name of a ref,
+/// all others are percent-encoded
+const REF_UNENCODED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_');
+
+/// Maximum size of the tail in bytes, so that long lines cannot make the
+/// tail grow to `tail_lines` times the maximum line length
+const MAX_TAIL_BYTES: usize = 1024 * 1024;
+
+/// The log directory of a build, `<root>/<project>-<hash>/<ref>/<build>`
+/// (see `locking::project_key` and `encode_ref`), with one log file per
+/// step
+pub struct BuildLog {
+    dir: Option<PathBuf>,
+    steps: Cell<usize>,
+    tail_lines: usize,
+}
+
+impl BuildLog {
+    /// Creates the log directory for a new build
+    pub fn create(root: &Path, exec_config: &ExecutionConfig, tail_lines: usize)
+        -> Result<BuildLog, String>
+    {
+        let build = format!("{}-{}",
+                            chrono::Local::now().format("%Y%m%dT%H%M%S"),
+                            std::process::id());
+        let dir = root.join(locking::project_key(&exec_config.repo_url))
+            .join(encode_ref(&git_ref(exec_config)))
+            .join(build);
+
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Could not create log dir {:?}: {}", dir, e))?;
+
+        Ok(BuildLog {
+            dir: Some(dir),
+            steps: Cell::new(0),
+            tail_lines,
+        })
+    }
+
+    /// A log that is not written anywhere
+    pub fn disabled() -> BuildLog {
+        BuildLog {
+            dir: None,
+            steps: Cell::new(0),
+            tail_lines: DEFAULT_TAIL_LINES,
+        }
+    }
+
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// Opens the log file for the next step of a pipeline
+    pub fn step(&self, pipeline: &str) -> StepLog {
+        let number = self.steps.get() + 1;
+        self.steps.set(number);
+
+        let path = self.dir.as_ref()
+            .map(|dir| dir.join(format!("{:03}-{}.log", number, sanitize(pipeline))));
+        let file = path.as_ref().and_then(|path| {
+            File::create(path)
+                .map_err(|e| eprintln!("Could not create step log {:?}: {}", path, e))
+                .ok()
+        });
+
+        StepLog {
+            path: file.as_ref().and(path),
+            file,
+            pipeline: pipeline.to_string(),
+            begun: false,
+            tail: VecDeque::new(),
+            tail_lines: self.tail_lines,
+            tail_bytes: 0,
+            truncated: false,
+        }
+    }
+
+    /// Records a step that failed before its command was executed
+    pub fn record(&self, pipeline: &str, result: StepResult) -> StepResult {
+        let mut log = self.step(pipeline);
+        log.end(&result);
+
+        StepResult {
+            log_file: log.path().map(Path::to_path_buf),
+            ..result
+        }
+    }
+
+    /// Marks the build as finished with the given status
+    pub fn finish(&self, status: &str) {
+        if let Some(dir) = &self.dir {
+            if let Err(e) = fs::write(dir.join(STATUS_FILE), format!("{}\n", status)) {
+                eprintln!("Could not write build status to log: {}", e);
+            }
+        }
+    }
+}
+
+/// The log file of a single step
+///
+/// Each line of the output is prefixed with the time it was read. Only the
+/// last lines of the output are kept in memory.
+pub struct StepLog {
+    file: Option<File>,
+    path: Option<PathBuf>,
+    pipeline: String,
+    begun: bool,
+    tail: VecDeque<String>,
+    tail_lines: usize,
+    tail_bytes: usize,
+    /// Whether lines were dropped from the tail
+    truncated: bool,
+}
+
+impl StepLog {
+    /// A log that is not written anywhere
+    #[cfg(test)]
+    pub fn disabled() -> StepLog {
+        StepLog {
+            file: None,
+            path: None,
+            pipeline: String::new(),
+            begun: false,
+            tail: VecDeque::new(),
+            tail_lines: DEFAULT_TAIL_LINES,
+            tail_bytes: 0,
+            truncated: false,
+        }
+    }
+
+    /// The log file, `None` if the log is not written anywhere
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn begin(&mut self, command: &str) {
+        self.begun = true;
+        let header = format!("=== {} | pipeline {} | {}\n", now(), self.pipeline, command);
+        self.write(&header);
+    }
+
+    /// Writes a line of the output to the log file and keeps it in the tail
+    pub fn line(&mut self, line: &str) {
+        let logged = format!("[{}] {}\n", chrono::Local::now().format("%H:%M:%S"), line);
+        self.write(&logged);
+
+        self.tail.push_back(line.to_string());
+        self.tail_bytes += line.len();
+        while self.tail.len() > self.tail_lines || self.tail_bytes > MAX_TAIL_BYTES {
+            if let Some(dropped) = self.tail.pop_front() {
+                self.tail_bytes -= dropped.len();
+            }
+            self.truncated = true;
+        }
+    }
+
+    /// Takes the tail of the output and whether lines were dropped from it
+    pub fn take_output(&mut self) -> (String, bool) {
+        let mut output = String::new();
+
+        self.tail_bytes = 0;
+        for line in self.tail.drain(..) {
+            // TODO: Newline style should be system dependent
+            output.push_str(&line);
+            output.push('\n');
+        }
+
+        (output, self.truncated)
+    }
+
+    pub fn end(&mut self, result: &StepResult) {
+        if !self.begun {
+            // the step failed before its command could be executed
+            self.begin(&result.command);
+            for line in result.output.lines() {
+                self.line(line);
+            }
+        }
+
+        let status = match &result.failure {
+            None => String::from("ok"),
+            Some(failure) => format!("failed ({})", failure),
+        };
+        let footer = format!("=== {} | {}\n", now(), status);
+        self.write(&footer);
+    }
+
+    /// The complete output of the step, read back from the log file
+    /// without the timestamps and the header and footer lines
+    pub fn read_output(path: &Path) -> io::Result<String> {
+        let contents = fs::read_to_string(path)?;
+        let mut output = String::new();
+
+        for line in contents.lines() {
+            // output lines are prefixed with `[HH:MM:SS] `
+            if line.starts_with('[') && line.get(9..11) == Some("] ") {
+                output.push_str(&line[11..]);
+                output.push('\n');
+            }
+        }
+
+        Ok(output)
+    }
+
+    fn write(&mut self, text: &str) {
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.write_all(text.as_bytes()) {
+                eprintln!("Could not write step log: {}", e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+/// A build found in the log root
+#[derive(Debug, PartialEq)]
+pub struct LoggedBuild {
+    pub project: String,
+    pub git_ref: String,
+    pub build: String,
+    /// `None` while the build is running (or if it was aborted)
+    pub status: Option<String>,
+    dir: PathBuf,
+}
+
+impl LoggedBuild {
+    /// The step log files in the order of execution
+    fn step_logs(&self) -> io::Result<Vec<PathBuf>> {
+        let mut logs = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .collect::<Vec<PathBuf>>();
+        logs.sort();
+
+        Ok(logs)
+    }
+
+    /// Whether the Cinderella process of the build still exists, it is
+    /// part of the build name
+    fn is_running(&self) -> bool {
+        let pid = self.build.rsplit('-').next().and_then(|pid| pid.parse().ok());
+
+        pid.map(|pid| unsafe { libc::kill(pid, 0) } == 0).unwrap_or(false)
+    }
+
+    fn read_status(&self) -> Option<String> {
+        fs::read_to_string(self.dir.join(STATUS_FILE)).ok()
+            .map(|status| status.trim().to_string())
+    }
+}
+
+/// Lists the logged builds, optionally only of one project and ref, oldest
+/// first
+///
+/// The project is given by its name or by its repository URL.
+pub fn list(root: &Path, project: Option<&str>, git_ref: Option<&str>)
+    -> Result<Vec<LoggedBuild>, String>
+{
+    let mut builds = Vec::new();
+    let name = |path: &Path| path.file_name().unwrap().to_string_lossy().to_string();
+
+    let project_dirs = subdirs(root, None)?.into_iter()
+        .filter(|dir| project.iter().all(|project| locking::is_project_key(&name(dir), project)));
+
+    for project_dir in project_dirs {
+        let key = name(&project_dir);
+        let project = key.rsplit_once('-').map_or(key.as_str(), |(project, _)| project);
+
+        for ref_dir in subdirs(&project_dir, git_ref.map(encode_ref).as_deref())? {
+            for build_dir in subdirs(&ref_dir, None)? {
+                let mut build = LoggedBuild {
+                    project: project.to_string(),
+                    git_ref: decode_ref(&name(&ref_dir)),
+                    build: name(&build_dir),
+                    status: None,
+                    dir: build_dir.clone(),
+                };
+                build.status = build.read_status();
+                builds.push(build);
+            }
+        }
+    }
+
+    Ok(builds)
+}
+
+/// Finds a build of a project and ref, the latest if no build is given
+pub fn find(root: &Path, project: &str, git_ref: &str, build: Option<&str>)
+    -> Result<LoggedBuild, String>
+{
+    let builds = list(root, Some(project), Some(git_ref))?;
+
+    let project_dir = |build: &LoggedBuild| build.dir.parent().and_then(Path::parent)
+        .map(Path::to_path_buf);
+    if builds.iter().any(|build| project_dir(build) != project_dir(&builds[0])) {
+        return Err(format!(
+            "Several projects are called {}, use the repository URL instead", project));
+    }
+
+    let found = match build {
+        Some(build) => builds.into_iter().find(|logged| logged.build == build),
+        None => builds.into_iter().last(),
+    };
+
+    found.ok_or_else(|| format!("No build log found for {} {}", project, git_ref))
+}
+
+/// Writes all step logs of a build to `out`
+pub fn print(build: &LoggedBuild, out: &mut dyn Write) -> Result<(), String> {
+    let logs = build.step_logs()
+        .map_err(|e| format!("Could not read build log: {}", e))?;
+
+    for log in logs {
+        let mut file = File::open(&log)
+            .map_err(|e| format!("Could not open {:?}: {}", log, e))?;
+        io::copy(&mut file, out)
+            .map_err(|e| format!("Could not print {:?}: {}", log, e))?;
+    }
+
+    Ok(())
+}
+
+/// Prints the logs of a build and new output until the build has finished
+pub fn follow(build: &LoggedBuild, out: &mut dyn Write) -> Result<(), String> {
+    let mut positions: Vec<(PathBuf, u64)> = Vec::new();
+
+    loop {
+        // read the status first, so that no output written before the
+        // build finished is missed
+        let finished = build.read_status().is_some() || !build.is_running();
+
+        let logs = build.step_logs()
+            .map_err(|e| format!("Could not read build log: {}", e))?;
+        for log in logs {
+            if !positions.iter().any(|(path, _)| *path == log) {
+                positions.push((log, 0));
+            }
+        }
+
+        for (path, position) in positions.iter_mut() {
+            let mut file = OpenOptions::new().read(true).open(&path)
+                .map_err(|e| format!("Could not open {:?}: {}", path, e))?;
+            file.seek(SeekFrom::Start(*position))
+                .map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+
+            let mut new_output = Vec::new();
+            file.read_to_end(&mut new_output)
+                .map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+            *position += new_output.len() as u64;
+
+            out.write_all(&new_output)
+                .and_then(|_| out.flush())
+                .map_err(|e| format!("Could not print log: {}", e))?;
+        }
+
+        if finished {
+            return Ok(());
+        }
+
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Searches all step logs of the builds for a regular expression and returns
+/// the matching lines prefixed with their build and step
+pub fn grep(builds: &[LoggedBuild], pattern: &str) -> Result<Vec<String>, String> {
+    let regex = Regex::new(pattern)
+        .map_err(|e| format!("Invalid pattern {}: {}", pattern, e))?;
+    let mut matches = Vec::new();
+
+    for build in builds {
+        let logs = build.step_logs()
+            .map_err(|e| format!("Could not read build log: {}", e))?;
+
+        for log in logs {
+            let content = fs::read(&log)
+                .map_err(|e| format!("Could not read {:?}: {}", log, e))?;
+            let step = log.file_stem().unwrap().to_string_lossy();
+
+            for line in String::from_utf8_lossy(&content).lines() {
+                if regex.is_match(line) {
+                    matches.push(format!("{}/{}/{}/{}: {}",
+                                         build.project, build.git_ref, build.build,
+                                         step, line));
+                }
+            }
+        }
+    }
+
+    Ok(matches)
+}
+
+/// The subdirectories of a directory sorted by name, only the given one if
+/// a name is set
+fn subdirs(dir: &Path, name: Option<&str>) -> Result<Vec<PathBuf>, String> {
+    if let Some(name) = name {
+        let subdir = dir.join(name);
+        return Ok(if subdir.is_dir() { vec![subdir] } else { vec![] });
+    }
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("Could not read log dir {:?}: {}", dir, e)),
+    };
+
+    let mut dirs = entries.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect::<Vec<PathBuf>>();
+    dirs.sort();
+
+    Ok(dirs)
+}
+
+pub fn git_ref(exec_config: &ExecutionConfig) -> String {
+    match (&exec_config.branch, &exec_config.tag) {
+        (Some(branch), _) => branch.clone(),
+        (None, Some(tag)) => tag.clone(),
+        (None, None) => String::from("default"),
+    }
+}
+
+/// Makes a ref usable as a single path component, reversibly so that e.g.
+/// the branches `feature/x` and `feature_x` get different directories
+fn encode_ref(git_ref: &str) -> String {
+    utf8_percent_encode(git_ref, REF_UNENCODED).to_string()
+}
+
+fn decode_ref(encoded: &str) -> String {
+    percent_decode_str(encoded).decode_utf8_lossy().to_string()
+}
+
+/// Makes a name usable as a single path component, e.g. pipeline names
+/// with a slash
+pub fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' })
+        .collect()
+}
+
+fn now() -> String {
+    chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::Failure;
+
+    #[test]
+    fn test_write_and_read_build_log() {
+        let root = tempfile::tempdir().unwrap();
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "feature/login");
+        let log = BuildLog::create(root.path(), &exec_config, DEFAULT_TAIL_LINES).unwrap();
+
+        let mut step = log.step("test");
+        step.begin("cargo test");
+        step.line("running 2 tests");
+        step.end(&StepResult {
+            pipeline: String::from("test"),
+            command: String::from("cargo test"),
+            output: String::new(),
+            truncated: false,
+            failure: Some(Failure::ExitCode(101)),
+            usage: None,
+            log_file: None,
+        });
+        let output = StepLog::read_output(step.path().unwrap()).unwrap();
+        assert_eq!(output, "running 2 tests\n");
+        log.record("deploy", StepResult::not_executed(
+            String::from("deploy.sh"), String::from("Unknown secret TOKEN")));
+
+        let builds = list(root.path(), Some("website.git"), None).unwrap();
+        assert_eq!(builds.len(), 1);
+        assert_eq!(builds[0].project, "website.git");
+        assert_eq!(builds[0].git_ref, "feature/login");
+        assert_eq!(builds[0].status, None);
+
+        log.finish("failed");
+        let build = find(root.path(), "website.git", "feature/login", None).unwrap();
+        assert_eq!(build.status, Some(String::from("failed")));
+
+        let mut out = Vec::new();
+        print(&build, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<&str>>();
+        assert!(lines[0].starts_with("=== ") && lines[0].ends_with(" | pipeline test | cargo test"));
+        assert!(lines[1].ends_with("] running 2 tests"));
+        assert!(lines[2].ends_with(" | failed (exit code 101)"));
+        assert!(lines[3].ends_with(" | pipeline deploy | deploy.sh"));
+        assert!(lines[4].ends_with("] Unknown secret TOKEN"));
+
+        // a finished build is printed completely when following
+        let mut followed = Vec::new();
+        follow(&build, &mut followed).unwrap();
+        assert_eq!(String::from_utf8(followed).unwrap(), out);
+
+        let matches = grep(&builds, "secret").unwrap();
+        assert_eq!(matches.len(), 1);
+        assert!(matches[0].starts_with(&format!(
+            "website.git/feature/login/{}/002-deploy: [", build.build)));
+    }
+
+    #[test]
+    fn test_similar_projects_and_refs_are_kept_apart() {
+        let root = tempfile::tempdir().unwrap();
+        let configs = [
+            ExecutionConfig::for_branch("/srv/git/website.git", "feature/x"),
+            ExecutionConfig::for_branch("/srv/git/website.git", "feature_x"),
+            ExecutionConfig::for_branch("/srv/forks/website.git", "feature/x"),
+        ];
+        for exec_config in &configs {
+            BuildLog::create(root.path(), exec_config, DEFAULT_TAIL_LINES).unwrap();
+        }
+
+        assert_eq!(list(root.path(), Some("/srv/git/website.git"), None).unwrap().len(), 2);
+        assert_eq!(list(root.path(), Some("website.git"), Some("feature_x")).unwrap().len(), 1);
+        assert!(find(root.path(), "/srv/forks/website.git", "feature/x", None).is_ok());
+        assert!(find(root.path(), "website.git", "feature/x", None).unwrap_err()
+            .starts_with("Several projects are called website.git"));
+    }
+
+    #[test]
+    fn test_only_tail_is_kept_in_memory() {
+        let root = tempfile::tempdir().unwrap();
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let log = BuildLog::create(root.path(), &exec_config, 2).unwrap();
+
+        let mut step = log.step("test");
+        step.begin("seq 3");
+        assert_eq!(step.take_output(), (String::new(), false));
+        for line in &["1", "2", "3"] {
+            step.line(line);
+        }
+
+        assert_eq!(step.take_output(), (String::from("2\n3\n"), true));
+
+        let long = "x".repeat(MAX_TAIL_BYTES / 2 + 1);
+        step.line(&long);
+        step.line(&long);
+        assert_eq!(step.take_output(), (format!("{}\n", long), true));
+
+        let mut out = Vec::new();
+        print(&find(root.path(), "website.git", "master", None).unwrap(), &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("] 1\n"));
+    }
+}
@@ -1,14 +1,17 @@
 use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
 use std::path::Path;
 use std::process;
 
 use rpassword;
 use env_logger;
-use getopts::Options;
-use cinderella::ExecutionConfig;
+use getopts::{Matches, Options};
+use cinderella::{ExecutionConfig, OutputFormat, Report, Trigger};
 
 fn print_usage(program: &str) {
-    println!("Usage: {} (run | encrypt | decrypt)", program);
+    println!("Usage: {} (run | exec | enqueue | daemon | scheduler | queue | logs | status | history | rerun | encrypt | decrypt)", program);
 }
 
 fn print_usage_command(program: &str, argline: &str, opts: Options) {
@@ -21,7 +24,9 @@ fn main() {
 
     const NAME: &'static str = env!("CARGO_PKG_NAME");
     const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-    println!("{} v{}", NAME, VERSION);
+    // on stderr, so that the output of e.g. `status --format json` can be
+    // parsed by scripts
+    eprintln!("{} v{}", NAME, VERSION);
 
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
@@ -30,6 +35,15 @@ fn main() {
         Some(command) => {
             match command.as_ref() {
                 "run" => run(args),
+                "exec" => exec(args),
+                "enqueue" => enqueue(args),
+                "daemon" => cinderella::daemon(),
+                "scheduler" => cinderella::scheduler(),
+                "queue" => cinderella::queue(),
+                "logs" => logs(args),
+                "status" => status(args),
+                "history" => history(args),
+                "rerun" => rerun(args),
                 "encrypt" => encrypt(args),
                 "decrypt" => decrypt(args),
                 "--help" | "-h" => print_usage(&program),
@@ -46,6 +60,137 @@ fn main() {
     }
 }
 
+fn logs(args: Vec<String>) {
+    let program = args[0].clone();
+
+    if args.len() < 3 || args[2] == "--help" || args[2] == "-h" {
+        println!("Usage: {} logs list [PROJECT [REF]]", program);
+        println!("       {} logs show PROJECT REF [BUILD]", program);
+        println!("       {} logs follow PROJECT REF [BUILD]", program);
+        println!("       {} logs grep PATTERN [PROJECT [REF]]", program);
+        return;
+    }
+
+    if let Err(msg) = cinderella::logs(&args[2..]) {
+        println!("{}", msg);
+        process::exit(1);
+    }
+}
+
+fn format_option(opts: &mut Options) {
+    opts.optopt("", "format", "print the result as text (default) or json", "FORMAT");
+}
+
+/// Parses the arguments of a query command, exits on invalid arguments
+fn parse_query_args(args: &[String], argline: &str, opts: Options)
+    -> (Matches, OutputFormat)
+{
+    let program = args[0].clone();
+
+    let matches = match opts.parse(&args[2..]) {
+        Ok(m) if !m.opt_present("h") => m,
+        Ok(_) => {
+            print_usage_command(&program, argline, opts);
+            process::exit(0);
+        },
+        Err(f) => {
+            println!("{}", f.to_string());
+            print_usage_command(&program, argline, opts);
+            process::exit(1);
+        },
+    };
+
+    let format = match matches.opt_get::<OutputFormat>("format") {
+        Ok(format) => format.unwrap_or(OutputFormat::Text),
+        Err(msg) => {
+            println!("{}", msg);
+            process::exit(1);
+        },
+    };
+
+    (matches, format)
+}
+
+fn status(args: Vec<String>) {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help");
+    format_option(&mut opts);
+
+    let (matches, format) = parse_query_args(&args, "status [options] [PROJECT [REF]]", opts);
+    let project = matches.free.get(0).map(|project| project.as_str());
+    let git_ref = matches.free.get(1).map(|git_ref| git_ref.as_str());
+
+    if let Err(msg) = cinderella::status(project, git_ref, format) {
+        println!("{}", msg);
+        process::exit(1);
+    }
+}
+
+fn history(args: Vec<String>) {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help");
+    opts.optopt("n", "count", "number of builds to list (default 10)", "COUNT");
+    format_option(&mut opts);
+
+    let argline = "history [options] PROJECT [BUILD]";
+    let (matches, format) = parse_query_args(&args, argline, opts);
+
+    let project = match matches.free.get(0) {
+        Some(project) => project,
+        None => {
+            println!("Please provide a project");
+            process::exit(1);
+        },
+    };
+    let number = match matches.free.get(1).map(|number| number.trim_start_matches('#').parse()) {
+        Some(Ok(number)) => Some(number),
+        Some(Err(e)) => {
+            println!("Invalid build number: {}", e);
+            process::exit(1);
+        },
+        None => None,
+    };
+    let count = match matches.opt_get_default("n", 10) {
+        Ok(count) => count,
+        Err(e) => {
+            println!("Invalid count: {}", e);
+            process::exit(1);
+        },
+    };
+
+    if let Err(msg) = cinderella::history(project, number, count, format) {
+        println!("{}", msg);
+        process::exit(1);
+    }
+}
+
+fn rerun(args: Vec<String>) {
+    let program = args[0].clone();
+
+    let (project, number) = match (args.get(2), args.get(3)) {
+        (Some(project), Some(number)) if project != "--help" && project != "-h" => (project, number),
+        _ => {
+            println!("Usage: {} rerun PROJECT BUILD", program);
+            return;
+        },
+    };
+    let number = match number.trim_start_matches('#').parse() {
+        Ok(number) => number,
+        Err(e) => {
+            println!("Invalid build number: {}", e);
+            process::exit(1);
+        },
+    };
+
+    match cinderella::rerun(project, number) {
+        Ok(report) => finish_build(&report, None, None),
+        Err(msg) => {
+            println!("{}", msg);
+            process::exit(2);
+        },
+    }
+}
+
 fn parse_password_arg(opts: &Options, args: Vec<String>)
     -> Result<Option<String>, String>
 {
@@ -95,19 +240,28 @@ fn decrypt(args: Vec<String>) {
         Path::new(".cinderella/secrets.toml"), &pass);
 }
 
-fn run(args: Vec<String>) {
-    let program = args[0].clone();
-
+fn build_options() -> Options {
     let mut opts = Options::new();
     opts.optopt("b", "branch", "set the branch to checkout", "BRANCH");
     opts.optopt("t", "tag", "set the tag to checkout", "TAG");
     opts.optopt("f", "file", "set a file to the cinderella CI configuration", "FILEPATH");
+    opts.optmulti("", "pipeline", "run only this pipeline, even if it is manual (repeatable)", "NAME");
+    opts.optmulti("", "skip", "do not run this pipeline (repeatable)", "NAME");
+
+    opts
+}
+
+/// Parses the arguments of a build command, exits on invalid arguments
+fn parse_build_args(args: &[String], command: &str, opts: Options)
+    -> Option<(ExecutionConfig, Matches)>
+{
+    let program = args[0].clone();
 
     let matches = match opts.parse(&args[2..]) {
         Ok(m) => { m },
         Err(f) => {
             println!("{}", f.to_string());
-            print_usage_command(&program, "run [options] REPO", opts);
+            print_usage_command(&program, &format!("{} [options] REPO", command), opts);
             process::exit(1);
         },
     };
@@ -116,7 +270,7 @@ fn run(args: Vec<String>) {
         matches.free[0].clone()
     } else {
         print_usage(&program);
-        return;
+        return None;
     };
 
     let repo = ExecutionConfig {
@@ -124,8 +278,155 @@ fn run(args: Vec<String>) {
         branch: matches.opt_str("b"),
         tag: matches.opt_str("t"),
         cinderella_filepath: matches.opt_str("f"),
+        pipelines: matches.opt_strs("pipeline"),
+        skip_pipelines: matches.opt_strs("skip"),
+        trigger: Trigger::Push,
+        commit: None,
+        rerun_of: None,
+    };
+
+    Some((repo, matches))
+}
+
+fn run(args: Vec<String>) {
+    let mut opts = build_options();
+    opts.optopt("", "report", "write a JSON report of the build to this file", "FILE");
+    opts.optopt("", "output", "print the result as text (default) or json", "FORMAT");
+    opts.optflag("", "dry-run", "print what the build would execute, but do not execute it");
+    opts.optopt("", "trigger", "how the build was started: push (default) or schedule", "TRIGGER");
+
+    if let Some((mut repo, matches)) = parse_build_args(&args, "run", opts) {
+        match matches.opt_get::<Trigger>("trigger") {
+            Ok(trigger) => repo.trigger = trigger.unwrap_or(Trigger::Push),
+            Err(msg) => {
+                println!("{}", msg);
+                process::exit(1);
+            },
+        }
+
+        if matches.opt_present("dry-run") {
+            if matches.opt_present("report") || matches.opt_present("output") {
+                println!("--dry-run cannot be combined with --report or --output");
+                process::exit(1);
+            }
+            if let Err(msg) = cinderella::dry_run(&repo) {
+                println!("{}", msg);
+                process::exit(1);
+            }
+            return;
+        }
+
+        let output = match matches.opt_get::<OutputFormat>("output") {
+            Ok(output) => output.unwrap_or(OutputFormat::Text),
+            Err(msg) => {
+                println!("{}", msg);
+                process::exit(1);
+            },
+        };
+
+        let json_out = match output {
+            OutputFormat::Json => Some(redirect_stdout_to_stderr()),
+            OutputFormat::Text => None,
+        };
+
+        let report = cinderella::run(&repo);
+        finish_build(&report, matches.opt_str("report"), json_out);
+    }
+}
+
+fn exec(args: Vec<String>) {
+    let program = args[0].clone();
+    let argline = "exec [options] [DIR]";
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help");
+    opts.optopt("f", "file", "set a file to the cinderella CI configuration", "FILEPATH");
+    opts.optmulti("", "pipeline", "run only this pipeline, even if it is manual (repeatable)", "NAME");
+    opts.optmulti("", "skip", "do not run this pipeline (repeatable)", "NAME");
+    opts.optflag("", "in-place", "build in the working tree instead of a copy of it");
+    opts.optopt("p", "password", "set the password of the secrets", "PASSWORD");
+
+    let matches = match opts.parse(&args[2..]) {
+        Ok(m) if !m.opt_present("h") => m,
+        Ok(_) => {
+            print_usage_command(&program, argline, opts);
+            return;
+        },
+        Err(f) => {
+            println!("{}", f.to_string());
+            print_usage_command(&program, argline, opts);
+            process::exit(1);
+        },
+    };
+
+    // the repository and the branch are determined from the directory
+    let exec_config = ExecutionConfig {
+        repo_url: matches.free.get(0).cloned().unwrap_or_else(|| String::from(".")),
+        branch: None,
+        tag: None,
+        cinderella_filepath: matches.opt_str("f"),
+        pipelines: matches.opt_strs("pipeline"),
+        skip_pipelines: matches.opt_strs("skip"),
+        trigger: Trigger::Push,
+        commit: None,
+        rerun_of: None,
     };
 
-    // TODO: Handle error from cinderella:run and display error message + usage
-    cinderella::run(&repo)
+    let report = cinderella::exec(
+        exec_config, matches.opt_present("in-place"), matches.opt_str("p"));
+    finish_build(&report, None, None);
+}
+
+/// Sends everything that is printed to stdout, including the output of the
+/// build commands, to stderr and returns the original stdout
+fn redirect_stdout_to_stderr() -> File {
+    io::stdout().flush().expect("Could not flush stdout");
+
+    let stdout = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    let redirected = unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) };
+    if stdout < 0 || redirected < 0 {
+        eprintln!("Could not redirect stdout: {}", io::Error::last_os_error());
+        process::exit(2);
+    }
+
+    unsafe { File::from_raw_fd(stdout) }
+}
+
+/// Writes the report of a build and exits with the exit code of its outcome
+fn finish_build(report: &Report, report_file: Option<String>, json_out: Option<File>) {
+    if let Some(path) = report_file {
+        if let Err(msg) = report.write(Path::new(&path)) {
+            eprintln!("{}", msg);
+        }
+    }
+
+    if let Some(mut out) = json_out {
+        let written = report.to_json()
+            .and_then(|json| writeln!(out, "{}", json).map_err(|e| e.to_string()));
+        if let Err(msg) = written {
+            eprintln!("Could not print report: {}", msg);
+        }
+    }
+
+    process::exit(report.outcome.exit_code());
+}
+
+fn enqueue(args: Vec<String>) {
+    let mut opts = build_options();
+    opts.optopt("", "priority", "builds with a higher priority are started first", "NUMBER");
+
+    if let Some((repo, matches)) = parse_build_args(&args, "enqueue", opts) {
+        let priority = match matches.opt_get::<i32>("priority") {
+            Ok(priority) => priority,
+            Err(e) => {
+                println!("Invalid priority: {}", e);
+                process::exit(1);
+            },
+        };
+
+        if let Err(msg) = cinderella::enqueue(&repo, priority) {
+            println!("{}", msg);
+            process::exit(1);
+        }
+    }
 }
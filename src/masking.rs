@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Replacement for secrets in all output of Cinderella
+pub const MASK: &str = "***";
+
+/// Secrets shorter than this are not masked, replacing every occurrence of
+/// a few characters would make the output unreadable
+pub const MIN_SECRET_LEN: usize = 4;
+
+/// Characters that are not escaped by most URL encoders
+const URL_UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// All registered secrets in the forms they might appear in, with the
+/// number of registrations of each form
+static SECRETS: Mutex<Vec<(String, usize)>> = Mutex::new(Vec::new());
+
+/// Keeps secrets masked as long as it lives, usually for one build
+///
+/// The secrets are unregistered when it is dropped, so that they are not
+/// masked in the output of later builds in the same process.
+#[must_use]
+#[derive(Debug, Default)]
+pub struct Registration {
+    forms: Vec<String>,
+}
+
+impl Registration {
+    /// Registers a secret, so that it is masked in all output
+    ///
+    /// Besides the plain value also its base64 and URL encoded forms are
+    /// masked. Returns `false` for secrets that are too short to be masked.
+    pub fn add(&mut self, secret: &str) -> bool {
+        if secret.chars().count() < MIN_SECRET_LEN {
+            return false;
+        }
+
+        let mut secrets = SECRETS.lock().unwrap();
+        for form in encoded_forms(secret) {
+            match secrets.iter_mut().find(|(known, _)| *known == form) {
+                Some((_, count)) => *count += 1,
+                None => secrets.push((form.clone(), 1)),
+            }
+            self.forms.push(form);
+        }
+
+        // longer forms first, so that a secret contained in another one does
+        // not leave parts of the longer one behind
+        secrets.sort_by_key(|(form, _)| std::cmp::Reverse(form.len()));
+
+        true
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+        for form in &self.forms {
+            if let Some((_, count)) = secrets.iter_mut().find(|(known, _)| known == form) {
+                *count -= 1;
+            }
+        }
+        secrets.retain(|(_, count)| *count > 0);
+    }
+}
+
+/// Registers a single secret, see `Registration::add`
+#[cfg(test)]
+pub fn register(secret: &str) -> Registration {
+    let mut registration = Registration::default();
+    registration.add(secret);
+
+    registration
+}
+
+/// Registers the secrets of a build, warning about the ones that are too
+/// short to be masked
+pub fn register_secrets(secrets: &HashMap<String, String>) -> Registration {
+    let mut registration = Registration::default();
+
+    for (name, secret) in secrets {
+        if !registration.add(secret) && !secret.is_empty() {
+            eprintln!(
+                "Warning: secret {} is shorter than {} characters and is not masked \
+                 in the output", name, MIN_SECRET_LEN);
+        }
+    }
+
+    registration
+}
+
+/// Replaces all registered secrets in the text with `***`
+pub fn mask(text: &str) -> String {
+    let secrets = SECRETS.lock().unwrap();
+
+    secrets.iter().fold(text.to_string(), |text, (secret, _)| {
+        if text.contains(secret.as_str()) {
+            text.replace(secret.as_str(), MASK)
+        } else {
+            text
+        }
+    })
+}
+
+fn encoded_forms(secret: &str) -> Vec<String> {
+    let forms = vec![
+        secret.to_string(),
+        base64::encode_config(secret, base64::STANDARD),
+        base64::encode_config(secret, base64::STANDARD_NO_PAD),
+        base64::encode_config(secret, base64::URL_SAFE),
+        base64::encode_config(secret, base64::URL_SAFE_NO_PAD),
+        utf8_percent_encode(secret, NON_ALPHANUMERIC).to_string(),
+        utf8_percent_encode(secret, URL_UNRESERVED).to_string(),
+    ];
+
+    let mut unique: Vec<String> = Vec::new();
+    for form in forms {
+        if !unique.contains(&form) {
+            unique.push(form);
+        }
+    }
+
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_registered_secret() {
+        let _registration = register("masking-test-s3cret/+");
+
+        assert_eq!(mask("password: masking-test-s3cret/+!"), "password: ***!");
+        assert_eq!(mask("nothing to hide"), "nothing to hide");
+    }
+
+    #[test]
+    fn test_mask_encoded_forms() {
+        let secret = "masking test:p@ss?";
+        let _registration = register(secret);
+
+        let base64 = base64::encode(secret);
+        assert_eq!(mask(&format!("Authorization: Basic {}", base64)),
+                   "Authorization: Basic ***");
+        assert_eq!(mask("https://host/?pw=masking%20test%3Ap%40ss%3F"),
+                   "https://host/?pw=***");
+    }
+
+    #[test]
+    fn test_short_secrets_are_ignored() {
+        let _empty = register("");
+        let _short = register("abc");
+
+        assert_eq!(mask("abc"), "abc");
+    }
+
+    #[test]
+    fn test_secrets_are_unregistered_on_drop() {
+        let outer = register("masking-test-dropped");
+        let inner = register("masking-test-dropped");
+        drop(inner);
+        assert_eq!(mask("masking-test-dropped"), "***");
+
+        drop(outer);
+        assert_eq!(mask("masking-test-dropped"), "masking-test-dropped");
+    }
+}
@@ -1,57 +1,135 @@
-pub fn parse_command(command: &str) -> Vec<String> {
-    // TODO: improve this to a good state machine
-    let mut parts = Vec::new();
-    let mut in_quotes = false;
-    let mut in_word = false;
-    let mut start_idx = 0;
-    let mut next_char_escaped = false;
-    let mut buffer = String::new();
-
-    for (i, c) in command.chars().enumerate() {
-        if in_quotes {
-            if c == '"' {
-                parts.push(String::from(&command[start_idx..i]));
-                in_quotes = false;
-                start_idx = i + 1;
-            }
-        } else {
-            if c == '\\' && !next_char_escaped {
-                buffer.push_str(&command[start_idx..i]);
-                buffer.push_str(&command[(i+1)..(i+2)]);
-                next_char_escaped = true;
-                start_idx = i + 2;
-            } else if c == '"' && !next_char_escaped {
-                in_quotes = true;
-                start_idx = i + 1;
-                next_char_escaped = false;
-            } else if !next_char_escaped && c != ' ' && !in_word {
-                start_idx = i;
-                in_word = true;
-                next_char_escaped = false;
-            } else if !next_char_escaped && c == ' ' && in_word {
-                parts.push(format!("{}{}", buffer, String::from(&command[start_idx..i])));
-                buffer = String::new();
-                in_word = false;
-                next_char_escaped = false;
-            }
-        }
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    /// Character position in the command where the error was detected
+    pub position: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
     }
+}
+
+enum State {
+    /// Between two words
+    Whitespace,
+    /// Inside a word, outside of quotes
+    Word,
+    /// Inside single quotes, started at the given position
+    SingleQuoted(usize),
+    /// Inside double quotes, started at the given position
+    DoubleQuoted(usize),
+}
 
-    let end = command.len();
-    if start_idx != end {
-        parts.push(format!("{}{}", buffer, String::from(&command[start_idx..end])));
+/// Splits a command into its arguments like a POSIX shell does
+///
+/// Supports single quotes (everything literal), double quotes (only `\\`,
+/// `\"`, `\$`, `` \` `` and a backslash-newline are escapes), backslash
+/// escapes outside of quotes and words built from adjacent quoted and
+/// unquoted parts like `--name="my value"`.
+pub fn parse_command(command: &str) -> Result<Vec<String>, ParseError> {
+    let mut parts = Vec::new();
+    let mut word = String::new();
+    let mut state = State::Whitespace;
+    let mut chars = command.chars().enumerate().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        state = match state {
+            State::Whitespace | State::Word => {
+                match c {
+                    c if c.is_whitespace() => {
+                        if let State::Word = state {
+                            parts.push(word);
+                            word = String::new();
+                        }
+                        State::Whitespace
+                    },
+                    '\\' => {
+                        match chars.next() {
+                            // line continuation
+                            Some((_, '\n')) => (),
+                            Some((_, escaped)) => word.push(escaped),
+                            None => return Err(ParseError {
+                                message: String::from("Trailing backslash"),
+                                position: i,
+                            }),
+                        }
+                        State::Word
+                    },
+                    '\'' => State::SingleQuoted(i),
+                    '"' => State::DoubleQuoted(i),
+                    c => {
+                        word.push(c);
+                        State::Word
+                    },
+                }
+            },
+            State::SingleQuoted(start) => {
+                match c {
+                    '\'' => State::Word,
+                    c => {
+                        word.push(c);
+                        State::SingleQuoted(start)
+                    },
+                }
+            },
+            State::DoubleQuoted(start) => {
+                match c {
+                    '"' => State::Word,
+                    '\\' => {
+                        match chars.peek() {
+                            Some((_, '\n')) => {
+                                chars.next();
+                            },
+                            Some((_, escaped @ '\\'))
+                                | Some((_, escaped @ '"'))
+                                | Some((_, escaped @ '$'))
+                                | Some((_, escaped @ '`')) =>
+                            {
+                                word.push(*escaped);
+                                chars.next();
+                            },
+                            _ => word.push(c),
+                        }
+                        State::DoubleQuoted(start)
+                    },
+                    c => {
+                        word.push(c);
+                        State::DoubleQuoted(start)
+                    },
+                }
+            },
+        };
     }
 
-    parts
+    match state {
+        State::SingleQuoted(start) => Err(ParseError {
+            message: String::from("Unterminated single quote"),
+            position: start,
+        }),
+        State::DoubleQuoted(start) => Err(ParseError {
+            message: String::from("Unterminated double quote"),
+            position: start,
+        }),
+        State::Word => {
+            parts.push(word);
+            Ok(parts)
+        },
+        State::Whitespace => Ok(parts),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_parse_simple_command() {
-        let result = parse_command("program execute something");
+        let result = parse_command("program execute something").unwrap();
 
         assert_eq!(result.len(), 3);
         assert_eq!(result[0], "program");
@@ -61,7 +139,7 @@ mod tests {
 
     #[test]
     fn test_parse_command_with_quoted_args() {
-        let result = parse_command("program \"execute something\" and \"something else\"");
+        let result = parse_command("program \"execute something\" and \"something else\"").unwrap();
 
         assert_eq!(result.len(), 4);
         assert_eq!(result[0], "program");
@@ -72,7 +150,7 @@ mod tests {
 
     #[test]
     fn test_parse_command_with_spaced_arg() {
-        let result = parse_command("program execute\\ something");
+        let result = parse_command("program execute\\ something").unwrap();
 
         assert_eq!(result.len(), 2);
         assert_eq!(result[0], "program");
@@ -81,7 +159,7 @@ mod tests {
 
     #[test]
     fn test_bash_command() {
-        let result = parse_command("bash -c \"exit 1\"");
+        let result = parse_command("bash -c \"exit 1\"").unwrap();
 
         assert_eq!(result.len(), 3);
         assert_eq!(result[0], "bash");
@@ -91,11 +169,107 @@ mod tests {
 
     #[test]
     fn test_parse_virtualenv_tox_command() {
-        let result = parse_command("bash -c \"virtualenv env && source env/bin/activate && tox\"");
+        let result = parse_command("bash -c \"virtualenv env && source env/bin/activate && tox\"").unwrap();
 
         assert_eq!(result.len(), 3);
         assert_eq!(result[0], "bash");
         assert_eq!(result[1], "-c");
         assert_eq!(result[2], "virtualenv env && source env/bin/activate && tox");
     }
+
+    #[test]
+    fn test_parse_single_quotes() {
+        let result = parse_command("echo 'this is my test'").unwrap();
+
+        assert_eq!(result, vec!["echo", "this is my test"]);
+
+        // no escapes inside single quotes
+        let result = parse_command(r#"echo 'a\b "c"'"#).unwrap();
+        assert_eq!(result, vec!["echo", r#"a\b "c""#]);
+    }
+
+    #[test]
+    fn test_parse_escapes_in_double_quotes() {
+        let result = parse_command(r#"echo "say \"hi\" \$HOME \n""#).unwrap();
+
+        assert_eq!(result, vec!["echo", r#"say "hi" $HOME \n"#]);
+    }
+
+    #[test]
+    fn test_parse_adjacent_parts() {
+        let result = parse_command(r#"cmd --name="my value"'s' a"b"c"#).unwrap();
+
+        assert_eq!(result, vec!["cmd", "--name=my values", "abc"]);
+    }
+
+    #[test]
+    fn test_parse_empty_quotes() {
+        let result = parse_command("cmd '' \"\"").unwrap();
+
+        assert_eq!(result, vec!["cmd", "", ""]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse_command("echo \\"), Err(ParseError {
+            message: String::from("Trailing backslash"),
+            position: 5,
+        }));
+        assert_eq!(parse_command("echo 'abc").unwrap_err().position, 5);
+        assert_eq!(parse_command("echo ok \"abc").unwrap_err().position, 8);
+    }
+
+    fn quote_single(word: &str) -> String {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+
+    fn quote_double(word: &str) -> String {
+        let mut quoted = String::from("\"");
+        for c in word.chars() {
+            if c == '\\' || c == '"' || c == '$' || c == '`' {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    fn quote_backslash(word: &str) -> String {
+        if word.is_empty() {
+            return String::from("''");
+        }
+
+        word.chars().map(|c| format!("\\{}", c)).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn prop_never_panics(command in "\\PC*") {
+            let _ = parse_command(&command);
+        }
+
+        #[test]
+        fn prop_quoted_words_roundtrip(
+            words in prop::collection::vec("[^\n]*", 0..5),
+            style in 0..3u8)
+        {
+            let command = words.iter().map(|w| match style {
+                0 => quote_single(w),
+                1 => quote_double(w),
+                _ => quote_backslash(w),
+            }).collect::<Vec<String>>().join(" ");
+
+            prop_assert_eq!(parse_command(&command).unwrap(), words);
+        }
+
+        #[test]
+        fn prop_plain_words_roundtrip(
+            words in prop::collection::vec("[a-zA-Z0-9_./=-]+", 0..5))
+        {
+            let command = words.join(" ");
+
+            prop_assert_eq!(parse_command(&command).unwrap(), words);
+        }
+    }
 }
@@ -1,44 +1,204 @@
 use std::fs;
 use std::path::PathBuf;
 use toml::Value;
+use toml::value::Table;
+
+use crate::limits::Limits;
+use crate::test_results::TestOutput;
 
 #[derive(Debug)]
 pub struct Pipeline {
     pub name: String,
     pub commands: Vec<String>,
     pub when: Option<String>,
+    pub manual: bool,
+    pub schedule: Option<String>,
+    pub runs_on: Option<String>,
+    /// Name of a sandbox from the Cinderella configuration to execute in
+    pub sandbox: Option<String>,
+    /// Whether a sandboxed pipeline can access the network
+    pub network: bool,
+    /// Resource limits, unset limits are taken from the Cinderella
+    /// configuration
+    pub limits: Limits,
+    /// Secrets that are passed to the commands as environment variables
+    pub secrets_env: Vec<String>,
+    /// Glob patterns of JUnit XML reports the commands write, relative to
+    /// the working copy
+    pub test_reports: Vec<String>,
+    /// Format of test runner output to parse from the commands
+    pub test_output: Option<TestOutput>,
+}
+
+impl Default for Pipeline {
+    /// An automatic pipeline without commands, as if all optional fields
+    /// were missing in the CI configuration
+    fn default() -> Pipeline {
+        Pipeline {
+            name: String::new(),
+            commands: vec![],
+            when: None,
+            manual: false,
+            schedule: None,
+            runs_on: None,
+            sandbox: None,
+            network: true,
+            limits: Limits::default(),
+            secrets_env: vec![],
+            test_reports: vec![],
+            test_output: None,
+        }
+    }
+}
+
+/// Restricts which pipelines of a CI configuration are executed
+///
+/// Without any explicitly selected pipelines all pipelines except the
+/// manual ones are executed. If pipelines are selected, exactly these
+/// are executed (including manual ones). Skipped pipelines are never
+/// executed. On scheduled builds only pipelines with a schedule are
+/// executed.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub pipelines: Vec<String>,
+    pub skip: Vec<String>,
+    pub scheduled_only: bool,
 }
 
-pub fn load_pipeline(path: &PathBuf) -> Option<Vec<Pipeline>> {
+impl Selection {
+    pub fn includes(&self, pipeline: &Pipeline) -> bool {
+        self.skip_reason(pipeline).is_none()
+    }
+
+    /// Why the pipeline is not part of the selection, `None` if it is
+    pub fn skip_reason(&self, pipeline: &Pipeline) -> Option<&'static str> {
+        if self.skip.contains(&pipeline.name) {
+            Some("skipped with --skip")
+        } else if self.scheduled_only && pipeline.schedule.is_none() {
+            Some("not a scheduled pipeline")
+        } else if self.pipelines.is_empty() {
+            if pipeline.manual { Some("manual pipeline") } else { None }
+        } else if self.pipelines.contains(&pipeline.name) {
+            None
+        } else {
+            Some("not selected with --pipeline")
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.pipelines.is_empty() && self.skip.is_empty() && !self.scheduled_only
+    }
+
+    /// Returns all names in the selection that do not match a pipeline
+    pub fn unknown_pipelines(&self, pipelines: &[Pipeline]) -> Vec<String> {
+        self.pipelines.iter().chain(self.skip.iter())
+            .filter(|name| !pipelines.iter().any(|p| &p.name == *name))
+            .cloned()
+            .collect()
+    }
+
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+
+        if self.scheduled_only {
+            parts.push(String::from("scheduled pipelines only"));
+        }
+        if !self.pipelines.is_empty() {
+            parts.push(format!("selected: {}", self.pipelines.join(", ")));
+        }
+        if !self.skip.is_empty() {
+            parts.push(format!("skipped: {}", self.skip.join(", ")));
+        }
+
+        if parts.is_empty() {
+            String::from("all automatic pipelines")
+        } else {
+            parts.join("; ")
+        }
+    }
+}
+
+/// Loads the pipelines of a CI configuration, `None` if there is none
+pub fn load_pipeline(path: &PathBuf) -> Result<Option<Vec<Pipeline>>, String> {
     if let Ok(contents) = fs::read_to_string(path) {
-        let data = contents.parse::<Value>().unwrap();
-
-        let res: Vec<Pipeline> = data.as_table().unwrap().iter()
-            .filter_map(|(key, value)| {
-                match value {
-                    Value::Table(table) => {
-                        // TODO: Better error handling needed
-                        Some(Pipeline {
-                            name: key.to_string(),
-                            commands: value["commands"].as_array().unwrap().iter()
-                                .map(|cmd| String::from(cmd.as_str().unwrap()))
-                                .collect(),
-                            when: table.get("when").map(|v| String::from(v.as_str().unwrap())),
-                        })
-                    },
-                    _ => None
-                }
-            }).collect();
-
-        Some(res)
+        parse_pipelines(&contents).map(Some)
     } else {
         // TODO: Should we differentiate more? Like:
         // - file does not exist: None
         // - file does exist, but cannot be read: Error
-        None
+        Ok(None)
     }
 }
 
+/// Parses the pipelines of the contents of a CI configuration
+pub fn parse_pipelines(contents: &str) -> Result<Vec<Pipeline>, String> {
+    let data: Table = toml::from_str(contents)
+        .map_err(|e| format!("Invalid CI configuration: {}", e))?;
+
+    data.iter()
+        .filter_map(|(key, value)| {
+            match value {
+                Value::Table(table) => Some(parse_pipeline(key, table)),
+                _ => None
+            }
+        }).collect()
+}
+
+fn parse_pipeline(name: &str, table: &Table) -> Result<Pipeline, String> {
+    let limits = match table.get("limits") {
+        Some(v) => v.clone().try_into()
+            .map_err(|e| format!("Invalid limits in pipeline {}: {}", name, e))?,
+        None => Limits::default(),
+    };
+    let test_output = match table.get("test_output") {
+        Some(v) => Some(v.clone().try_into()
+            .map_err(|e| format!("Invalid test_output in pipeline {}: {}", name, e))?),
+        None => None,
+    };
+
+    Ok(Pipeline {
+        name: name.to_string(),
+        commands: strings(name, table, "commands")?
+            .ok_or_else(|| format!("Invalid commands in pipeline {}: missing", name))?,
+        when: string(name, table, "when")?,
+        manual: boolean(name, table, "manual")?.unwrap_or(false),
+        schedule: string(name, table, "schedule")?,
+        runs_on: string(name, table, "runs_on")?,
+        sandbox: string(name, table, "sandbox")?,
+        network: boolean(name, table, "network")?.unwrap_or(true),
+        limits,
+        secrets_env: strings(name, table, "secrets_env")?.unwrap_or_default(),
+        test_reports: strings(name, table, "test_reports")?.unwrap_or_default(),
+        test_output,
+    })
+}
+
+fn invalid(name: &str, field: &str, expected: &str) -> String {
+    format!("Invalid {} in pipeline {}: expected {}", field, name, expected)
+}
+
+fn string(name: &str, table: &Table, field: &str) -> Result<Option<String>, String> {
+    table.get(field)
+        .map(|v| v.as_str().map(String::from).ok_or_else(|| invalid(name, field, "a string")))
+        .transpose()
+}
+
+fn boolean(name: &str, table: &Table, field: &str) -> Result<Option<bool>, String> {
+    table.get(field)
+        .map(|v| v.as_bool().ok_or_else(|| invalid(name, field, "true or false")))
+        .transpose()
+}
+
+fn strings(name: &str, table: &Table, field: &str) -> Result<Option<Vec<String>>, String> {
+    table.get(field)
+        .map(|v| v.as_array()
+            .and_then(|values| values.iter()
+                .map(|value| value.as_str().map(String::from))
+                .collect::<Option<Vec<String>>>())
+            .ok_or_else(|| invalid(name, field, "an array of strings")))
+        .transpose()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -52,7 +212,7 @@ mod tests {
         let f = tmpfile.as_file_mut();
         f.write_all(config.as_bytes()).expect("Unable to write to file");
 
-        let pipelines = load_pipeline(&tmpfile.path().to_path_buf());
+        let pipelines = load_pipeline(&tmpfile.path().to_path_buf()).unwrap();
         let pipelines = pipelines.unwrap();
 
         assert_eq!(pipelines.len(), 1);
@@ -61,6 +221,169 @@ mod tests {
         assert_eq!(pipelines[0].commands[0], "echo Hallo");
     }
 
+    #[test]
+    fn test_load_manual_pipeline() {
+        let config = "[test]\ncommands = [\"cargo test\"]\n\n\
+                      [deploy]\ncommands = [\"make deploy\"]\nmanual = true";
+        let mut tmpfile = NamedTempFile::new().unwrap();
+        let f = tmpfile.as_file_mut();
+        f.write_all(config.as_bytes()).expect("Unable to write to file");
+
+        let pipelines = load_pipeline(&tmpfile.path().to_path_buf()).unwrap().unwrap();
+
+        assert!(!pipelines[0].manual);
+        assert!(pipelines[1].manual);
+    }
+
+    #[test]
+    fn test_load_pipeline_limits() {
+        let config = "[test]\ncommands = [\"cargo test\"]\n\
+                      limits = { memory = \"2G\", processes = 256 }";
+        let mut tmpfile = NamedTempFile::new().unwrap();
+        let f = tmpfile.as_file_mut();
+        f.write_all(config.as_bytes()).expect("Unable to write to file");
+
+        let pipelines = load_pipeline(&tmpfile.path().to_path_buf()).unwrap().unwrap();
+
+        assert_eq!(pipelines[0].limits.memory, Some(2 << 30));
+        assert_eq!(pipelines[0].limits.processes, Some(256));
+        assert!(pipelines[0].limits.cpu_time.is_none());
+    }
+
+    #[test]
+    fn test_load_pipeline_test_results() {
+        let config = "[test]\ncommands = [\"cargo test\"]\n\
+                      test_output = \"libtest\"\n\
+                      test_reports = [\"target/junit/*.xml\"]";
+        let mut tmpfile = NamedTempFile::new().unwrap();
+        let f = tmpfile.as_file_mut();
+        f.write_all(config.as_bytes()).expect("Unable to write to file");
+
+        let pipelines = load_pipeline(&tmpfile.path().to_path_buf()).unwrap().unwrap();
+
+        assert_eq!(pipelines[0].test_output, Some(TestOutput::Libtest));
+        assert_eq!(pipelines[0].test_reports, vec![String::from("target/junit/*.xml")]);
+    }
+
+    #[test]
+    fn test_invalid_pipeline_options_are_errors() {
+        let config = "[test]\ncommands = [\"cargo test\"]\nlimits = { memory = \"lots\" }";
+        let mut tmpfile = NamedTempFile::new().unwrap();
+        tmpfile.as_file_mut().write_all(config.as_bytes()).unwrap();
+
+        let msg = load_pipeline(&tmpfile.path().to_path_buf()).unwrap_err();
+        assert!(msg.starts_with("Invalid limits in pipeline test"));
+
+        let config = "[test]\ncommands = [\"npm test\"]\ntest_output = \"jest\"";
+        let mut tmpfile = NamedTempFile::new().unwrap();
+        tmpfile.as_file_mut().write_all(config.as_bytes()).unwrap();
+
+        let msg = load_pipeline(&tmpfile.path().to_path_buf()).unwrap_err();
+        assert!(msg.starts_with("Invalid test_output in pipeline test"));
+    }
+
+    #[test]
+    fn test_invalid_toml_is_an_error() {
+        let msg = parse_pipelines("[test\ncommands = [").unwrap_err();
+        assert!(msg.starts_with("Invalid CI configuration: "));
+    }
+
+    #[test]
+    fn test_missing_commands_are_an_error() {
+        let msg = parse_pipelines("[test]\nwhen = \"true\"").unwrap_err();
+        assert_eq!(msg, "Invalid commands in pipeline test: missing");
+
+        let msg = parse_pipelines("[test]\ncommands = \"cargo test\"").unwrap_err();
+        assert_eq!(msg, "Invalid commands in pipeline test: expected an array of strings");
+
+        let msg = parse_pipelines("[test]\ncommands = [1]").unwrap_err();
+        assert_eq!(msg, "Invalid commands in pipeline test: expected an array of strings");
+    }
+
+    #[test]
+    fn test_non_string_options_are_errors() {
+        for field in &["when", "schedule", "runs_on", "sandbox"] {
+            let config = format!("[test]\ncommands = [\"cargo test\"]\n{} = 1", field);
+            let msg = parse_pipelines(&config).unwrap_err();
+            assert_eq!(msg, format!("Invalid {} in pipeline test: expected a string", field));
+        }
+    }
+
+    #[test]
+    fn test_non_string_lists_are_errors() {
+        for field in &["secrets_env", "test_reports"] {
+            let config = format!("[test]\ncommands = [\"cargo test\"]\n{} = [true]", field);
+            let msg = parse_pipelines(&config).unwrap_err();
+            assert_eq!(msg, format!("Invalid {} in pipeline test: expected an array of strings", field));
+
+            let config = format!("[test]\ncommands = [\"cargo test\"]\n{} = \"x\"", field);
+            assert!(parse_pipelines(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_non_boolean_flags_are_errors() {
+        for field in &["manual", "network"] {
+            let config = format!("[test]\ncommands = [\"cargo test\"]\n{} = \"yes\"", field);
+            let msg = parse_pipelines(&config).unwrap_err();
+            assert_eq!(msg, format!("Invalid {} in pipeline test: expected true or false", field));
+        }
+    }
+
+    #[test]
+    fn test_selection() {
+        let pipelines = vec![
+            Pipeline {
+                name: String::from("test"),
+                commands: vec![],
+                ..Pipeline::default()
+            },
+            Pipeline {
+                name: String::from("deploy"),
+                commands: vec![],
+                manual: true,
+                ..Pipeline::default()
+            },
+        ];
+
+        let default = Selection::default();
+        assert!(default.includes(&pipelines[0]));
+        assert!(!default.includes(&pipelines[1]));
+        assert_eq!(default.skip_reason(&pipelines[1]), Some("manual pipeline"));
+
+        let selected = Selection {
+            pipelines: vec![String::from("deploy")],
+            skip: vec![],
+            scheduled_only: false,
+        };
+        assert!(!selected.includes(&pipelines[0]));
+        assert_eq!(selected.skip_reason(&pipelines[0]), Some("not selected with --pipeline"));
+        assert!(selected.includes(&pipelines[1]));
+
+        let skipped = Selection {
+            pipelines: vec![],
+            skip: vec![String::from("test")],
+            scheduled_only: false,
+        };
+        assert!(!skipped.includes(&pipelines[0]));
+        assert_eq!(skipped.skip_reason(&pipelines[0]), Some("skipped with --skip"));
+        assert!(!skipped.includes(&pipelines[1]));
+
+        let unknown = Selection {
+            pipelines: vec![String::from("deploy"), String::from("docs")],
+            skip: vec![],
+            scheduled_only: false,
+        };
+        assert_eq!(unknown.unknown_pipelines(&pipelines), vec!["docs"]);
+
+        let scheduled = Selection {
+            pipelines: vec![],
+            skip: vec![],
+            scheduled_only: true,
+        };
+        assert!(!scheduled.includes(&pipelines[0]));
+    }
+
     #[test]
     fn test_none_on_missing_config() {
         let mut path = PathBuf::new();
@@ -68,6 +391,6 @@ mod tests {
 
         let pipelines = load_pipeline(&path);
 
-        assert!(pipelines.is_none());
+        assert!(pipelines.unwrap().is_none());
     }
 }
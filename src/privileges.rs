@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{lchown, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use duct::{cmd, Expression};
+
+use crate::config::{CinderellaConfig, ExecutionConfig};
+use crate::executor::Command;
+
+/// Script that exports the NUL separated variables read from stdin and then
+/// executes the command, so that the environment of a command started with
+/// `run_as_helper` does not show up on any command line
+const ENV_LOADER: &str = "while IFS= read -r -d '' var; do export \"$var\"; done; \
+                          exec \"$@\" < /dev/null";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl User {
+    pub fn lookup(name: &str) -> Result<User, String> {
+        let c_name = CString::new(name)
+            .map_err(|_| format!("Invalid user name: {}", name))?;
+
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buffer = vec![0 as libc::c_char; 4096];
+        let mut result: *mut libc::passwd = ptr::null_mut();
+
+        let code = unsafe {
+            libc::getpwnam_r(c_name.as_ptr(), &mut passwd,
+                             buffer.as_mut_ptr(), buffer.len(), &mut result)
+        };
+
+        if code != 0 || result.is_null() {
+            return Err(format!("Unknown user: {}", name));
+        }
+
+        let name = unsafe { CStr::from_ptr(passwd.pw_name) }
+            .to_string_lossy().to_string();
+
+        Ok(User {
+            name,
+            uid: passwd.pw_uid,
+            gid: passwd.pw_gid,
+        })
+    }
+}
+
+/// How Cinderella switches to the build user
+#[derive(Debug, PartialEq)]
+enum Switch {
+    /// Cinderella runs as root and can change the user of child processes
+    Direct,
+    /// A helper like `sudo` is used to run commands as the build user
+    Helper(String),
+    /// Cinderella already runs as the build user
+    Unchanged,
+}
+
+/// Runs commands of a build as an unprivileged build user with its own
+/// fresh home directory
+///
+/// The home directory is deleted when the value is dropped.
+pub struct RunAs {
+    user: User,
+    switch: Switch,
+    workdir: PathBuf,
+    home: PathBuf,
+}
+
+impl RunAs {
+    /// Determines the build user for a build, if one is configured
+    ///
+    /// A `run_as` of the project overrides the global `run_as`. The build
+    /// user gets `home` as its home directory.
+    pub fn from_config(
+        cinderella_config: &CinderellaConfig,
+        exec_config: &ExecutionConfig,
+        workdir: &Path,
+        home: &Path) -> Result<Option<RunAs>, String>
+    {
+        let project_run_as = cinderella_config.project(&exec_config.repo_url)
+            .and_then(|project| project.run_as.as_ref());
+
+        let name = match project_run_as.or(cinderella_config.run_as.as_ref()) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+
+        let user = User::lookup(name)?;
+        let current_uid = unsafe { libc::geteuid() };
+
+        let switch = if current_uid == user.uid {
+            Switch::Unchanged
+        } else if current_uid == 0 {
+            Switch::Direct
+        } else if let Some(helper) = &cinderella_config.run_as_helper {
+            Switch::Helper(helper.clone())
+        } else {
+            return Err(format!(
+                "Cannot run build as user {}: Cinderella does not run as \
+                 root and no run_as_helper is configured", user.name));
+        };
+
+        Ok(Some(RunAs {
+            user,
+            switch,
+            workdir: workdir.to_path_buf(),
+            home: home.to_path_buf(),
+        }))
+    }
+
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    /// Hands the work dir over to the build user and creates its home
+    pub fn prepare(&self) -> Result<(), String> {
+        let workdir = &self.workdir;
+
+        fs::create_dir_all(&self.home)
+            .map_err(|e| format!("Could not create build home: {}", e))?;
+        fs::set_permissions(&self.home, fs::Permissions::from_mode(0o700))
+            .map_err(|e| format!("Could not protect build home: {}", e))?;
+
+        match &self.switch {
+            Switch::Direct => {
+                chown_recursive(workdir, &self.user)
+                    .and_then(|_| chown_recursive(&self.home, &self.user))
+                    .map_err(|e| format!("Could not change owner of work dir: {}", e))
+            },
+            Switch::Helper(helper) => {
+                let owner = format!("{}:", self.user.name);
+                self.helper_chown(helper, &owner)
+                    .map_err(|e| format!("Could not change owner of work dir: {}", e))
+            },
+            Switch::Unchanged => Ok(()),
+        }
+    }
+
+    /// Builds an expression that runs the command as the build user
+    ///
+    /// The helper usually resets the environment, so the variables are
+    /// passed through a pipe on stdin. The command itself gets an empty
+    /// stdin then.
+    pub fn expression(&self, command: &Command, vars: &HashMap<String, String>)
+        -> Expression
+    {
+        let home = self.home.to_string_lossy().to_string();
+
+        match &self.switch {
+            Switch::Helper(helper) => {
+                cmd(helper, self.helper_args(command)).stdin_bytes(env_input(vars))
+            },
+            Switch::Direct | Switch::Unchanged => {
+                let expression = cmd(&command.command, &command.args)
+                    .env("HOME", home)
+                    .env("USER", &self.user.name)
+                    .env("LOGNAME", &self.user.name);
+
+                self.switch_user(expression)
+            },
+        }
+    }
+
+    /// Whether commands are started through `run_as_helper`
+    pub fn uses_helper(&self) -> bool {
+        matches!(self.switch, Switch::Helper(_))
+    }
+
+    /// Makes the expression run with the ids of the build user, if
+    /// Cinderella can change them itself
+    pub fn switch_user(&self, expression: Expression) -> Expression {
+        let (uid, gid) = (self.user.uid, self.user.gid);
+
+        if self.switch == Switch::Direct {
+            // when changing the uid as root, std also drops all
+            // supplementary groups
+            expression.before_spawn(move |cmd| {
+                cmd.gid(gid);
+                cmd.uid(uid);
+                Ok(())
+            })
+        } else {
+            expression
+        }
+    }
+
+    fn helper_chown(&self, helper: &str, owner: &str) -> io::Result<()> {
+        cmd(helper, &["-n", "chown", "-R", owner,
+                      &self.workdir.to_string_lossy(),
+                      &self.home.to_string_lossy()]).run()
+            .map(|_| ())
+    }
+
+    fn helper_args(&self, command: &Command) -> Vec<String> {
+        let mut args = vec![
+            String::from("-n"),
+            String::from("-u"),
+            self.user.name.clone(),
+            String::from("--"),
+            String::from("env"),
+            String::from("-i"),
+            format!("HOME={}", self.home.to_string_lossy()),
+            format!("USER={}", self.user.name),
+            format!("LOGNAME={}", self.user.name),
+            String::from("bash"),
+            String::from("-c"),
+            String::from(ENV_LOADER),
+            String::from("cinderella-env"),
+            command.command.clone(),
+        ];
+        args.extend(command.args.iter().cloned());
+
+        args
+    }
+}
+
+/// The variables for `ENV_LOADER`, without the ones that describe the
+/// build user
+fn env_input(vars: &HashMap<String, String>) -> Vec<u8> {
+    let mut input = Vec::new();
+
+    for (name, value) in vars {
+        if !["HOME", "USER", "LOGNAME"].contains(&name.as_str()) {
+            input.extend(format!("{}={}", name, value).into_bytes());
+            input.push(0);
+        }
+    }
+
+    input
+}
+
+impl Drop for RunAs {
+    fn drop(&mut self) {
+        // without root permissions Cinderella can only delete the work dir
+        // if it owns it again
+        if let Switch::Helper(helper) = &self.switch {
+            let owner = format!("{}:", unsafe { libc::geteuid() });
+            if let Err(e) = self.helper_chown(helper, &owner) {
+                eprintln!("Could not take back ownership of work dir: {}", e);
+            }
+        }
+
+        if let Err(e) = fs::remove_dir_all(&self.home) {
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("Could not delete build home {:?}: {}", self.home, e);
+            }
+        }
+    }
+}
+
+fn chown_recursive(path: &Path, user: &User) -> io::Result<()> {
+    lchown(path, Some(user.uid), Some(user.gid))?;
+
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_recursive(&entry?.path(), user)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_user() {
+        let root = User::lookup("root").unwrap();
+
+        assert_eq!(root.uid, 0);
+        assert_eq!(root.gid, 0);
+        assert!(User::lookup("cinderella-user-that-does-not-exist").is_err());
+    }
+
+    #[test]
+    fn test_helper_args() {
+        let run_as = RunAs {
+            user: User {
+                name: String::from("build"),
+                uid: 1500,
+                gid: 1500,
+            },
+            switch: Switch::Helper(String::from("sudo")),
+            workdir: PathBuf::from("/tmp/cinderella/abc"),
+            home: PathBuf::from("/tmp/cinderella/abc-home"),
+        };
+        // the helper must not be called when dropping in the test
+        let run_as = std::mem::ManuallyDrop::new(run_as);
+        let command = Command {
+            command: String::from("cargo"),
+            args: vec![String::from("test")],
+        };
+
+        assert_eq!(run_as.helper_args(&command), vec![
+            "-n", "-u", "build", "--", "env", "-i",
+            "HOME=/tmp/cinderella/abc-home", "USER=build", "LOGNAME=build",
+            "bash", "-c", ENV_LOADER, "cinderella-env", "cargo", "test",
+        ]);
+    }
+
+    #[test]
+    fn test_helper_environment_is_passed_on_stdin() {
+        let run_as = std::mem::ManuallyDrop::new(RunAs {
+            user: User {
+                name: String::from("build"),
+                uid: 1500,
+                gid: 1500,
+            },
+            switch: Switch::Helper(String::from("sudo")),
+            workdir: PathBuf::from("/tmp/cinderella/abc"),
+            home: PathBuf::from("/tmp/cinderella/abc-home"),
+        });
+        let command = Command {
+            command: String::from("sh"),
+            args: vec![String::from("-c"), String::from("echo \"$CI|$MULTILINE|$HOME\"")],
+        };
+
+        let mut vars = HashMap::new();
+        vars.insert(String::from("PATH"), String::from("/usr/bin:/bin"));
+        vars.insert(String::from("HOME"), String::from("/root"));
+        vars.insert(String::from("CI"), String::from("true"));
+        vars.insert(String::from("MULTILINE"), String::from("a\nb"));
+
+        // everything after `--` is what the helper executes as the user
+        let args = run_as.helper_args(&command);
+        let user_args = &args[args.iter().position(|arg| arg == "--").unwrap() + 1..];
+        assert!(!user_args.iter().any(|arg| arg.contains("true")));
+
+        let output = cmd(&user_args[0], &user_args[1..])
+            .stdin_bytes(env_input(&vars))
+            .read()
+            .unwrap();
+        assert_eq!(output, "true|a\nb|/tmp/cinderella/abc-home");
+    }
+
+    #[test]
+    fn test_unchanged_user_sets_fresh_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let workdir = dir.path().join("work");
+        fs::create_dir(&workdir).unwrap();
+
+        let current = unsafe { libc::geteuid() };
+        let run_as = RunAs {
+            user: User {
+                name: String::from("current"),
+                uid: current,
+                gid: unsafe { libc::getegid() },
+            },
+            switch: Switch::Unchanged,
+            workdir: workdir.clone(),
+            home: dir.path().join("work-home"),
+        };
+        run_as.prepare().unwrap();
+
+        let command = Command {
+            command: String::from("bash"),
+            args: vec![String::from("-c"), String::from("echo $HOME")],
+        };
+        let out = run_as.expression(&command, &HashMap::new()).read().unwrap();
+
+        assert_eq!(PathBuf::from(out), dir.path().join("work-home"));
+    }
+}
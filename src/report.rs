@@ -0,0 +1,228 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::config::ExecutionConfig;
+use crate::execution::{ExecutionContext, StepResult};
+use crate::history::StepRecord;
+use crate::pipeline::Pipeline;
+use crate::test_results::TestResults;
+
+/// The overall outcome of a build
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failed,
+    /// The build was cancelled, e.g. by a newer build
+    Cancelled,
+    /// The build was skipped because a newer build was waiting
+    Skipped,
+    /// No pipeline was selected or all conditions were false
+    NoExecution,
+    /// The build could not be started, e.g. because the clone failed
+    Error,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failed => "failed",
+            Outcome::Cancelled => "cancelled",
+            Outcome::Skipped => "skipped",
+            Outcome::NoExecution => "no pipeline executed",
+            Outcome::Error => "error",
+        }
+    }
+
+    /// The exit code of `cinderella run`
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Success | Outcome::Skipped | Outcome::NoExecution => 0,
+            Outcome::Failed => 1,
+            Outcome::Error => 2,
+            Outcome::Cancelled => 3,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PipelineStatus {
+    Success,
+    Failed,
+    Skipped,
+    /// The build failed in an earlier pipeline
+    NotReached,
+}
+
+/// Machine-readable description of a build, written by `run --report`
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub project: String,
+    pub repo_url: String,
+    pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub commit: Option<String>,
+    pub build_number: Option<u64>,
+    pub trigger: String,
+    pub outcome: Outcome,
+    /// Why the build could not be started, for the outcome `error`
+    pub error: Option<String>,
+    pub log_dir: Option<String>,
+    pub pipelines: Vec<PipelineReport>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PipelineReport {
+    pub name: String,
+    pub status: PipelineStatus,
+    pub skip_reason: Option<String>,
+    /// The `when` condition of the pipeline
+    pub when: Option<String>,
+    /// The value of the condition, `None` if it was not evaluated
+    pub when_result: Option<bool>,
+    pub steps: Vec<StepRecord>,
+    /// The parsed test results, if the pipeline declares test reports or
+    /// a test output format and was executed
+    pub tests: Option<TestResults>,
+}
+
+impl Report {
+    /// A report without pipelines
+    pub fn new(exec_config: &ExecutionConfig, outcome: Outcome) -> Report {
+        Report {
+            project: exec_config.name(),
+            repo_url: exec_config.repo_url.clone(),
+            branch: exec_config.branch.clone(),
+            tag: exec_config.tag.clone(),
+            commit: exec_config.commit.clone(),
+            build_number: None,
+            trigger: exec_config.trigger.as_str().to_string(),
+            outcome,
+            error: None,
+            log_dir: None,
+            pipelines: vec![],
+        }
+    }
+
+    /// A report for a build that could not be started
+    pub fn error(exec_config: &ExecutionConfig, msg: String) -> Report {
+        Report {
+            error: Some(msg),
+            ..Report::new(exec_config, Outcome::Error)
+        }
+    }
+
+    /// Adds all pipelines of the CI configuration with what happened to
+    /// them during `execute`
+    pub fn add_pipelines(
+        &mut self,
+        pipelines: &[Pipeline],
+        context: &ExecutionContext,
+        steps: &[StepResult])
+    {
+        let decisions = context.decisions.borrow();
+        let test_results = context.test_results.borrow();
+
+        for pipeline in pipelines {
+            let steps: Vec<StepRecord> = steps.iter()
+                .filter(|step| step.pipeline == pipeline.name)
+                .map(StepRecord::from)
+                .collect();
+            let decision = decisions.iter()
+                .find(|decision| decision.pipeline == pipeline.name);
+
+            let status = match decision {
+                None => PipelineStatus::NotReached,
+                Some(decision) if decision.skip_reason.is_some() => PipelineStatus::Skipped,
+                Some(_) if steps.iter().all(|step| step.success) => PipelineStatus::Success,
+                Some(_) => PipelineStatus::Failed,
+            };
+
+            self.pipelines.push(PipelineReport {
+                name: pipeline.name.clone(),
+                status,
+                skip_reason: decision.and_then(|decision| decision.skip_reason.clone()),
+                when: pipeline.when.clone(),
+                when_result: decision.and_then(|decision| decision.condition),
+                steps,
+                tests: test_results.get(&pipeline.name).cloned(),
+            });
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Could not serialize report: {}", e))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_json()? + "\n")
+            .map_err(|e| format!("Could not write report to {:?}: {}", path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::config::CinderellaConfig;
+    use crate::execution::{self, ExecutionResult};
+
+    fn pipeline(name: &str, command: &str, when: Option<&str>, manual: bool) -> Pipeline {
+        Pipeline {
+            name: String::from(name),
+            commands: vec![String::from(command)],
+            when: when.map(String::from),
+            manual,
+            ..Pipeline::default()
+        }
+    }
+
+    #[test]
+    fn test_report_of_failed_build() {
+        let exec_config = ExecutionConfig::for_branch("/srv/git/website.git", "master");
+        let pipelines = vec![
+            pipeline("docs", "echo docs", Some("\"%BRANCH\" == \"gh-pages\""), false),
+            pipeline("deploy", "echo deploy", None, true),
+            pipeline("lint", "echo lint", None, false),
+            pipeline("test", "bash -c \"exit 3\"", None, false),
+            pipeline("release", "echo release", None, false),
+        ];
+        let mut variables = HashMap::new();
+        variables.insert(String::from("branch"), String::from("master"));
+        let config = CinderellaConfig::default();
+        let context = ExecutionContext::new(&config);
+
+        let steps = match execution::execute(&pipelines, &variables, &context) {
+            ExecutionResult::Error(steps) => steps,
+            _ => panic!("build should fail"),
+        };
+        let mut report = Report::new(&exec_config, Outcome::Failed);
+        report.add_pipelines(&pipelines, &context, &steps);
+
+        let status: Vec<PipelineStatus> = report.pipelines.iter()
+            .map(|pipeline| pipeline.status)
+            .collect();
+        assert_eq!(status, vec![
+            PipelineStatus::Skipped, PipelineStatus::Skipped, PipelineStatus::Success,
+            PipelineStatus::Failed, PipelineStatus::NotReached,
+        ]);
+        assert_eq!(report.pipelines[0].when_result, Some(false));
+        assert_eq!(report.pipelines[1].skip_reason, Some(String::from("manual pipeline")));
+        // the steps of passed pipelines before the failed one are kept
+        assert_eq!(report.pipelines[2].steps.len(), 1);
+        assert_eq!(report.pipelines[2].steps[0].command, "echo lint");
+        assert_eq!(report.pipelines[2].steps[0].exit_code, Some(0));
+        assert_eq!(report.pipelines[3].steps[0].exit_code, Some(3));
+        assert_eq!(report.pipelines[3].tests, None);
+
+        let json = report.to_json().unwrap();
+        assert!(json.contains("\"outcome\": \"failed\""));
+        assert!(json.contains("\"status\": \"not_reached\""));
+        assert_eq!(Outcome::Failed.exit_code(), 1);
+    }
+}
@@ -0,0 +1,562 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Arc;
+
+use duct::cmd;
+
+use crate::config::Sandbox;
+use crate::execution::StepResult;
+use crate::executor::{check_limits, run_streamed, Command, Executor};
+use crate::limits::{self, Limits};
+use crate::logs::StepLog;
+use crate::privileges::RunAs;
+
+/// Mount point of the work dir inside the sandbox
+const SANDBOX_WORKDIR: &str = "/work";
+
+const SANDBOX_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Devices of the server that are bind-mounted into the `/dev` of the
+/// sandbox, if they exist
+const SANDBOX_DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom", "tty"];
+
+/// Symlinks in the `/dev` of the sandbox and their targets
+const SANDBOX_DEV_LINKS: &[(&str, &str)] = &[
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+];
+
+/// Executes commands in a throwaway root filesystem using Linux user and
+/// mount namespaces
+///
+/// The root filesystem is unpacked from the configured tarball before the
+/// first step and deleted after the last step. The work dir is mounted at
+/// `/work`. The commands run in their own PID namespace with a fresh
+/// `/proc` and get a minimal `/dev` with only the basic devices. Without
+/// network access they also run in their own network namespace, which only
+/// has a loopback device.
+pub struct SandboxExecutor<'a> {
+    sandbox: Sandbox,
+    workdir: PathBuf,
+    rootfs: PathBuf,
+    network: bool,
+    limits: Limits,
+    env: HashMap<String, String>,
+    run_as: Option<&'a RunAs>,
+}
+
+/// Everything the child process needs to set up the sandbox, prepared
+/// before forking
+struct Setup {
+    rootfs: CString,
+    network: bool,
+    /// Source, target and whether the bind mount is read-only
+    binds: Vec<(CString, CString, bool)>,
+    /// `/dev` in the root filesystem, gets a tmpfs
+    dev: CString,
+    /// Source and target of the devices bind-mounted into `dev`
+    devices: Vec<(CString, CString)>,
+    /// Target and path of the symlinks in `dev`
+    dev_links: Vec<(CString, CString)>,
+    /// `/proc` in the root filesystem, gets a procfs of the PID namespace
+    proc: CString,
+    workdir: CString,
+}
+
+impl<'a> SandboxExecutor<'a> {
+    pub fn new(
+        sandbox: &Sandbox,
+        workdir: &Path,
+        network: bool,
+        limits: Limits,
+        env: HashMap<String, String>,
+        run_as: Option<&'a RunAs>) -> Result<SandboxExecutor<'a>, String>
+    {
+        if matches!(run_as, Some(run_as) if run_as.uses_helper()) {
+            return Err(String::from(
+                "Sandboxes cannot be combined with run_as_helper"));
+        }
+
+        // next to the work dir, so that the build cannot delete it
+        let mut rootfs_name = workdir.file_name()
+            .ok_or_else(|| format!("Invalid work dir: {:?}", workdir))?
+            .to_os_string();
+        rootfs_name.push("-rootfs");
+
+        Ok(SandboxExecutor {
+            sandbox: sandbox.clone(),
+            workdir: workdir.to_path_buf(),
+            rootfs: workdir.with_file_name(rootfs_name),
+            network,
+            limits,
+            env,
+            run_as,
+        })
+    }
+
+    /// Path of a bind mount target inside the unpacked root filesystem
+    fn target(&self, path: &str) -> PathBuf {
+        self.rootfs.join(path.trim_start_matches('/'))
+    }
+
+    fn create_mount_points(&self) -> io::Result<()> {
+        for dir in &[SANDBOX_WORKDIR, "/dev", "/proc"] {
+            fs::create_dir_all(self.target(dir))?;
+        }
+
+        for bind in &self.sandbox.binds {
+            let target = self.target(bind);
+
+            if fs::metadata(bind)?.is_dir() {
+                fs::create_dir_all(&target)?;
+            } else if !target.exists() {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::File::create(&target)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn setup(&self) -> Result<Setup, String> {
+        let c_path = |path: &Path| CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| format!("Invalid path: {:?}", path));
+
+        let mut binds = vec![
+            (c_path(&self.workdir)?, c_path(&self.target(SANDBOX_WORKDIR))?, false),
+        ];
+        for bind in &self.sandbox.binds {
+            binds.push((c_path(Path::new(bind))?, c_path(&self.target(bind))?, true));
+        }
+
+        let dev = self.target("/dev");
+        let mut devices = Vec::new();
+        for device in SANDBOX_DEVICES {
+            let source = Path::new("/dev").join(device);
+            if source.exists() {
+                devices.push((c_path(&source)?, c_path(&dev.join(device))?));
+            }
+        }
+        let mut dev_links = Vec::new();
+        for (link, target) in SANDBOX_DEV_LINKS {
+            dev_links.push((c_path(Path::new(target))?, c_path(&dev.join(link))?));
+        }
+
+        Ok(Setup {
+            rootfs: c_path(&self.rootfs)?,
+            network: self.network,
+            binds,
+            dev: c_path(&dev)?,
+            devices,
+            dev_links,
+            proc: c_path(&self.target("/proc"))?,
+            workdir: c_path(Path::new(SANDBOX_WORKDIR))?,
+        })
+    }
+}
+
+impl<'a> Executor for SandboxExecutor<'a> {
+    fn prepare(&mut self) -> Result<(), String> {
+        println!("Unpacking sandbox root filesystem {}", self.sandbox.rootfs);
+
+        fs::create_dir_all(&self.rootfs)
+            .map_err(|e| format!("Could not create sandbox root: {}", e))?;
+        let rootfs = self.rootfs.to_string_lossy().to_string();
+        cmd("tar", &["-xf", &self.sandbox.rootfs, "-C", &rootfs]).run()
+            .map_err(|e| format!("Could not unpack sandbox root filesystem: {}", e))?;
+        self.create_mount_points()
+            .map_err(|e| format!("Could not create mount points in sandbox: {}", e))?;
+
+        Ok(())
+    }
+
+    fn execute(&self, command: &Command, log: &mut StepLog) -> StepResult {
+        let setup = match self.setup() {
+            Ok(setup) => Arc::new(setup),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                return StepResult::not_executed(command.command_string(), msg);
+            },
+        };
+
+        // the user is root inside the sandbox and the paths of the server
+        // are usually not available
+        let mut expression = cmd(&command.command, &command.args)
+            .env("HOME", "/root")
+            .env("USER", "root")
+            .env("LOGNAME", "root")
+            .env("PATH", SANDBOX_PATH)
+            .env("TMPDIR", "/tmp")
+            .env("CINDERELLA_WORKDIR", SANDBOX_WORKDIR)
+            .full_env(&self.env);
+
+        // std switches to the build user before calling the hook, so the
+        // namespaces belong to the build user
+        if let Some(run_as) = self.run_as {
+            expression = run_as.switch_user(expression);
+        }
+        let expression = expression.before_spawn(move |cmd| {
+            let setup = Arc::clone(&setup);
+            unsafe {
+                cmd.pre_exec(move || enter(&setup));
+            }
+            Ok(())
+        });
+
+        let oom_kills = limits::oom_kills();
+        let result = run_streamed(self.limits.apply(expression), command.command_string(), log);
+        check_limits(result, &self.limits, oom_kills)
+    }
+
+    fn cleanup(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.rootfs) {
+            eprintln!("Could not delete sandbox root {:?}: {}", self.rootfs, e);
+        }
+    }
+}
+
+/// Moves the current process into the sandbox
+///
+/// Called in the forked child right before `exec`, so it must not allocate.
+/// Only children enter a new PID namespace, so the process forks once more:
+/// the new child becomes PID 1 of the sandbox and executes the command,
+/// the current process waits for it and exits the same way.
+fn enter(setup: &Setup) -> io::Result<()> {
+    let uid = unsafe { libc::geteuid() };
+    let gid = unsafe { libc::getegid() };
+
+    let mut flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+    if !setup.network {
+        flags |= libc::CLONE_NEWNET;
+    }
+    check(unsafe { libc::unshare(flags) })?;
+
+    // the build user becomes root inside the sandbox
+    write_file(b"/proc/self/setgroups\0", b"deny")?;
+    write_id_map(b"/proc/self/uid_map\0", uid)?;
+    write_id_map(b"/proc/self/gid_map\0", gid)?;
+
+    // do not propagate the mounts of the sandbox to the server
+    check(unsafe {
+        libc::mount(ptr::null(), b"/\0".as_ptr() as *const libc::c_char,
+                    ptr::null(), libc::MS_REC | libc::MS_PRIVATE, ptr::null())
+    })?;
+
+    for (source, target, read_only) in &setup.binds {
+        check(unsafe {
+            libc::mount(source.as_ptr(), target.as_ptr(), ptr::null(),
+                        libc::MS_BIND | libc::MS_REC, ptr::null())
+        })?;
+
+        if *read_only {
+            remount_read_only(target)?;
+        }
+    }
+
+    create_dev(setup)?;
+
+    if !setup.network {
+        loopback_up()?;
+    }
+
+    let pid = unsafe { libc::fork() };
+    check(pid)?;
+    if pid != 0 {
+        wait_and_exit(pid);
+    }
+
+    // do not outlive the waiting parent, e.g. when the build is cancelled
+    check(unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) })?;
+
+    check(unsafe {
+        libc::mount(b"proc\0".as_ptr() as *const libc::c_char, setup.proc.as_ptr(),
+                    b"proc\0".as_ptr() as *const libc::c_char,
+                    libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC, ptr::null())
+    })?;
+
+    check(unsafe { libc::chroot(setup.rootfs.as_ptr()) })?;
+    check(unsafe { libc::chdir(setup.workdir.as_ptr()) })?;
+
+    Ok(())
+}
+
+/// Mounts a tmpfs on the `/dev` of the root filesystem with only the basic
+/// devices of the server
+///
+/// New device nodes cannot be created in a user namespace, so the devices
+/// are bind-mounted onto empty files.
+fn create_dev(setup: &Setup) -> io::Result<()> {
+    check(unsafe {
+        libc::mount(b"tmpfs\0".as_ptr() as *const libc::c_char, setup.dev.as_ptr(),
+                    b"tmpfs\0".as_ptr() as *const libc::c_char,
+                    libc::MS_NOSUID | libc::MS_NOEXEC,
+                    b"mode=755\0".as_ptr() as *const libc::c_void)
+    })?;
+
+    for (source, target) in &setup.devices {
+        let fd = unsafe {
+            libc::open(target.as_ptr(), libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC, 0o666)
+        };
+        check(fd)?;
+        unsafe { libc::close(fd) };
+
+        check(unsafe {
+            libc::mount(source.as_ptr(), target.as_ptr(), ptr::null(),
+                        libc::MS_BIND, ptr::null())
+        })?;
+    }
+
+    for (target, link) in &setup.dev_links {
+        check(unsafe { libc::symlink(target.as_ptr(), link.as_ptr()) })?;
+    }
+
+    Ok(())
+}
+
+/// Waits for PID 1 of the sandbox and exits with its status, a command
+/// that was killed by a signal kills this process with the same signal
+fn wait_and_exit(pid: libc::pid_t) -> ! {
+    // the command inherited everything it needs, in particular the pipe
+    // through which std learns that `exec` succeeded must be closed here
+    let mut rlimit: libc::rlimit = unsafe { std::mem::zeroed() };
+    let max_fd = match unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) } {
+        0 if rlimit.rlim_cur < 65536 => rlimit.rlim_cur as libc::c_int,
+        _ => 65536,
+    };
+    for fd in 0..max_fd {
+        unsafe { libc::close(fd) };
+    }
+
+    let mut status = 0;
+    while unsafe { libc::waitpid(pid, &mut status, 0) } == -1 {
+        if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            unsafe { libc::_exit(127) };
+        }
+    }
+
+    unsafe {
+        if libc::WIFSIGNALED(status) {
+            let signal = libc::WTERMSIG(status);
+            libc::signal(signal, libc::SIG_DFL);
+            libc::kill(libc::getpid(), signal);
+            libc::_exit(128 + signal);
+        }
+        libc::_exit(libc::WEXITSTATUS(status))
+    }
+}
+
+fn check(code: libc::c_int) -> io::Result<()> {
+    if code == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn write_file(path: &[u8], content: &[u8]) -> io::Result<()> {
+    let fd = unsafe {
+        libc::open(path.as_ptr() as *const libc::c_char, libc::O_WRONLY)
+    };
+    check(fd)?;
+
+    let written = unsafe {
+        libc::write(fd, content.as_ptr() as *const libc::c_void, content.len())
+    };
+    let error = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+
+    if written == content.len() as isize {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
+
+/// Maps id 0 in the sandbox to the given id outside of it
+fn write_id_map(path: &[u8], id: u32) -> io::Result<()> {
+    write_file(path, &id_map_line(id))
+}
+
+/// Formats "0 <id> 1" without allocating, leading zeros are fine
+fn id_map_line(id: u32) -> [u8; 14] {
+    let mut line = *b"0 0000000000 1";
+    let mut rest = id;
+    for digit in line[2..12].iter_mut().rev() {
+        *digit = b'0' + (rest % 10) as u8;
+        rest /= 10;
+    }
+
+    line
+}
+
+/// Makes a bind mount read-only, keeping the flags that cannot be removed
+/// in a user namespace
+fn remount_read_only(target: &CString) -> io::Result<()> {
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    check(unsafe { libc::statvfs(target.as_ptr(), &mut stat) })?;
+
+    let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+    for (st_flag, ms_flag) in &[
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & st_flag != 0 {
+            flags |= ms_flag;
+        }
+    }
+
+    check(unsafe {
+        libc::mount(ptr::null(), target.as_ptr(), ptr::null(), flags, ptr::null())
+    })
+}
+
+/// Enables the loopback device of a new network namespace, so that builds
+/// can still use servers on localhost
+fn loopback_up() -> io::Result<()> {
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    check(socket)?;
+
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dest, src) in request.ifr_name.iter_mut().zip(b"lo") {
+        *dest = *src as libc::c_char;
+    }
+
+    let result = check(unsafe {
+        libc::ioctl(socket, libc::SIOCGIFFLAGS as _, &mut request)
+    }).and_then(|_| {
+        unsafe { request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+        check(unsafe { libc::ioctl(socket, libc::SIOCSIFFLAGS as _, &request) })
+    });
+    unsafe { libc::close(socket) };
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::Failure;
+
+    fn command(script: &str) -> Command {
+        Command {
+            command: String::from("sh"),
+            args: vec![String::from("-c"), String::from(script)],
+        }
+    }
+
+    fn output(result: StepResult) -> String {
+        match result.failure {
+            None => result.output,
+            Some(failure) => {
+                panic!("command failed with {}: {}", failure, result.output)
+            },
+        }
+    }
+
+    #[test]
+    fn test_id_map_line() {
+        assert_eq!(&id_map_line(0), b"0 0000000000 1");
+        assert_eq!(&id_map_line(1500), b"0 0000001500 1");
+        assert_eq!(&id_map_line(u32::MAX), b"0 4294967295 1");
+    }
+
+    /// Whether this process may set up a sandbox, unprivileged user
+    /// namespaces are often restricted by sysctl or AppArmor
+    fn user_namespaces_available() -> bool {
+        let uid = unsafe { libc::geteuid() };
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let entered = check(unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) })
+                .and_then(|_| write_file(b"/proc/self/setgroups\0", b"deny"))
+                .and_then(|_| write_id_map(b"/proc/self/uid_map\0", uid))
+                .and_then(|_| check(unsafe {
+                    libc::mount(ptr::null(), b"/\0".as_ptr() as *const libc::c_char,
+                                ptr::null(), libc::MS_REC | libc::MS_PRIVATE, ptr::null())
+                }));
+            unsafe { libc::_exit(if entered.is_ok() { 0 } else { 1 }) };
+        }
+
+        let mut status = 0;
+        pid > 0
+            && unsafe { libc::waitpid(pid, &mut status, 0) } == pid
+            && libc::WIFEXITED(status)
+            && libc::WEXITSTATUS(status) == 0
+    }
+
+    #[test]
+    fn test_executes_in_unpacked_rootfs() {
+        if !user_namespaces_available() {
+            eprintln!("Skipping sandbox test, user namespaces are not available");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+
+        // a minimal root filesystem, the programs come from the server
+        let tree = dir.path().join("tree");
+        fs::create_dir_all(tree.join("etc")).unwrap();
+        fs::write(tree.join("etc/sandbox-marker"), "inside\n").unwrap();
+        let tarball = dir.path().join("rootfs.tar");
+        cmd!("tar", "-cf", &tarball, "-C", &tree, ".").run().unwrap();
+
+        let workdir = dir.path().join("work");
+        fs::create_dir(&workdir).unwrap();
+        fs::write(workdir.join("Cargo.toml"), "").unwrap();
+
+        let binds = ["/usr", "/bin", "/lib", "/lib64"].iter()
+            .filter(|path| Path::new(path).exists())
+            .map(|path| path.to_string())
+            .collect();
+        let sandbox = Sandbox {
+            rootfs: tarball.to_string_lossy().to_string(),
+            binds,
+        };
+
+        let mut executor = SandboxExecutor::new(
+            &sandbox, &workdir, false, Limits::default(), HashMap::new(), None)
+            .unwrap();
+        executor.prepare().unwrap();
+
+        let out = output(executor.execute(&command(
+            "cat /etc/sandbox-marker; pwd; ls; id -u; echo $$; \
+             tail -n +3 /proc/net/dev | cut -d: -f1 | tr -d ' '"), &mut StepLog::disabled()));
+        assert_eq!(out, "inside\n/work\nCargo.toml\n0\n1\nlo\n");
+
+        // only the processes of the sandbox and the basic devices are visible
+        let out = output(executor.execute(
+            &command("cat /proc/1/comm; ls /dev; echo hidden > /dev/null"),
+            &mut StepLog::disabled()));
+        let mut devices: Vec<&str> = SANDBOX_DEVICES.iter()
+            .filter(|device| Path::new("/dev").join(device).exists())
+            .cloned()
+            .chain(SANDBOX_DEV_LINKS.iter().map(|(link, _)| *link))
+            .collect();
+        devices.sort();
+        assert_eq!(out, format!("sh\n{}\n", devices.join("\n")));
+
+        // the binds are read-only
+        let result = executor.execute(
+            &command("touch /usr/sandbox-test"), &mut StepLog::disabled());
+        assert_eq!(result.failure, Some(Failure::ExitCode(1)));
+
+        // the work dir is writable
+        output(executor.execute(&command("touch built"), &mut StepLog::disabled()));
+        assert!(workdir.join("built").exists());
+
+        executor.cleanup();
+        assert!(!dir.path().join("work-rootfs").exists());
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time;
+
+use chrono::{Duration, Local, NaiveDateTime, Timelike};
+use duct::{cmd, Handle};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{CinderellaConfig, ExecutionConfig, Project, Trigger};
+use crate::cron::Schedule;
+use crate::daemon::Job;
+use crate::pipeline;
+use crate::vcs;
+
+// Pipeline definitions are re-read from the repository after this time, so
+// that changed schedules become active without a restart
+const REFRESH_MINUTES: i64 = 15;
+
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+struct ScheduledPipeline {
+    name: String,
+    schedule: Schedule,
+}
+
+/// A scheduled build that was started and not reaped yet
+struct RunningBuild {
+    name: String,
+    handle: Handle,
+}
+
+struct ProjectSchedules {
+    pipelines: Vec<ScheduledPipeline>,
+    loaded_at: NaiveDateTime,
+}
+
+/// Persisted state of the scheduler, used to detect runs that were missed
+/// while the scheduler was not running
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    last_check: HashMap<String, String>,
+}
+
+impl State {
+    fn load(path: &Path) -> State {
+        match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
+                eprintln!("Scheduler state invalid, starting fresh: {}", e);
+                State::default()
+            }),
+            _ => State::default(),
+        }
+    }
+
+    fn save(&self, path: &Path) {
+        let contents = toml::to_string(self)
+            .expect("Scheduler state cannot be serialized");
+
+        if let Err(e) = fs::write(path, contents) {
+            eprintln!("Could not write scheduler state to {:?}: {}", path, e);
+        }
+    }
+
+    fn last_check(&self, key: &str) -> Option<NaiveDateTime> {
+        self.last_check.get(key)
+            .and_then(|t| NaiveDateTime::parse_from_str(t, TIME_FORMAT).ok())
+    }
+
+    fn set_last_check(&mut self, key: &str, time: &NaiveDateTime) {
+        self.last_check.insert(
+            key.to_string(), time.format(TIME_FORMAT).to_string());
+    }
+}
+
+fn project_key(project: &Project) -> String {
+    match &project.branch {
+        Some(branch) => format!("{}#{}", project.repo_url, branch),
+        None => project.repo_url.clone(),
+    }
+}
+
+/// The configuration of a scheduled build of the project
+fn exec_config(project: &Project, pipelines: Vec<String>) -> ExecutionConfig {
+    ExecutionConfig {
+        repo_url: project.repo_url.clone(),
+        branch: project.branch.clone(),
+        tag: None,
+        cinderella_filepath: project.cinderella_filepath.clone(),
+        pipelines,
+        skip_pipelines: vec![],
+        trigger: Trigger::Schedule,
+        commit: None,
+        rerun_of: None,
+    }
+}
+
+/// Reads the CI configuration that a build of the project would use
+///
+/// Builds run in their work dir, so relative paths are read from the
+/// repository and absolute paths from the server.
+fn read_config(project: &Project) -> Result<Option<String>, String> {
+    let path = exec_config(project, vec![]).cinderella_file(&PathBuf::new());
+
+    if path.is_absolute() {
+        return match fs::read_to_string(&path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Could not read {:?}: {}", path, e)),
+        };
+    }
+
+    let repo = vcs::GitSource {
+        src: project.repo_url.clone(),
+    };
+    let tempdir = crate::random_dir("/tmp/cinderella");
+
+    // only the CI configuration is needed, not a working copy
+    repo.read_file(project.branch.as_deref(), &path.to_string_lossy(), &tempdir)
+}
+
+fn load_schedules(project: &Project, now: NaiveDateTime) -> ProjectSchedules {
+    let mut pipelines = Vec::new();
+
+    match read_config(project) {
+        Ok(contents) => {
+            let loaded = contents.map(|contents| pipeline::parse_pipelines(&contents))
+                .transpose()
+                .unwrap_or_else(|msg| {
+                    eprintln!("Could not load pipelines of {}: {}", project.repo_url, msg);
+                    None
+                });
+            for p in loaded.unwrap_or_default() {
+                if let Some(expression) = &p.schedule {
+                    match expression.parse::<Schedule>() {
+                        Ok(schedule) => pipelines.push(ScheduledPipeline {
+                            name: p.name.clone(),
+                            schedule,
+                        }),
+                        Err(msg) => eprintln!(
+                            "Invalid schedule for pipeline {} in {}: {}",
+                            p.name, project.repo_url, msg),
+                    }
+                }
+            }
+        },
+        Err(msg) => eprintln!("{}", msg),
+    }
+
+    ProjectSchedules {
+        pipelines,
+        loaded_at: now,
+    }
+}
+
+/// Returns the names of all pipelines that were due in `(last, now]`
+fn due_pipelines(
+    pipelines: &[ScheduledPipeline],
+    last: &NaiveDateTime,
+    now: &NaiveDateTime) -> Vec<String>
+{
+    pipelines.iter()
+        .filter(|p| p.schedule.due_between(last, now))
+        .map(|p| p.name.clone())
+        .collect()
+}
+
+/// Starts a scheduled build with `cinderella run` in a child process
+///
+/// Like in the daemon, the build must not share the process with the
+/// scheduler: it changes the current directory, and its cancellation and
+/// masked secrets are global to the process. The scheduler does not wait
+/// for the build, so that a long build does not delay the schedules of the
+/// other projects.
+fn trigger_build(project: &Project, pipelines: Vec<String>) -> Option<RunningBuild> {
+    let exec_config = exec_config(project, pipelines);
+
+    let mut args = Job::new(&exec_config, 0).run_args();
+    args.splice(1..1, vec![String::from("--trigger"), String::from(Trigger::Schedule.as_str())]);
+
+    let result = std::env::current_exe()
+        .and_then(|executable| cmd(executable, args).stdin_null().unchecked().start());
+    match result {
+        Ok(handle) => Some(RunningBuild { name: exec_config.name(), handle }),
+        Err(e) => {
+            eprintln!("Could not start scheduled build of {}: {}", exec_config.name(), e);
+            None
+        },
+    }
+}
+
+/// Removes the finished builds from the running ones
+fn reap(running: &mut Vec<RunningBuild>) {
+    running.retain(|build| {
+        match build.handle.try_wait() {
+            Ok(Some(output)) => {
+                println!("Scheduled build of {} finished ({})", build.name, output.status);
+                false
+            },
+            Ok(None) => true,
+            Err(e) => {
+                eprintln!("Could not wait for scheduled build of {}: {}", build.name, e);
+                false
+            },
+        }
+    });
+}
+
+fn current_minute() -> NaiveDateTime {
+    let now = Local::now().naive_local();
+    now.with_second(0).and_then(|t| t.with_nanosecond(0)).unwrap_or(now)
+}
+
+pub fn run(cinderella_config: &CinderellaConfig, state_file: &Path) {
+    let mut state = State::load(state_file);
+    let mut schedules: HashMap<String, ProjectSchedules> = HashMap::new();
+    let mut running: Vec<RunningBuild> = Vec::new();
+
+    println!("Scheduler started for {} projects",
+             cinderella_config.projects.len());
+
+    loop {
+        let now = current_minute();
+        reap(&mut running);
+
+        for project in &cinderella_config.projects {
+            let key = project_key(project);
+
+            let needs_refresh = match schedules.get(&key) {
+                Some(s) => now - s.loaded_at >= Duration::minutes(REFRESH_MINUTES),
+                None => true,
+            };
+            if needs_refresh {
+                schedules.insert(key.clone(), load_schedules(project, now));
+            }
+
+            let last = state.last_check(&key)
+                .unwrap_or_else(|| now - Duration::minutes(1));
+            // all runs missed during a downtime are combined into one build
+            let due = due_pipelines(&schedules[&key].pipelines, &last, &now);
+
+            if !due.is_empty() {
+                if now - last > Duration::minutes(1) {
+                    println!("Catching up missed scheduled runs since {}", last);
+                }
+                println!("Triggering scheduled pipelines {} of {}",
+                         due.join(", "), project.repo_url);
+                running.extend(trigger_build(project, due));
+            }
+
+            state.set_last_check(&key, &now);
+            state.save(state_file);
+        }
+
+        // sleep until the start of the next minute
+        let elapsed = Local::now().naive_local() - now;
+        let remaining = Duration::minutes(1) - elapsed;
+        if let Ok(remaining) = remaining.to_std() {
+            thread::sleep(remaining);
+        } else {
+            thread::sleep(time::Duration::from_secs(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn time(h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2019, 10, 1).unwrap()
+            .and_hms_opt(h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn test_due_pipelines() {
+        let pipelines = vec![
+            ScheduledPipeline {
+                name: String::from("nightly"),
+                schedule: "0 3 * * *".parse().unwrap(),
+            },
+            ScheduledPipeline {
+                name: String::from("hourly"),
+                schedule: "30 * * * *".parse().unwrap(),
+            },
+        ];
+
+        assert_eq!(due_pipelines(&pipelines, &time(2, 59), &time(3, 0)),
+                   vec!["nightly"]);
+        assert!(due_pipelines(&pipelines, &time(3, 0), &time(3, 1)).is_empty());
+
+        // after a downtime from 2:00 to 4:00 both have to be caught up
+        assert_eq!(due_pipelines(&pipelines, &time(2, 0), &time(4, 0)),
+                   vec!["nightly", "hourly"]);
+    }
+
+    #[test]
+    fn test_config_is_read_from_configured_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        fs::create_dir(&repo).unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(&["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(&repo)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q", "-b", "master"]);
+        fs::create_dir(repo.join("ci")).unwrap();
+        fs::write(repo.join(".cinderella.toml"), "[default]\n").unwrap();
+        fs::write(repo.join("ci/nightly.toml"), "[nightly]\n").unwrap();
+        git(&["add", "-A"]);
+        git(&["commit", "-q", "-m", "initial"]);
+        let server_file = dir.path().join("server.toml");
+        fs::write(&server_file, "[server]\n").unwrap();
+
+        let mut project = Project {
+            repo_url: repo.to_string_lossy().to_string(),
+            branch: None,
+            cinderella_filepath: None,
+            run_as: None,
+            profile: None,
+            concurrency: None,
+            priority: None,
+        };
+        assert_eq!(read_config(&project).unwrap(), Some(String::from("[default]\n")));
+
+        project.cinderella_filepath = Some(String::from("ci/nightly.toml"));
+        assert_eq!(read_config(&project).unwrap(), Some(String::from("[nightly]\n")));
+
+        project.cinderella_filepath = Some(server_file.to_string_lossy().to_string());
+        assert_eq!(read_config(&project).unwrap(), Some(String::from("[server]\n")));
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.toml");
+
+        let mut state = State::load(&path);
+        assert!(state.last_check("repo#master").is_none());
+
+        state.set_last_check("repo#master", &time(3, 0));
+        state.save(&path);
+
+        let state = State::load(&path);
+        assert_eq!(state.last_check("repo#master"), Some(time(3, 0)));
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// The two kinds of placeholders that can be used in templated fields
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Source {
+    /// `%NAME`, a Cinderella variable
+    Variable,
+    /// `$NAME`, an environment variable
+    Environment,
+}
+
+impl Source {
+    fn sigil(self) -> char {
+        match self {
+            Source::Variable => '%',
+            Source::Environment => '$',
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Placeholder {
+    pub source: Source,
+    pub name: String,
+    pub default: Option<String>,
+    /// The placeholder as written in the template
+    pub raw: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Token {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// Splits a template into text and placeholders in a single pass
+///
+/// Supported forms are `%NAME`, `%{NAME}` and `%{NAME:-default}` (and the
+/// same with `$`). `%%` and `$$` produce a literal `%` or `$`. A sigil that
+/// is not followed by a name is kept as text.
+pub fn tokenize(template: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let source = match c {
+            '%' => Source::Variable,
+            '$' => Source::Environment,
+            _ => {
+                text.push(c);
+                continue;
+            },
+        };
+
+        match chars.peek() {
+            Some(next) if *next == c => {
+                chars.next();
+                text.push(c);
+            },
+            Some('{') => {
+                chars.next();
+                let placeholder = parse_delimited(source, &mut chars)
+                    .map_err(|msg| format!("{} in: {}", msg, template))?;
+
+                push_text(&mut tokens, &mut text);
+                tokens.push(Token::Placeholder(placeholder));
+            },
+            Some(next) if is_name_char(*next) => {
+                let name = take_name(&mut chars);
+
+                push_text(&mut tokens, &mut text);
+                tokens.push(Token::Placeholder(Placeholder {
+                    source,
+                    raw: format!("{}{}", c, name),
+                    name,
+                    default: None,
+                }));
+            },
+            _ => text.push(c),
+        }
+    }
+
+    push_text(&mut tokens, &mut text);
+
+    Ok(tokens)
+}
+
+/// Replaces all placeholders in a template
+///
+/// Unknown Cinderella variables in braces and without a default are an
+/// error. Other unknown placeholders only give a warning and are kept as
+/// written, because they might be meant for the command itself, e.g.
+/// `date +%Y` or a shell called by the command.
+pub fn render(
+    template: &str,
+    variables: &HashMap<String, String>,
+    environment: &HashMap<String, String>) -> Result<String, String>
+{
+    let mut result = String::new();
+
+    for token in tokenize(template)? {
+        match token {
+            Token::Text(text) => result.push_str(&text),
+            Token::Placeholder(placeholder) => {
+                match resolve(&placeholder, variables, environment) {
+                    Some(value) => result.push_str(&value),
+                    None if placeholder.source == Source::Variable
+                        && placeholder.raw.starts_with("%{") =>
+                    {
+                        return Err(format!(
+                            "Unknown variable {} in: {}",
+                            placeholder.raw, template));
+                    },
+                    None if placeholder.source == Source::Variable => {
+                        eprintln!(
+                            "Warning: unknown variable {} is kept as written",
+                            placeholder.raw);
+                        result.push_str(&placeholder.raw);
+                    },
+                    None => {
+                        eprintln!(
+                            "Warning: environment variable ${} is not set",
+                            placeholder.name);
+                        result.push_str(&placeholder.raw);
+                    },
+                }
+            },
+        }
+    }
+
+    Ok(result)
+}
+
+/// Looks up the value of a placeholder, falling back to its default
+///
+/// Environment variables are looked up in the environment of the build
+/// commands, not in Cinderella's own environment.
+pub fn resolve(
+    placeholder: &Placeholder,
+    variables: &HashMap<String, String>,
+    environment: &HashMap<String, String>) -> Option<String>
+{
+    let value = match placeholder.source {
+        Source::Variable => lookup(variables, &placeholder.name).cloned(),
+        Source::Environment => environment.get(&placeholder.name).cloned(),
+    };
+
+    value.or_else(|| placeholder.default.clone())
+}
+
+/// Finds a variable by name, variable names are case insensitive
+pub fn lookup<'a>(variables: &'a HashMap<String, String>, name: &str)
+    -> Option<&'a String>
+{
+    let name = name.to_uppercase();
+
+    variables.iter()
+        .find(|(key, _)| key.to_uppercase() == name)
+        .map(|(_, value)| value)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn take_name(chars: &mut Peekable<Chars>) -> String {
+    let mut name = String::new();
+
+    while let Some(c) = chars.peek() {
+        if !is_name_char(*c) {
+            break;
+        }
+        name.push(*c);
+        chars.next();
+    }
+
+    name
+}
+
+fn parse_delimited(source: Source, chars: &mut Peekable<Chars>)
+    -> Result<Placeholder, String>
+{
+    let name = take_name(chars);
+    if name.is_empty() {
+        return Err(format!("Missing name after {}{{", source.sigil()));
+    }
+
+    let mut default = None;
+    if chars.peek() == Some(&':') {
+        chars.next();
+        if chars.next() != Some('-') {
+            return Err(format!(
+                "Expected :- after {}{{{}", source.sigil(), name));
+        }
+
+        let mut value = String::new();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => value.push(c),
+                None => return Err(format!(
+                    "Unterminated {}{{{}", source.sigil(), name)),
+            }
+        }
+
+        default = Some(value);
+    } else if chars.next() != Some('}') {
+        return Err(format!("Unterminated {}{{{}", source.sigil(), name));
+    }
+
+    let raw = match &default {
+        Some(value) => format!("{}{{{}:-{}}}", source.sigil(), name, value),
+        None => format!("{}{{{}}}", source.sigil(), name),
+    };
+
+    Ok(Placeholder {
+        source,
+        name,
+        default,
+        raw,
+    })
+}
+
+fn push_text(tokens: &mut Vec<Token>, text: &mut String) {
+    if !text.is_empty() {
+        tokens.push(Token::Text(text.clone()));
+        text.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_names_do_not_overlap() {
+        let vars = variables(&[("tag", "1.0"), ("tagline", "hello")]);
+
+        assert_eq!(render("%TAG %TAGLINE", &vars, &HashMap::new()).unwrap(), "1.0 hello");
+        assert_eq!(render("%{TAG}LINE", &vars, &HashMap::new()).unwrap(), "1.0LINE");
+    }
+
+    #[test]
+    fn test_escapes() {
+        let vars = variables(&[("tag", "1.0")]);
+
+        assert_eq!(render("100%% of %TAG", &vars, &HashMap::new()).unwrap(), "100% of 1.0");
+        assert_eq!(render("costs $$5", &vars, &HashMap::new()).unwrap(), "costs $5");
+        assert_eq!(render("%%TAG", &vars, &HashMap::new()).unwrap(), "%TAG");
+        assert_eq!(render("50% done", &vars, &HashMap::new()).unwrap(), "50% done");
+    }
+
+    #[test]
+    fn test_defaults() {
+        let vars = variables(&[("branch", "master")]);
+
+        assert_eq!(render("%{BRANCH:-dev}", &vars, &HashMap::new()).unwrap(), "master");
+        assert_eq!(render("%{TAG:-none}", &vars, &HashMap::new()).unwrap(), "none");
+        assert_eq!(render("%{TAG:-}", &vars, &HashMap::new()).unwrap(), "");
+    }
+
+    #[test]
+    fn test_unknown_variable_in_braces_is_an_error() {
+        let vars = variables(&[]);
+
+        assert!(render("echo %{MISSING}", &vars, &HashMap::new()).is_err());
+        assert!(render("echo %{BRANCH", &vars, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_unknown_variable_without_braces_is_kept() {
+        let vars = variables(&[("branch", "master")]);
+
+        assert_eq!(render("date +%Y-%m-%d", &vars, &HashMap::new()).unwrap(),
+                   "date +%Y-%m-%d");
+        assert_eq!(render("printf \"%s\" %BRANCH", &vars, &HashMap::new()).unwrap(),
+                   "printf \"%s\" master");
+    }
+
+    #[test]
+    fn test_environment_variables() {
+        let vars = variables(&[]);
+        let env = variables(&[("CINDERELLA_TEMPLATE_TEST", "env-value")]);
+
+        assert_eq!(render("$CINDERELLA_TEMPLATE_TEST", &vars, &env).unwrap(),
+                   "env-value");
+        assert_eq!(render("${CINDERELLA_TEMPLATE_TEST}DIR", &vars, &env).unwrap(),
+                   "env-valueDIR");
+        // unknown environment variables are kept for the shell
+        assert_eq!(render("$CINDERELLA_TEMPLATE_UNSET", &vars, &env).unwrap(),
+                   "$CINDERELLA_TEMPLATE_UNSET");
+        assert_eq!(render("${CINDERELLA_TEMPLATE_UNSET:-x}", &vars, &env).unwrap(),
+                   "x");
+        // Cinderella's own environment is not used
+        assert_eq!(render("${PATH:-none}", &vars, &env).unwrap(), "none");
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let tokens = tokenize("a %{B:-c} $D").unwrap();
+
+        assert_eq!(tokens, vec![
+            Token::Text(String::from("a ")),
+            Token::Placeholder(Placeholder {
+                source: Source::Variable,
+                name: String::from("B"),
+                default: Some(String::from("c")),
+                raw: String::from("%{B:-c}"),
+            }),
+            Token::Text(String::from(" ")),
+            Token::Placeholder(Placeholder {
+                source: Source::Environment,
+                name: String::from("D"),
+                default: None,
+                raw: String::from("$D"),
+            }),
+        ]);
+    }
+}
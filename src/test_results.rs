@@ -0,0 +1,362 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// Failure messages are cut after this many lines, the complete message
+/// is in the step log or the test report
+const MAX_MESSAGE_LINES: usize = 10;
+
+/// Formats of test runner output that can be parsed from the output of
+/// the commands of a pipeline
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TestOutput {
+    /// The test harness of Rust, e.g. `cargo test`
+    Libtest,
+    Pytest,
+}
+
+/// The results of the tests of a pipeline
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TestResults {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    #[serde(default)]
+    pub failures: Vec<TestFailure>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TestFailure {
+    pub name: String,
+    pub message: String,
+}
+
+impl TestResults {
+    pub fn merge(&mut self, other: TestResults) {
+        self.passed += other.passed;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+        self.failures.extend(other.failures);
+    }
+
+    pub fn total(&self) -> usize {
+        self.passed + self.failed + self.skipped
+    }
+
+    /// The counts of the tests, e.g. `12 passed, 1 failed, 2 skipped`
+    pub fn describe(&self) -> String {
+        format!("{} passed, {} failed, {} skipped", self.passed, self.failed, self.skipped)
+    }
+
+    /// Lists the failed tests with their messages, one indented block
+    /// per test
+    pub fn failure_summary(&self) -> String {
+        let mut summary = String::new();
+
+        for failure in &self.failures {
+            summary.push_str(&format!("{}\n", failure.name));
+            for line in failure.message.lines() {
+                summary.push_str(&format!("    {}\n", line));
+            }
+        }
+        if self.failed > self.failures.len() {
+            summary.push_str(&format!(
+                "[{} more failed tests, see the log]\n", self.failed - self.failures.len()));
+        }
+
+        summary
+    }
+
+    fn fail(&mut self, name: &str, message: &str) {
+        self.failed += 1;
+        self.failures.push(TestFailure {
+            name: name.to_string(),
+            message: shorten(message),
+        });
+    }
+}
+
+/// Parses the output of a test runner
+pub fn parse_output(format: TestOutput, output: &str) -> TestResults {
+    match format {
+        TestOutput::Libtest => parse_libtest(output),
+        TestOutput::Pytest => parse_pytest(output),
+    }
+}
+
+/// Parses all JUnit XML reports that match the glob patterns, relative
+/// to `dir`
+///
+/// Reports that cannot be read are reported and skipped, so that a
+/// broken report does not hide the results of the others.
+pub fn collect_reports(patterns: &[String], dir: &Path) -> TestResults {
+    let mut results = TestResults::default();
+
+    for pattern in patterns {
+        let full_pattern = dir.join(pattern).to_string_lossy().to_string();
+        let paths = match glob::glob(&full_pattern) {
+            Ok(paths) => paths,
+            Err(e) => {
+                eprintln!("Invalid test report pattern {}: {}", pattern, e);
+                continue;
+            },
+        };
+
+        let mut found = false;
+        for path in paths.filter_map(Result::ok) {
+            found = true;
+            let parsed = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|xml| parse_junit(&xml));
+
+            match parsed {
+                Ok(parsed) => results.merge(parsed),
+                Err(msg) => eprintln!("Could not read test report {:?}: {}", path, msg),
+            }
+        }
+        if !found {
+            eprintln!("Warning: no test reports match {}", pattern);
+        }
+    }
+
+    results
+}
+
+/// Parses a JUnit XML report, test cases with a `failure` or `error`
+/// element count as failed
+pub fn parse_junit(xml: &str) -> Result<TestResults, String> {
+    let doc = roxmltree::Document::parse(xml).map_err(|e| e.to_string())?;
+    let mut results = TestResults::default();
+
+    for testcase in doc.descendants().filter(|node| node.has_tag_name("testcase")) {
+        let name = match (testcase.attribute("classname"), testcase.attribute("name")) {
+            (Some(classname), Some(name)) if !classname.is_empty() => {
+                format!("{}.{}", classname, name)
+            },
+            (_, name) => name.unwrap_or_default().to_string(),
+        };
+
+        let failure = testcase.children()
+            .find(|child| child.has_tag_name("failure") || child.has_tag_name("error"));
+
+        if let Some(failure) = failure {
+            let message = match failure.attribute("message") {
+                Some(message) if !message.trim().is_empty() => message,
+                _ => failure.text().unwrap_or_default(),
+            };
+            results.fail(&name, message);
+        } else if testcase.children().any(|child| child.has_tag_name("skipped")) {
+            results.skipped += 1;
+        } else {
+            results.passed += 1;
+        }
+    }
+
+    Ok(results)
+}
+
+/// Parses the output of the Rust test harness
+///
+/// The messages of failed tests are taken from their captured output,
+/// which libtest prints after all tests have finished.
+pub fn parse_libtest(output: &str) -> TestResults {
+    let mut results = TestResults::default();
+    let mut failed = Vec::new();
+    let mut messages: Vec<(String, Vec<&str>)> = Vec::new();
+    let mut in_message = false;
+
+    for line in output.lines() {
+        if let Some(test) = line.strip_prefix("test ") {
+            if test.ends_with(" ... ok") {
+                results.passed += 1;
+            } else if let Some(name) = test.strip_suffix(" ... FAILED") {
+                failed.push(name);
+            } else if test.contains(" ... ignored") {
+                results.skipped += 1;
+            }
+            in_message = false;
+        } else if let Some(name) = line.strip_prefix("---- ")
+            .and_then(|header| header.strip_suffix(" stdout ----"))
+        {
+            messages.push((name.to_string(), Vec::new()));
+            in_message = true;
+        } else if line == "failures:" || line.starts_with("test result: ") {
+            in_message = false;
+        } else if in_message && !line.starts_with("note: run with `RUST_BACKTRACE") {
+            if let Some((_, lines)) = messages.last_mut() {
+                lines.push(line);
+            }
+        }
+    }
+
+    for name in failed {
+        let message = messages.iter()
+            .find(|(failed_name, _)| failed_name == name)
+            .map(|(_, lines)| lines.join("\n"))
+            .unwrap_or_default();
+        results.fail(name, &message);
+    }
+
+    results
+}
+
+/// Parses the output of pytest
+///
+/// The counts are taken from the final summary line and the failed tests
+/// from the short test summary, which pytest prints by default.
+pub fn parse_pytest(output: &str) -> TestResults {
+    let mut results = TestResults::default();
+    let mut failures = Vec::new();
+
+    for line in output.lines() {
+        if let Some(test) = line.strip_prefix("FAILED ").or_else(|| line.strip_prefix("ERROR ")) {
+            let mut parts = test.splitn(2, " - ");
+            let name = parts.next().unwrap_or_default().trim();
+            let message = parts.next().unwrap_or_default().trim();
+            failures.push(TestFailure { name: name.to_string(), message: shorten(message) });
+        } else if line.starts_with('=') && line.trim_end().ends_with('=') {
+            add_pytest_counts(&mut results, line.trim_matches(|c| c == '=' || c == ' '));
+        }
+    }
+
+    results.failures = failures;
+    results
+}
+
+/// Adds the counts of a pytest summary line like
+/// `1 failed, 2 passed, 1 skipped in 0.12s`
+fn add_pytest_counts(results: &mut TestResults, summary: &str) {
+    let counts = match summary.rfind(" in ") {
+        Some(pos) => &summary[..pos],
+        None => return,
+    };
+
+    for part in counts.split(", ") {
+        let mut words = part.split_whitespace();
+        let count = match words.next().and_then(|count| count.parse::<usize>().ok()) {
+            Some(count) => count,
+            None => continue,
+        };
+
+        match words.next() {
+            Some("passed") | Some("xpassed") => results.passed += count,
+            Some("failed") | Some("error") | Some("errors") => results.failed += count,
+            Some("skipped") | Some("xfailed") => results.skipped += count,
+            _ => (),
+        }
+    }
+}
+
+fn shorten(message: &str) -> String {
+    let lines: Vec<&str> = message.trim().lines().collect();
+
+    if lines.len() > MAX_MESSAGE_LINES {
+        format!("{}\n[{} more lines]",
+                lines[..MAX_MESSAGE_LINES].join("\n"), lines.len() - MAX_MESSAGE_LINES)
+    } else {
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_junit() {
+        let xml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                   <testsuites>\n\
+                     <testsuite name=\"login\" tests=\"4\">\n\
+                       <testcase classname=\"LoginTest\" name=\"accepts_password\"/>\n\
+                       <testcase classname=\"LoginTest\" name=\"rejects_empty\">\n\
+                         <failure message=\"expected error\">stack trace</failure>\n\
+                       </testcase>\n\
+                       <testcase classname=\"LoginTest\" name=\"times_out\">\n\
+                         <error>Connection refused</error>\n\
+                       </testcase>\n\
+                       <testcase name=\"sso\"><skipped/></testcase>\n\
+                     </testsuite>\n\
+                   </testsuites>";
+
+        let results = parse_junit(xml).unwrap();
+
+        assert_eq!(results.describe(), "1 passed, 2 failed, 1 skipped");
+        assert_eq!(results.failures, vec![
+            TestFailure {
+                name: String::from("LoginTest.rejects_empty"),
+                message: String::from("expected error"),
+            },
+            TestFailure {
+                name: String::from("LoginTest.times_out"),
+                message: String::from("Connection refused"),
+            },
+        ]);
+        assert!(parse_junit("<testsuite>").is_err());
+    }
+
+    #[test]
+    fn test_parse_libtest() {
+        let output = "running 3 tests\n\
+                      test tests::test_parse ... ok\n\
+                      test tests::test_slow ... ignored\n\
+                      test tests::test_login ... FAILED\n\
+                      \n\
+                      failures:\n\
+                      \n\
+                      ---- tests::test_login stdout ----\n\
+                      thread 'tests::test_login' panicked at src/login.rs:12:9:\n\
+                      assertion failed: user.is_valid()\n\
+                      note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace\n\
+                      \n\
+                      \n\
+                      failures:\n\
+                      \x20   tests::test_login\n\
+                      \n\
+                      test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured\n";
+
+        let results = parse_libtest(output);
+
+        assert_eq!(results.describe(), "1 passed, 1 failed, 1 skipped");
+        assert_eq!(results.failures[0].name, "tests::test_login");
+        assert_eq!(
+            results.failures[0].message,
+            "thread 'tests::test_login' panicked at src/login.rs:12:9:\n\
+             assertion failed: user.is_valid()");
+    }
+
+    #[test]
+    fn test_parse_pytest() {
+        let output = "============ test session starts ============\n\
+                      collected 4 items\n\
+                      \n\
+                      tests/test_login.py .F.s                [100%]\n\
+                      \n\
+                      ========= short test summary info ==========\n\
+                      FAILED tests/test_login.py::test_empty - AssertionError: assert 1 == 2\n\
+                      ==== 1 failed, 2 passed, 1 skipped in 0.12s ====\n";
+
+        let results = parse_pytest(output);
+
+        assert_eq!(results.describe(), "2 passed, 1 failed, 1 skipped");
+        assert_eq!(results.failures, vec![TestFailure {
+            name: String::from("tests/test_login.py::test_empty"),
+            message: String::from("AssertionError: assert 1 == 2"),
+        }]);
+    }
+
+    #[test]
+    fn test_long_messages_are_shortened() {
+        let mut results = TestResults::default();
+        let message: Vec<String> = (1..=15).map(|i| i.to_string()).collect();
+        results.fail("test_lines", &message.join("\n"));
+        results.failed += 1;
+
+        let summary = results.failure_summary();
+
+        assert!(summary.starts_with("test_lines\n    1\n    2\n"));
+        assert!(summary.contains("    10\n    [5 more lines]\n"));
+        assert!(summary.ends_with("[1 more failed tests, see the log]\n"));
+    }
+}
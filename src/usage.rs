@@ -0,0 +1,112 @@
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Resources used by the command of a step, including all child processes
+/// it waited for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub wall_time: Duration,
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// Peak resident set size of the largest process in bytes
+    pub max_rss: u64,
+}
+
+impl Usage {
+    fn from_rusage(rusage: &libc::rusage, wall_time: Duration) -> Usage {
+        let duration = |time: libc::timeval| {
+            Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+        };
+
+        Usage {
+            wall_time,
+            user_time: duration(rusage.ru_utime),
+            system_time: duration(rusage.ru_stime),
+            // Linux reports kilobytes
+            max_rss: rusage.ru_maxrss as u64 * 1024,
+        }
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} wall, {} user, {} system, {} peak RSS",
+               format_duration(self.wall_time),
+               format_duration(self.user_time),
+               format_duration(self.system_time),
+               format_bytes(self.max_rss))
+    }
+}
+
+/// Waits until the process has exited and returns its resource usage
+///
+/// The process is not reaped (like `wait4` would do), so that its exit
+/// status can still be collected by duct afterwards.
+pub fn wait_for_exit(pid: u32, started: Instant) -> io::Result<Usage> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    loop {
+        // only the raw system call reports the resource usage
+        let result = unsafe {
+            libc::syscall(libc::SYS_waitid, libc::P_PID, pid as libc::id_t,
+                          &mut info as *mut libc::siginfo_t,
+                          libc::WEXITED | libc::WNOWAIT,
+                          &mut rusage as *mut libc::rusage)
+        };
+
+        if result == 0 {
+            return Ok(Usage::from_rusage(&rusage, started.elapsed()));
+        }
+
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    format!("{:.1}s", duration.as_secs_f64())
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    format!("{:.1}M", bytes as f64 / (1024.0 * 1024.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_usage_of_exited_process() {
+        let started = Instant::now();
+        let mut child = Command::new("sh")
+            .args(&["-c", "i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done"])
+            .spawn()
+            .unwrap();
+
+        let usage = wait_for_exit(child.id(), started).unwrap();
+
+        // the process can still be reaped
+        assert!(child.wait().unwrap().success());
+        assert!(usage.user_time + usage.system_time > Duration::from_millis(0));
+        assert!(usage.wall_time >= usage.user_time);
+        assert!(usage.max_rss > 0);
+    }
+
+    #[test]
+    fn test_display() {
+        let usage = Usage {
+            wall_time: Duration::from_millis(12_340),
+            user_time: Duration::from_millis(10_080),
+            system_time: Duration::from_millis(500),
+            max_rss: 120 * 1024 * 1024,
+        };
+
+        assert_eq!(usage.to_string(),
+                   "12.3s wall, 10.1s user, 0.5s system, 120.0M peak RSS");
+    }
+}
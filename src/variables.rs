@@ -6,11 +6,15 @@ use toml;
 use crate::config::Configs;
 use crate::crypto;
 
-pub fn load(workdir: &PathBuf, configs: &Configs) -> HashMap<String, String> {
+/// Loads all variables, the secrets have to be loaded before with
+/// `load_secrets_from_file`
+pub fn load(configs: &Configs, secrets: &HashMap<String, String>)
+    -> HashMap<String, String>
+{
     let mut variables = HashMap::new();
 
     variables.extend(load_internal(configs));
-    variables.extend(load_secrets_from_file(workdir, configs));
+    variables.extend(secrets.clone());
 
     variables
 }
@@ -18,6 +22,16 @@ pub fn load(workdir: &PathBuf, configs: &Configs) -> HashMap<String, String> {
 fn load_internal(configs: &Configs) -> HashMap<String, String> {
     let mut variables = HashMap::new();
 
+    variables.insert(
+        "trigger".to_string(),
+        configs.execution_config.trigger.as_str().to_string());
+
+    // built-in variables are always defined, so that conditions like
+    // "%BRANCH" == "master" do not fail on tag builds
+    variables.insert("reftype".to_string(), String::new());
+    variables.insert("branch".to_string(), String::new());
+    variables.insert("tag".to_string(), String::new());
+
     if let Some(branch) = &configs.execution_config.branch {
         variables.insert("reftype".to_string(), "branch".to_string());
         variables.insert("branch".to_string(), branch.to_string());
@@ -30,7 +44,7 @@ fn load_internal(configs: &Configs) -> HashMap<String, String> {
     variables
 }
 
-fn load_secrets_from_file(workdir: &PathBuf, configs: &Configs)
+pub fn load_secrets_from_file(workdir: &PathBuf, configs: &Configs)
     -> HashMap<String, String>
 {
     let mut variables = HashMap::new();
@@ -1,8 +1,13 @@
 use std::error::Error;
 use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 
-use git2::Repository;
+use git2::{ErrorCode, Repository, StatusOptions};
+
+/// Where `GitSource::read_file` fetches the requested ref to
+const FETCHED_REF: &str = "refs/cinderella/fetched";
 
 pub trait CodeSource {
     // TODO: Returned working copy here should be dynamic
@@ -10,8 +15,11 @@ pub trait CodeSource {
 }
 
 pub trait WorkingCopy {
-    fn checkout_branch(&self, branch: &str);
-    fn checkout_tag(&self, tag: &str);
+    fn checkout_branch(&self, branch: &str) -> Result<(), String>;
+    fn checkout_tag(&self, tag: &str) -> Result<(), String>;
+    fn checkout_commit(&self, commit: &str) -> Result<(), String>;
+    /// The id of the commit that is checked out
+    fn head_commit(&self) -> Option<String>;
 }
 
 pub struct GitSource {
@@ -37,26 +45,210 @@ impl CodeSource for GitSource {
     }
 }
 
+impl GitSource {
+    /// Reads a file of a branch, or of the default branch, without creating
+    /// a working copy
+    ///
+    /// Local repositories are read in place. Other repositories are fetched
+    /// into a bare repository in `tmp`, which is deleted afterwards, and
+    /// only the requested branch is fetched. Returns `None` if the file does
+    /// not exist.
+    pub fn read_file(&self, branch: Option<&str>, path: &str, tmp: &Path)
+        -> Result<Option<String>, String>
+    {
+        let rev = match branch {
+            Some(branch) => format!("refs/heads/{}", branch),
+            None => String::from("HEAD"),
+        };
+
+        let result = match Repository::open(&self.src) {
+            Ok(repo) => read_blob(&repo, &rev, path),
+            Err(_) => {
+                let result = fetch_ref(&self.src, &rev, tmp)
+                    .and_then(|repo| read_blob(&repo, FETCHED_REF, path));
+                if let Err(e) = fs::remove_dir_all(tmp) {
+                    eprintln!("Could not delete {:?}: {}", tmp, e);
+                }
+                result
+            },
+        };
+
+        result.map_err(|e| format!("Could not read {} from {}: {}", path, self.src, e.message()))
+    }
+}
+
+fn fetch_ref(url: &str, rev: &str, target: &Path) -> Result<Repository, git2::Error> {
+    let repo = Repository::init_bare(target)?;
+    repo.remote_anonymous(url)?
+        .fetch(&[&format!("+{}:{}", rev, FETCHED_REF)], None, None)?;
+
+    Ok(repo)
+}
+
+fn read_blob(repo: &Repository, rev: &str, path: &str) -> Result<Option<String>, git2::Error> {
+    let tree = repo.revparse_single(rev)?.peel_to_tree()?;
+    let entry = match tree.get_path(Path::new(path)) {
+        Ok(entry) => entry,
+        Err(ref e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let blob = entry.to_object(repo)?.peel_to_blob()?;
+
+    Ok(Some(String::from_utf8_lossy(blob.content()).to_string()))
+}
+
 impl WorkingCopy for GitWorkingCopy {
-    fn checkout_branch(&self, branch_name: &str) {
+    fn checkout_branch(&self, branch_name: &str) -> Result<(), String> {
         let revname = format!("refs/remotes/origin/{}", branch_name);
-        self.checkout_rev(&revname);
+        self.checkout_rev(&revname)
+            .map_err(|e| format!("Could not check out branch {}: {}", branch_name, e))
     }
 
-    fn checkout_tag(&self, tag_name: &str) {
+    fn checkout_tag(&self, tag_name: &str) -> Result<(), String> {
         let revname = format!("refs/tags/{}", tag_name);
-        self.checkout_rev(&revname);
+        self.checkout_rev(&revname)
+            .map_err(|e| format!("Could not check out tag {}: {}", tag_name, e))
+    }
+
+    fn checkout_commit(&self, commit: &str) -> Result<(), String> {
+        self.checkout_rev(commit).map_err(|e| match e.code() {
+            ErrorCode::NotFound => format!("Commit {} no longer exists", commit),
+            _ => format!("Could not check out commit {}: {}", commit, e.message()),
+        })
+    }
+
+    fn head_commit(&self) -> Option<String> {
+        let commit = self.repo.head().ok()?.peel_to_commit().ok()?;
+
+        Some(commit.id().to_string())
     }
 }
 
 impl GitWorkingCopy {
-    fn checkout_rev(&self, rev: &str) {
-        let obj = self.repo.revparse_single(rev).unwrap();
+    fn checkout_rev(&self, rev: &str) -> Result<(), git2::Error> {
+        let obj = self.repo.revparse_single(rev)?;
 
         self.repo.checkout_tree(
             &obj,
             None
-        ).expect("Checkout of tree failed");
+        )?;
+
+        // HEAD points to the built commit, so that e.g. `git describe`
+        // works in the build
+        let commit = obj.peel_to_commit()?;
+        self.repo.set_head_detached(commit.id())
+    }
+}
+
+/// A repository on the local disk, which is built with the uncommitted
+/// changes of its working tree
+pub struct LocalRepository {
+    /// The root of the working tree
+    pub path: PathBuf,
+    repo: Repository,
+}
+
+impl LocalRepository {
+    /// Finds the repository that contains the directory
+    pub fn discover(dir: &Path) -> Result<LocalRepository, String> {
+        let repo = Repository::discover(dir)
+            .map_err(|e| format!("{:?} is not in a git repository: {}", dir, e.message()))?;
+        let path = repo.workdir()
+            .ok_or_else(|| format!("Repository {:?} has no working tree", repo.path()))?
+            .to_path_buf();
+
+        Ok(LocalRepository { path, repo })
+    }
+
+    /// The checked out branch, `None` if the HEAD is detached
+    pub fn branch(&self) -> Option<String> {
+        let head = self.repo.head().ok()?;
+
+        if head.is_branch() {
+            head.shorthand().map(String::from)
+        } else {
+            None
+        }
+    }
+
+    pub fn head_commit(&self) -> Option<String> {
+        let commit = self.repo.head().ok()?.peel_to_commit().ok()?;
+
+        Some(commit.id().to_string())
+    }
+
+    /// Copies the working tree to `target` like a fresh clone would look
+    /// after applying the uncommitted changes
+    ///
+    /// Tracked files and untracked files that are not ignored are copied
+    /// as they are in the working tree, ignored files like build output
+    /// are left out. The git directory is copied as well, so that git
+    /// commands work in the copy.
+    pub fn copy_to(&self, target: &Path) -> Result<GitWorkingCopy, String> {
+        let copy_error = |e: io::Error| format!("Could not copy working tree to {:?}: {}", target, e);
+
+        let mut files: Vec<PathBuf> = self.repo.index()
+            .map_err(|e| format!("Could not read git index: {}", e.message()))?
+            .iter()
+            .map(|entry| PathBuf::from(String::from_utf8_lossy(&entry.path).to_string()))
+            .collect();
+
+        let mut options = StatusOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true);
+        let statuses = self.repo.statuses(Some(&mut options))
+            .map_err(|e| format!("Could not read git status: {}", e.message()))?;
+        files.extend(statuses.iter()
+            .filter(|entry| entry.status().is_wt_new())
+            .filter_map(|entry| entry.path().map(PathBuf::from)));
+
+        fs::create_dir_all(target).map_err(copy_error)?;
+        copy_dir(self.repo.path(), &target.join(".git")).map_err(copy_error)?;
+
+        for file in files {
+            let source = self.path.join(&file);
+            let destination = target.join(&file);
+
+            // files that were deleted in the working tree are not copied
+            let metadata = match fs::symlink_metadata(&source) {
+                Ok(metadata) => metadata,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(copy_error(e)),
+            };
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent).map_err(copy_error)?;
+            }
+            copy_entry(&source, &destination, &metadata).map_err(copy_error)?;
+        }
+
+        let repo = Repository::open(target)
+            .map_err(|e| format!("Could not open copied repository: {}", e.message()))?;
+
+        Ok(GitWorkingCopy {
+            path: target.to_path_buf(),
+            repo,
+        })
+    }
+}
+
+fn copy_dir(source: &Path, destination: &Path) -> io::Result<()> {
+    fs::create_dir_all(destination)?;
+
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        copy_entry(&entry.path(), &destination.join(entry.file_name()), &entry.metadata()?)?;
+    }
+
+    Ok(())
+}
+
+/// Copies a file, a symbolic link or (e.g. for submodules) a directory
+fn copy_entry(source: &Path, destination: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    if metadata.file_type().is_symlink() {
+        symlink(fs::read_link(source)?, destination)
+    } else if metadata.is_dir() {
+        copy_dir(source, destination)
+    } else {
+        fs::copy(source, destination).map(|_| ())
     }
 }
 
@@ -67,3 +259,102 @@ impl Drop for GitWorkingCopy {
             .expect("Could not delete work dir");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(&["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_copy_working_tree_with_uncommitted_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        fs::create_dir(&repo).unwrap();
+        git(&repo, &["init", "-q", "-b", "feature"]);
+        fs::write(repo.join(".gitignore"), "target/\n").unwrap();
+        fs::write(repo.join("changed.txt"), "committed\n").unwrap();
+        fs::write(repo.join("deleted.txt"), "committed\n").unwrap();
+        git(&repo, &["add", "-A"]);
+        git(&repo, &["commit", "-q", "-m", "initial"]);
+
+        fs::write(repo.join("changed.txt"), "uncommitted\n").unwrap();
+        fs::remove_file(repo.join("deleted.txt")).unwrap();
+        fs::create_dir_all(repo.join("src/new")).unwrap();
+        fs::write(repo.join("src/new/untracked.txt"), "new\n").unwrap();
+        fs::create_dir(repo.join("target")).unwrap();
+        fs::write(repo.join("target/ignored.txt"), "build output\n").unwrap();
+
+        let local = LocalRepository::discover(&repo.join("src")).unwrap();
+        assert_eq!(local.branch(), Some(String::from("feature")));
+
+        let target = dir.path().join("copy");
+        let copy = local.copy_to(&target).unwrap();
+
+        assert_eq!(fs::read_to_string(target.join("changed.txt")).unwrap(), "uncommitted\n");
+        assert_eq!(fs::read_to_string(target.join("src/new/untracked.txt")).unwrap(), "new\n");
+        assert!(!target.join("deleted.txt").exists());
+        assert!(!target.join("target").exists());
+        assert_eq!(copy.head_commit(), local.head_commit());
+
+        drop(copy);
+        assert!(!target.exists());
+        assert!(repo.join("changed.txt").exists());
+    }
+
+    #[test]
+    fn test_checkout_of_missing_commit_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        fs::create_dir(&repo).unwrap();
+        git(&repo, &["init", "-q", "-b", "master"]);
+        git(&repo, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+
+        let source = GitSource { src: repo.to_string_lossy().to_string() };
+        let workdir = source.fetch(&dir.path().join("clone")).unwrap();
+
+        assert!(workdir.checkout_branch("master").is_ok());
+        assert!(workdir.checkout_tag("v1.0").is_err());
+        assert_eq!(
+            workdir.checkout_commit("0123456789abcdef0123456789abcdef01234567"),
+            Err(String::from("Commit 0123456789abcdef0123456789abcdef01234567 no longer exists")));
+    }
+
+    #[test]
+    fn test_read_file_without_working_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        fs::create_dir(&repo).unwrap();
+        git(&repo, &["init", "-q", "-b", "master"]);
+        fs::write(repo.join(".cinderella.toml"), "[test]\n").unwrap();
+        git(&repo, &["add", "-A"]);
+        git(&repo, &["commit", "-q", "-m", "initial"]);
+        git(&repo, &["checkout", "-q", "-b", "docs"]);
+        fs::write(repo.join(".cinderella.toml"), "[docs]\n").unwrap();
+        git(&repo, &["commit", "-q", "-am", "docs"]);
+        git(&repo, &["checkout", "-q", "master"]);
+
+        let tmp = dir.path().join("fetched");
+        let local = GitSource { src: repo.to_string_lossy().to_string() };
+        let remote = GitSource { src: format!("file://{}", repo.to_string_lossy()) };
+
+        for source in &[local, remote] {
+            assert_eq!(source.read_file(None, ".cinderella.toml", &tmp).unwrap(),
+                       Some(String::from("[test]\n")));
+            assert_eq!(source.read_file(Some("docs"), ".cinderella.toml", &tmp).unwrap(),
+                       Some(String::from("[docs]\n")));
+            assert_eq!(source.read_file(None, "missing.toml", &tmp).unwrap(), None);
+            assert!(source.read_file(Some("missing"), ".cinderella.toml", &tmp).is_err());
+            assert!(!tmp.exists());
+        }
+    }
+}